@@ -1,31 +1,73 @@
 use anyhow::Result;
-use btlink::BtLinkConfig;
-use clap::Parser;
-use common::{init_tracing, Backoff, ClientConfig};
-use mux::{MuxConfig, MuxSession, Role};
-use proxy_http::run_http_proxy;
+use btlink::{BtLink, BtLinkConfig};
+use clap::{Parser, ValueEnum};
+use common::{init_tracing, shutdown_signal, Backoff, ClientCommand, ClientConfig, Transport};
+use mux::keys::ClientKey;
+use mux::{Compression, MuxConfig, MuxSession, Role, GOAWAY_NO_ERROR};
+use proxy_http::{run_http_proxy, PriorityRules};
 use tokio::time::{sleep, Duration};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cfg = ClientConfig::parse();
+    if let Some(ClientCommand::Keygen { file, comment }) = &cfg.command {
+        return keygen(file, comment);
+    }
     init_tracing(&cfg.log);
     info!(
-        bt_addr = %cfg.bt_addr,
+        transport = ?cfg.transport,
+        bt_addr = cfg.bt_addr.as_deref().unwrap_or(""),
         channel = cfg.channel,
         uuid = cfg.uuid.as_deref().unwrap_or(""),
+        server_addr = cfg.server_addr.as_deref().unwrap_or(""),
         listen = %cfg.listen,
         "starting btproxy client"
     );
 
+    let rules = PriorityRules::parse(&cfg.priority)?;
+    let client_key = cfg.key.as_deref().map(ClientKey::load).transpose()?;
+    if let Some(key) = &client_key {
+        info!(key = %key.public_key(), "using client key");
+    }
+    let drain_timeout = Duration::from_millis(cfg.drain_timeout_ms);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     let mut backoff = Backoff::new(1000, 30_000);
     loop {
-        match connect_session(&cfg).await {
+        let connected = tokio::select! {
+            connected = connect_session(&cfg, client_key.as_ref()) => connected,
+            _ = &mut shutdown => return Ok(()),
+        };
+        match connected {
             Ok(session) => {
                 backoff.reset(1000);
-                if let Err(err) = run_http_proxy(&cfg.listen, session).await {
-                    error!(?err, "proxy exited");
+                tokio::select! {
+                    res = run_http_proxy(&cfg.listen, session.clone(), rules.clone()) => {
+                        if let Err(err) = res {
+                            error!(?err, "proxy exited");
+                        }
+                    }
+                    reason = session.closed() => {
+                        let stats = session.stats();
+                        warn!(
+                            %reason,
+                            data_sent = stats.data_sent,
+                            data_received = stats.data_received,
+                            compression_ratio = stats.compression_ratio().map(|ratio| format!("{:.2}", ratio)),
+                            "session closed"
+                        );
+                    }
+                    // Streams still running finish on the old session.
+                    _ = session.going_away() => {
+                        info!("server is going away, reconnecting");
+                    }
+                    _ = &mut shutdown => {
+                        info!("shutting down");
+                        let reason = session.drain(GOAWAY_NO_ERROR, drain_timeout).await;
+                        info!(%reason, "session ended");
+                        return Ok(());
+                    }
                 }
             }
             Err(err) => {
@@ -35,25 +77,219 @@ async fn main() -> Result<()> {
 
         let delay = backoff.next_delay();
         info!("reconnecting in {} ms", delay);
-        sleep(Duration::from_millis(delay)).await;
+        tokio::select! {
+            _ = sleep(Duration::from_millis(delay)) => {}
+            _ = &mut shutdown => return Ok(()),
+        }
+    }
+}
+
+/// Writes a new key pair and prints what to do with the public half.
+fn keygen(file: &std::path::Path, comment: &str) -> Result<()> {
+    let key = ClientKey::generate(comment);
+    let public_path = key.save(file)?;
+    println!("private key: {}", file.display());
+    println!("public key:  {}", public_path.display());
+    println!("fingerprint: {}", key.public_key());
+    println!("add this line to the server's authorized_keys:");
+    println!("{}", key.public_key().to_line(comment));
+    Ok(())
+}
+
+async fn connect_session(cfg: &ClientConfig, client_key: Option<&ClientKey>) -> Result<MuxSession> {
+    // Parse the bond specs before dialling anything.
+    let bonds = cfg
+        .bond
+        .iter()
+        .map(|spec| Ok((spec.clone(), bond_config(cfg, spec)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let link = connect_link(cfg).await?;
+
+    let mux_cfg = MuxConfig {
+        max_frame: 65536,
+        keepalive_ms: cfg.keepalive_ms,
+        max_missed_pongs: cfg.keepalive_misses,
+        idle_timeout_ms: cfg.idle_timeout_ms,
+        psk: cfg.psk.as_ref().map(|s| s.as_bytes().to_vec()),
+        client_key: client_key.cloned(),
+        authorized_keys: None,
+        checksum: cfg.checksum,
+        initial_window: cfg.stream_window,
+        compression: cfg
+            .compress
+            .iter()
+            .map(|alg| Ok(alg.parse::<Compression>()?))
+            .collect::<Result<_>>()?,
+        write_batch: cfg.write_batch,
+        open_timeout_ms: cfg.open_timeout_ms,
+        bond: !bonds.is_empty(),
+    };
+    let session = MuxSession::start(link, mux_cfg, Role::Client).await?;
+    if !bonds.is_empty() && session.bond_token().is_none() {
+        warn!("server does not accept bonded sessions, using a single link");
+    } else {
+        for (spec, bond_cfg) in bonds {
+            tokio::spawn(maintain_bond_link(session.clone(), bond_cfg, spec));
+        }
+    }
+    Ok(session)
+}
+
+async fn connect_link(cfg: &ClientConfig) -> Result<BtLink> {
+    let link_cfg = BtLinkConfig {
+        write_batch: cfg.write_batch,
+        write_delay: (cfg.write_delay_ms > 0).then(|| Duration::from_millis(cfg.write_delay_ms)),
+        ..BtLinkConfig::default()
+    };
+    let link = match cfg.transport {
+        Transport::Tcp => {
+            let addr = cfg
+                .server_addr
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("--server-addr required for tcp transport"))?;
+            btlink::connect_tcp(addr, link_cfg).await?
+        }
+        Transport::Rfcomm => connect_rfcomm(cfg, link_cfg).await?,
+        Transport::Tty => open_tty(cfg, link_cfg).await?,
+        Transport::Exec => {
+            let command = cfg
+                .exec
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("--exec required for exec transport"))?;
+            btlink::spawn_command(command, link_cfg).await?
+        }
+        Transport::Uds => connect_uds(cfg, link_cfg).await?,
+        Transport::Stdio => {
+            return Err(anyhow::anyhow!(
+                "stdio transport is server-only, use --transport exec"
+            ))
+        }
+    };
+    Ok(link)
+}
+
+/// Derives the settings for one `--bond TRANSPORT:TARGET` link from the
+/// primary ones.
+fn bond_config(cfg: &ClientConfig, spec: &str) -> Result<ClientConfig> {
+    let (transport, target) = spec
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("invalid bond spec {}, expected TRANSPORT:TARGET", spec))?;
+    let mut bond = cfg.clone();
+    bond.bond.clear();
+    bond.transport = Transport::from_str(transport, true)
+        .map_err(|_| anyhow::anyhow!("unknown transport in bond spec {}", spec))?;
+    let target = Some(target.to_string());
+    match bond.transport {
+        Transport::Tcp => bond.server_addr = target,
+        Transport::Rfcomm => {
+            bond.channel =
+                Some(
+                    target.as_deref().unwrap_or_default().parse().map_err(|_| {
+                        anyhow::anyhow!("invalid rfcomm channel in bond spec {}", spec)
+                    })?,
+                )
+        }
+        Transport::Tty => bond.tty = target,
+        Transport::Exec => bond.exec = target,
+        Transport::Uds => bond.socket_path = target,
+        Transport::Stdio => {
+            return Err(anyhow::anyhow!("stdio cannot be bonded"));
+        }
+    }
+    Ok(bond)
+}
+
+/// Keeps one extra link joined to `session`, reconnecting it when it drops,
+/// until the session itself ends.
+async fn maintain_bond_link(session: MuxSession, cfg: ClientConfig, spec: String) {
+    let mut backoff = Backoff::new(1000, 30_000);
+    loop {
+        let joined = async {
+            let link = connect_link(&cfg).await?;
+            Ok::<_, anyhow::Error>(session.join(link).await?)
+        };
+        tokio::select! {
+            _ = session.closed() => return,
+            res = joined => match res {
+                Ok(handle) => {
+                    backoff.reset(1000);
+                    info!(%spec, links = session.stats().links, "bond link joined");
+                    tokio::select! {
+                        reason = handle.closed() => warn!(%spec, %reason, "bond link dropped"),
+                        _ = session.closed() => return,
+                    }
+                }
+                Err(err) => warn!(%spec, ?err, "bond link failed"),
+            },
+        }
+        let delay = backoff.next_delay();
+        tokio::select! {
+            _ = sleep(Duration::from_millis(delay)) => {}
+            _ = session.closed() => return,
+        }
     }
 }
 
-async fn connect_session(cfg: &ClientConfig) -> Result<MuxSession> {
-    let link_cfg = BtLinkConfig::default();
+async fn open_tty(cfg: &ClientConfig, link_cfg: BtLinkConfig) -> Result<BtLink> {
+    let path = cfg
+        .tty
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("--tty required for tty transport"))?;
+
+    #[cfg(target_os = "linux")]
+    let link = {
+        let tty_cfg = btlink::TtyConfig {
+            baud: cfg.baud,
+            flow_control: cfg.flow_control,
+        };
+        btlink::open_linux_tty(path, &tty_cfg, link_cfg).await?
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    let link = {
+        let _ = (path, link_cfg);
+        return Err(anyhow::anyhow!("tty transport is only supported on linux"));
+    };
+
+    Ok(link)
+}
+
+async fn connect_uds(cfg: &ClientConfig, link_cfg: BtLinkConfig) -> Result<BtLink> {
+    let path = cfg
+        .socket_path
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("--socket-path required for uds transport"))?;
+
+    #[cfg(target_os = "linux")]
+    let link = btlink::connect_linux_uds(path, &cfg.socket_owners, link_cfg).await?;
+
+    #[cfg(not(target_os = "linux"))]
+    let link = {
+        let _ = (path, link_cfg);
+        return Err(anyhow::anyhow!("uds transport is only supported on linux"));
+    };
+
+    Ok(link)
+}
+
+async fn connect_rfcomm(cfg: &ClientConfig, link_cfg: BtLinkConfig) -> Result<BtLink> {
+    let bt_addr = cfg
+        .bt_addr
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("--bt-addr required for rfcomm transport"))?;
+
     #[cfg(target_os = "linux")]
     let link = {
         let channel = cfg
             .channel
             .ok_or_else(|| anyhow::anyhow!("--channel required on linux"))?;
-        btlink::connect_linux_rfcomm(&cfg.bt_addr, channel, link_cfg).await?
+        btlink::connect_linux_rfcomm(bt_addr, channel, link_cfg).await?
     };
 
     #[cfg(target_os = "windows")]
     let link = {
         info!("attempting rfcomm connection");
-        btlink::connect_windows_rfcomm(&cfg.bt_addr, cfg.uuid.as_deref(), cfg.channel, link_cfg)
-            .await?
+        btlink::connect_windows_rfcomm(bt_addr, cfg.uuid.as_deref(), cfg.channel, link_cfg).await?
     };
 
     #[cfg(not(any(target_os = "linux", target_os = "windows")))]
@@ -61,11 +297,5 @@ async fn connect_session(cfg: &ClientConfig) -> Result<MuxSession> {
         return Err(anyhow::anyhow!("unsupported platform"));
     };
 
-    let mux_cfg = MuxConfig {
-        max_frame: 65536,
-        keepalive_ms: 10_000,
-        psk: cfg.psk.as_ref().map(|s| s.as_bytes().to_vec()),
-    };
-    let session = MuxSession::start(link, mux_cfg, Role::Client).await?;
-    Ok(session)
+    Ok(link)
 }
@@ -1,57 +1,399 @@
 use anyhow::Result;
-use btlink::BtLinkConfig;
-use bytes::Bytes;
+use btlink::{BtLink, BtLinkConfig, LinkListener};
 use clap::Parser;
-use common::{init_tracing, ServerConfig};
-use mux::{MuxConfig, MuxSession, Role, TargetAddr};
+use common::error::BtProxyError;
+use common::{init_tracing, shutdown_signal, ClientPolicy, ServerConfig, Transport};
+use mux::keys::{AuthorizedKey, AuthorizedKeys};
+use mux::{
+    Compression, Incoming, MuxConfig, MuxSession, PendingLink, Role, TargetAddr, GOAWAY_REJECTED,
+    GOAWAY_REPLACED, GOAWAY_SHUTDOWN,
+};
 use socks5::connect_via_socks5;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::io::copy_bidirectional;
 use tokio::net::TcpStream;
-use tracing::{info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{info, info_span, warn, Instrument};
+
+const LISTEN_BACKLOG: i32 = 8;
+
+/// Sessions currently being served, keyed by a per-process session id.
+#[derive(Default)]
+struct ActivePeers {
+    next_id: u64,
+    sessions: HashMap<u64, (String, MuxSession)>,
+    /// Set once the server is shutting down.
+    stopping: bool,
+}
+
+type Peers = Arc<Mutex<ActivePeers>>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cfg = ServerConfig::parse();
     init_tracing(&cfg.log);
     info!(
+        transport = ?cfg.transport,
         channel = cfg.channel,
         clash_socks = %cfg.clash_socks,
         direct = cfg.direct,
+        client_policy = ?cfg.client_policy,
+        bond = cfg.bond,
         "starting btproxy server"
     );
 
-    let link_cfg = BtLinkConfig::default();
-    #[cfg(target_os = "linux")]
-    let link = btlink::accept_linux_rfcomm(cfg.channel, link_cfg).await?;
-
-    #[cfg(target_os = "windows")]
-    let link = btlink::accept_windows_rfcomm(cfg.channel, link_cfg).await?;
-
-    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
-    let link = {
-        return Err(anyhow::anyhow!("unsupported platform"));
+    let link_cfg = BtLinkConfig {
+        write_batch: cfg.write_batch,
+        write_delay: (cfg.write_delay_ms > 0).then(|| Duration::from_millis(cfg.write_delay_ms)),
+        ..BtLinkConfig::default()
+    };
+    let authorized_keys = match cfg.authorized_keys.as_deref() {
+        Some(path) => {
+            let keys = AuthorizedKeys::load(path)?;
+            info!(keys = keys.len(), "loaded authorized keys");
+            Some(Arc::new(keys))
+        }
+        None => None,
     };
-
     let mux_cfg = MuxConfig {
         max_frame: 65536,
-        keepalive_ms: 10_000,
+        keepalive_ms: cfg.keepalive_ms,
+        max_missed_pongs: cfg.keepalive_misses,
+        idle_timeout_ms: cfg.idle_timeout_ms,
         psk: cfg.psk.as_ref().map(|s| s.as_bytes().to_vec()),
+        client_key: None,
+        authorized_keys,
+        checksum: cfg.checksum,
+        initial_window: cfg.stream_window,
+        compression: cfg
+            .compress
+            .iter()
+            .map(|alg| Ok(alg.parse::<Compression>()?))
+            .collect::<Result<_>>()?,
+        write_batch: cfg.write_batch,
+        // Applies to streams the server opens towards the client.
+        open_timeout_ms: cfg.open_timeout_ms,
+        bond: cfg.bond,
+    };
+
+    // Serial lines and pipes carry exactly one link for the life of the
+    // process; everything else keeps accepting.
+    let listener = match cfg.transport {
+        Transport::Tcp => LinkListener::Tcp(btlink::TcpLinkListener::bind(&cfg.listen).await?),
+        Transport::Rfcomm => rfcomm_listener(&cfg)?,
+        Transport::Uds => uds_listener(&cfg)?,
+        Transport::Tty => {
+            let link = open_tty(&cfg, link_cfg).await?;
+            let session = MuxSession::start(link, mux_cfg, Role::Server).await?;
+            serve_until_shutdown(session, cfg).await;
+            return Ok(());
+        }
+        Transport::Stdio => {
+            let link = btlink::stdio_link(link_cfg);
+            let session = MuxSession::start(link, mux_cfg, Role::Server).await?;
+            serve_until_shutdown(session, cfg).await;
+            return Ok(());
+        }
+        Transport::Exec => {
+            let command = cfg
+                .exec
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("--exec required for exec transport"))?;
+            let link = btlink::spawn_command(command, link_cfg).await?;
+            let session = MuxSession::start(link, mux_cfg, Role::Server).await?;
+            serve_until_shutdown(session, cfg).await;
+            return Ok(());
+        }
     };
-    let session = MuxSession::start(link, mux_cfg, Role::Server).await?;
 
+    serve_listener(listener, cfg, mux_cfg, link_cfg, shutdown_signal()).await;
+    Ok(())
+}
+
+/// Accepts clients on `listener` and serves each of them until `shutdown`
+/// resolves, then drains every session and returns once all are done.
+async fn serve_listener(
+    listener: LinkListener,
+    cfg: ServerConfig,
+    mux_cfg: MuxConfig,
+    link_cfg: BtLinkConfig,
+    shutdown: impl Future<Output = ()>,
+) {
     info!("server ready");
+    let peers = Peers::default();
+    let (stop, stopped) = watch::channel(false);
+    let handshake_timeout = match cfg.handshake_timeout_ms {
+        0 => Duration::MAX,
+        ms => Duration::from_millis(ms),
+    };
+    let mut tasks = JoinSet::new();
+    tokio::pin!(shutdown);
     loop {
-        if let Some((target, stream)) = session.accept_stream().await {
-            info!(?target, stream_id = stream.stream_id, "accepted mux stream");
-            let session = session.clone();
-            let cfg = cfg.clone();
-            tokio::spawn(async move {
+        let accepted = tokio::select! {
+            accepted = listener.accept(link_cfg.clone()) => accepted,
+            Some(_) = tasks.join_next() => continue,
+            _ = &mut shutdown => break,
+        };
+        let (link, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(?err, "accept failed");
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let peers = Arc::clone(&peers);
+        let cfg = cfg.clone();
+        let mut mux_cfg = mux_cfg.clone();
+        let mut stopped = stopped.clone();
+        let span = info_span!("peer", %peer);
+        tasks.spawn(
+            async move {
+                // Pick up edits to authorized_keys; a broken file lets no one in.
+                if let Some(path) = cfg.authorized_keys.as_deref() {
+                    match AuthorizedKeys::load(path) {
+                        Ok(keys) => mux_cfg.authorized_keys = Some(Arc::new(keys)),
+                        Err(err) => {
+                            warn!(%err, "cannot read authorized keys, dropping connection");
+                            return;
+                        }
+                    }
+                }
+                let accepted = tokio::select! {
+                    accepted = timeout(handshake_timeout, MuxSession::accept(link, mux_cfg)) => accepted,
+                    _ = stopped.wait_for(|stopped| *stopped) => {
+                        info!("shutting down, dropping handshake in progress");
+                        return;
+                    }
+                };
+                match accepted {
+                    Ok(Ok(Incoming::Session(session))) => {
+                        serve_peer(&peers, peer, session, cfg).await
+                    }
+                    Ok(Ok(Incoming::Join { token, link })) => {
+                        join_session(&peers, &peer, token, *link)
+                    }
+                    Ok(Err(err)) => warn!(?err, "handshake failed"),
+                    Err(_) => warn!(timeout = ?handshake_timeout, "handshake timed out"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    // Stop accepting, then give every client a chance to finish. Sessions
+    // whose handshake completes from here on are turned away by `admit`.
+    drop(listener);
+    let sessions: Vec<(String, MuxSession)> = {
+        let mut active = peers.lock().unwrap();
+        active.stopping = true;
+        active.sessions.values().cloned().collect()
+    };
+    stop.send_replace(true);
+    info!(sessions = sessions.len(), "shutting down");
+    let drain_timeout = Duration::from_millis(cfg.drain_timeout_ms);
+    for (peer, session) in sessions {
+        tasks.spawn(async move {
+            let reason = session.drain(GOAWAY_SHUTDOWN, drain_timeout).await;
+            info!(%peer, %reason, "session ended");
+        });
+    }
+    while tasks.join_next().await.is_some() {}
+}
+
+/// Serves the only session of a single-link transport until it ends or the
+/// process is asked to stop.
+async fn serve_until_shutdown(session: MuxSession, cfg: ServerConfig) {
+    let drain_timeout = Duration::from_millis(cfg.drain_timeout_ms);
+    tokio::select! {
+        _ = serve_session(session.clone(), cfg) => {}
+        _ = shutdown_signal() => {
+            info!("shutting down");
+            let reason = session.drain(GOAWAY_SHUTDOWN, drain_timeout).await;
+            info!(%reason, "session ended");
+        }
+    }
+}
+
+async fn serve_peer(peers: &Peers, peer: String, session: MuxSession, cfg: ServerConfig) {
+    let drain_timeout = Duration::from_millis(cfg.drain_timeout_ms);
+    if !key_allowed_from(session.client_key(), &peer) {
+        session.drain(GOAWAY_REJECTED, drain_timeout).await;
+        return;
+    }
+    let id = match admit(peers, &peer, &session, cfg.client_policy, drain_timeout) {
+        Ok(id) => id,
+        Err(code) => {
+            session.drain(code, drain_timeout).await;
+            return;
+        }
+    };
+    serve_session(session, cfg).await;
+    peers.lock().unwrap().sessions.remove(&id);
+}
+
+/// Hands an extra link to the bonded session it names.
+fn join_session(peers: &Peers, peer: &str, token: u64, link: PendingLink) {
+    if !key_allowed_from(link.client_key(), peer) {
+        return;
+    }
+    let session = peers
+        .lock()
+        .unwrap()
+        .sessions
+        .values()
+        .find(|(_, session)| session.bond_token() == Some(token))
+        .map(|(peer, session)| (peer.clone(), session.clone()));
+    match session {
+        Some((owner, session)) => match session.attach(link) {
+            Ok(()) => info!(%owner, links = session.stats().links, "link joined bonded session"),
+            Err(err) => warn!(?err, "failed to attach link"),
+        },
+        None => warn!("join for an unknown session, dropping link"),
+    }
+}
+
+/// Applies the `from` option of the client's key, if it has one.
+fn key_allowed_from(key: Option<&AuthorizedKey>, peer: &str) -> bool {
+    match key {
+        Some(key) if !key.allows_from(peer) => {
+            warn!(%peer, %key, "key is not allowed from this address");
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Applies the client policy to a newly accepted peer and registers its
+/// session if it is to be served. Both happen under one lock, so clients
+/// that arrive together cannot all get past `Reject` or all survive
+/// `KickOld`. Returns the session id, or the GOAWAY code to turn the peer
+/// away with.
+fn admit(
+    peers: &Peers,
+    peer: &str,
+    session: &MuxSession,
+    policy: ClientPolicy,
+    drain_timeout: Duration,
+) -> Result<u64, u16> {
+    let mut active = peers.lock().unwrap();
+    if active.stopping {
+        return Err(GOAWAY_SHUTDOWN);
+    }
+    if !active.sessions.is_empty() {
+        match policy {
+            ClientPolicy::Allow => {}
+            ClientPolicy::Reject => {
+                warn!(%peer, active = active.sessions.len(), "rejecting client, another one is connected");
+                return Err(GOAWAY_REJECTED);
+            }
+            ClientPolicy::KickOld => {
+                // Replaced sessions leave at once, so the next newcomer
+                // does not kick them again.
+                for (_, (old, session)) in active.sessions.drain() {
+                    warn!(%peer, %old, "new client connected, closing old session");
+                    tokio::spawn(async move {
+                        session.drain(GOAWAY_REPLACED, drain_timeout).await;
+                    });
+                }
+            }
+        }
+    }
+    let id = active.next_id;
+    active.next_id += 1;
+    active
+        .sessions
+        .insert(id, (peer.to_string(), session.clone()));
+    Ok(id)
+}
+
+async fn serve_session(session: MuxSession, cfg: ServerConfig) {
+    info!("session ready");
+    while let Some((target, stream)) = session.accept_stream().await {
+        info!(?target, stream_id = stream.stream_id, "accepted mux stream");
+        let session = session.clone();
+        let cfg = cfg.clone();
+        tokio::spawn(
+            async move {
                 if let Err(err) = handle_stream(session, cfg, target, stream).await {
                     warn!(?err, "stream error");
                 }
-            });
-        }
+            }
+            .in_current_span(),
+        );
     }
+    let reason = session.closed().await;
+    let stats = session.stats();
+    info!(
+        %reason,
+        data_sent = stats.data_sent,
+        data_received = stats.data_received,
+        compression_ratio = stats.compression_ratio().map(|ratio| format!("{:.2}", ratio)),
+        "session ended"
+    );
+}
+
+async fn open_tty(cfg: &ServerConfig, link_cfg: BtLinkConfig) -> Result<BtLink> {
+    let path = cfg
+        .tty
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("--tty required for tty transport"))?;
+
+    #[cfg(target_os = "linux")]
+    let link = {
+        let tty_cfg = btlink::TtyConfig {
+            baud: cfg.baud,
+            flow_control: cfg.flow_control,
+        };
+        btlink::open_linux_tty(path, &tty_cfg, link_cfg).await?
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    let link = {
+        let _ = (path, link_cfg);
+        return Err(anyhow::anyhow!("tty transport is only supported on linux"));
+    };
+
+    Ok(link)
+}
+
+fn uds_listener(cfg: &ServerConfig) -> Result<LinkListener> {
+    let path = cfg
+        .socket_path
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("--socket-path required for uds transport"))?;
+
+    #[cfg(target_os = "linux")]
+    let listener = LinkListener::Uds(btlink::UdsListener::bind(
+        path,
+        cfg.socket_mode,
+        LISTEN_BACKLOG,
+    )?);
+
+    #[cfg(not(target_os = "linux"))]
+    let listener = {
+        let _ = path;
+        return Err(anyhow::anyhow!("uds transport is only supported on linux"));
+    };
+
+    Ok(listener)
+}
+
+fn rfcomm_listener(cfg: &ServerConfig) -> Result<LinkListener> {
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    let listener = LinkListener::Rfcomm(btlink::RfcommListener::bind(cfg.channel, LISTEN_BACKLOG)?);
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    let listener = {
+        let _ = cfg;
+        return Err(anyhow::anyhow!("unsupported platform"));
+    };
+
+    Ok(listener)
 }
 
 async fn handle_stream(
@@ -69,7 +411,9 @@ async fn handle_stream(
         "opening outbound connection"
     );
     let outbound = if cfg.direct {
-        TcpStream::connect(format!("{}:{}", host, port)).await
+        TcpStream::connect(format!("{}:{}", host, port))
+            .await
+            .map_err(BtProxyError::from)
     } else {
         connect_via_socks5(
             &cfg.clash_socks,
@@ -87,14 +431,15 @@ async fn handle_stream(
             let _ = session
                 .send_open_err(mux_stream.stream_id, 500, "connect failed")
                 .await;
-            return Err(err);
+            return Err(err.into());
         }
     };
 
     session.send_open_ok(mux_stream.stream_id).await.ok();
     info!(stream_id = mux_stream.stream_id, "proxying stream");
-    if let Err(err) = proxy_streams(outbound, mux_stream.clone()).await {
-        let _ = session.send_rst(mux_stream.stream_id, 500).await;
+    let stream_id = mux_stream.stream_id;
+    if let Err(err) = proxy_streams(outbound, mux_stream).await {
+        let _ = session.send_rst(stream_id, 500).await;
         return Err(err);
     }
     Ok(())
@@ -108,34 +453,162 @@ fn target_to_host_port(target: &TargetAddr) -> Result<(String, u16)> {
     }
 }
 
-async fn proxy_streams(outbound: TcpStream, mux_stream: mux::MuxStream) -> Result<()> {
-    let (mut outbound_read, mut outbound_write) = outbound.into_split();
-    let inbound = mux_stream.clone();
+async fn proxy_streams(mut outbound: TcpStream, mut mux_stream: mux::MuxStream) -> Result<()> {
+    copy_bidirectional(&mut outbound, &mut mux_stream).await?;
+    Ok(())
+}
 
-    let mux_to_outbound = tokio::spawn(async move {
-        while let Some(chunk) = inbound.recv_data().await {
-            outbound_write.write_all(&chunk).await?;
-        }
-        let _ = outbound_write.shutdown().await;
-        Ok::<(), anyhow::Error>(())
-    });
-
-    let outbound_to_mux = tokio::spawn(async move {
-        let mut buf = [0u8; 4096];
-        loop {
-            let n = outbound_read.read(&mut buf).await?;
-            if n == 0 {
-                let _ = mux_stream.send_fin().await;
-                break;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mux::SessionEnd;
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+
+    struct Server {
+        addr: String,
+        stop: oneshot::Sender<()>,
+        task: JoinHandle<()>,
+    }
+
+    async fn start_server(args: &[&str]) -> Server {
+        let cfg = ServerConfig::parse_from(
+            [
+                "btproxy-server",
+                "--transport",
+                "tcp",
+                "--drain-timeout-ms",
+                "500",
+            ]
+            .iter()
+            .chain(args),
+        );
+        let listener = btlink::TcpLinkListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(serve_listener(
+            LinkListener::Tcp(listener),
+            cfg,
+            MuxConfig::default(),
+            BtLinkConfig::default(),
+            async {
+                let _ = stopped.await;
+            },
+        ));
+        Server { addr, stop, task }
+    }
+
+    async fn connect(server: &Server) -> MuxSession {
+        let link = btlink::connect_tcp(&server.addr, BtLinkConfig::default())
+            .await
+            .unwrap();
+        MuxSession::start(link, MuxConfig::default(), Role::Client)
+            .await
+            .unwrap()
+    }
+
+    /// How the session ended, or `None` if it is still being served.
+    async fn ended(session: &MuxSession) -> Option<SessionEnd> {
+        timeout(Duration::from_millis(300), session.closed())
+            .await
+            .ok()
+    }
+
+    #[tokio::test]
+    async fn allow_serves_every_client() {
+        let server = start_server(&["--client-policy", "allow"]).await;
+        let first = connect(&server).await;
+        let second = connect(&server).await;
+        assert_eq!(ended(&first).await, None);
+        assert_eq!(ended(&second).await, None);
+    }
+
+    #[tokio::test]
+    async fn reject_turns_the_newcomer_away() {
+        let server = start_server(&["--client-policy", "reject"]).await;
+        let first = connect(&server).await;
+        sleep(Duration::from_millis(50)).await;
+        let second = connect(&server).await;
+        assert_eq!(
+            ended(&second).await,
+            Some(SessionEnd::GoAway {
+                code: GOAWAY_REJECTED
+            })
+        );
+        assert_eq!(ended(&first).await, None);
+    }
+
+    #[tokio::test]
+    async fn kick_old_replaces_the_existing_client() {
+        let server = start_server(&["--client-policy", "kick-old"]).await;
+        let first = connect(&server).await;
+        sleep(Duration::from_millis(50)).await;
+        let second = connect(&server).await;
+        assert_eq!(
+            ended(&first).await,
+            Some(SessionEnd::GoAway {
+                code: GOAWAY_REPLACED
+            })
+        );
+        assert_eq!(ended(&second).await, None);
+    }
+
+    #[tokio::test]
+    async fn clients_arriving_together_leave_one_served() {
+        for policy in ["reject", "kick-old"] {
+            let server = start_server(&["--client-policy", policy]).await;
+            let clients = tokio::join!(
+                connect(&server),
+                connect(&server),
+                connect(&server),
+                connect(&server)
+            );
+            let clients = [clients.0, clients.1, clients.2, clients.3];
+            let mut served = 0;
+            for client in &clients {
+                if ended(client).await.is_none() {
+                    served += 1;
+                }
             }
-            mux_stream
-                .send_data(Bytes::copy_from_slice(&buf[..n]))
-                .await
-                .map_err(|_| anyhow::anyhow!("mux send failed"))?;
+            assert_eq!(served, 1, "{} served {} clients", policy, served);
         }
-        Ok::<(), anyhow::Error>(())
-    });
+    }
 
-    let _ = tokio::try_join!(mux_to_outbound, outbound_to_mux)?;
-    Ok(())
+    #[tokio::test]
+    async fn silent_connection_is_dropped_after_the_handshake_timeout() {
+        let server = start_server(&["--handshake-timeout-ms", "200"]).await;
+        let mut silent = TcpStream::connect(&server.addr).await.unwrap();
+        let read = timeout(Duration::from_secs(5), silent.read(&mut [0; 64]))
+            .await
+            .expect("connection kept open without a handshake");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        // The server goes on accepting.
+        let client = connect(&server).await;
+        assert_eq!(ended(&client).await, None);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_sessions_and_drops_handshakes() {
+        let server = start_server(&[]).await;
+        let client = connect(&server).await;
+        let mut silent = TcpStream::connect(&server.addr).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+
+        server.stop.send(()).unwrap();
+        timeout(Duration::from_secs(5), server.task)
+            .await
+            .expect("shutdown waited for the handshake")
+            .unwrap();
+        assert_eq!(
+            ended(&client).await,
+            Some(SessionEnd::GoAway {
+                code: GOAWAY_SHUTDOWN
+            })
+        );
+        let read = timeout(Duration::from_secs(5), silent.read(&mut [0; 64]))
+            .await
+            .expect("handshake outlived the server");
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
 }
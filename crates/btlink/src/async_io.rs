@@ -0,0 +1,131 @@
+use crate::link::{
+    stats_loop, Batcher, BtLink, BtLinkConfig, BtLinkHandle, CloseReason, LinkCounters, Worker,
+};
+use bytes::{Bytes, BytesMut};
+use std::sync::{atomic::Ordering, Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Drives any pair of tokio byte streams (child pipes, stdin/stdout, ...) as
+/// a link. The halves are dropped when the tasks stop, which closes pipes;
+/// there is no socket to shut down. Both tasks stop on the closing signal,
+/// which `close()` and a failure of either direction raise, even in the
+/// middle of a write. Must be called from within a tokio runtime.
+pub fn spawn_async_io<R, W>(reader: R, writer: W, cfg: BtLinkConfig) -> BtLink
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (tx_outgoing, rx_outgoing) = mpsc::channel::<Bytes>(cfg.queue_bound);
+    let (tx_incoming, rx_incoming) = mpsc::channel::<Bytes>(cfg.queue_bound);
+    let counters = Arc::new(LinkCounters::default());
+    let handle = BtLinkHandle::new(|| {});
+
+    let read_task = tokio::spawn(read_loop(
+        reader,
+        tx_incoming,
+        cfg.max_chunk,
+        Arc::clone(&counters),
+        handle.clone(),
+    ));
+    handle.add_worker(Worker::Task(read_task));
+    let write_task = tokio::spawn(write_loop(
+        writer,
+        Batcher::new(rx_outgoing, &cfg),
+        Arc::clone(&counters),
+        handle.clone(),
+    ));
+    handle.add_worker(Worker::Task(write_task));
+
+    if let Some(period) = cfg.stats_interval {
+        let stats = tokio::spawn(stats_loop(handle.clone(), counters, period));
+        handle.add_worker(Worker::Task(stats));
+    }
+
+    BtLink {
+        tx: tx_outgoing,
+        rx: rx_incoming,
+        handle,
+    }
+}
+
+async fn read_loop<R>(
+    mut reader: R,
+    tx: mpsc::Sender<Bytes>,
+    max_chunk: usize,
+    counters: Arc<LinkCounters>,
+    handle: BtLinkHandle,
+) where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        // See `BtLink::spawn` for why every read gets a fresh buffer.
+        let mut buf = BytesMut::with_capacity(max_chunk);
+        let res = tokio::select! {
+            _ = tx.closed() => {
+                handle.set_reason(CloseReason::LocalShutdown);
+                break;
+            }
+            _ = handle.closing() => break,
+            res = reader.read_buf(&mut buf) => res,
+        };
+        match res {
+            Ok(0) => {
+                debug!("btlink reader eof");
+                handle.set_reason(CloseReason::PeerEof);
+                break;
+            }
+            Ok(n) => {
+                counters.rx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                let sent = tokio::select! {
+                    sent = tx.send(buf.freeze()) => sent,
+                    _ = handle.closing() => break,
+                };
+                if sent.is_err() {
+                    handle.set_reason(CloseReason::LocalShutdown);
+                    break;
+                }
+            }
+            Err(err) => {
+                debug!(?err, "btlink reader error");
+                handle.fail(&err);
+                break;
+            }
+        }
+    }
+}
+
+async fn write_loop<W>(
+    mut writer: W,
+    mut batcher: Batcher,
+    counters: Arc<LinkCounters>,
+    handle: BtLinkHandle,
+) where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let chunk = tokio::select! {
+            chunk = batcher.next() => chunk,
+            _ = handle.closing() => None,
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        counters.count_write(chunk.len());
+        // A peer that stopped reading must not keep `close()` waiting.
+        let res = tokio::select! {
+            res = async {
+                writer.write_all(&chunk).await?;
+                writer.flush().await
+            } => res,
+            _ = handle.closing() => return,
+        };
+        if let Err(err) = res {
+            debug!(?err, "btlink writer error");
+            handle.fail(&err);
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
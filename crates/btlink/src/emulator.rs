@@ -0,0 +1,272 @@
+use crate::link::{
+    stats_loop, Batcher, BtLink, BtLinkConfig, BtLinkHandle, CloseReason, LinkCounters, Worker,
+};
+use bytes::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tracing::info;
+
+/// Shape of an emulated link. The default is an ideal, instantaneous pipe.
+#[derive(Debug, Clone, Default)]
+pub struct EmulatorConfig {
+    /// Throughput cap per direction in bytes per second; `None` is unlimited.
+    pub bandwidth: Option<u64>,
+    /// One-way delay added to every fragment.
+    pub latency: Duration,
+    /// Extra random delay in `0..=jitter` per fragment. Delivery order is
+    /// preserved, so jitter shows up as bursts rather than reordering.
+    pub jitter: Duration,
+    /// Split writes into fragments of random length up to this many bytes.
+    pub max_fragment: Option<usize>,
+    /// Reset the link this long after it was created.
+    pub disconnect_after: Option<Duration>,
+    /// Reset the link once this many bytes have crossed it, both directions
+    /// counted together.
+    pub disconnect_after_bytes: Option<u64>,
+    /// Seed for jitter and fragmentation so a failing run can be replayed.
+    pub seed: u64,
+}
+
+impl EmulatorConfig {
+    /// Roughly what a classic RFCOMM channel delivers in practice: a few
+    /// hundred kbit/s, tens of milliseconds of bursty latency and L2CAP-sized
+    /// fragments.
+    pub fn rfcomm() -> Self {
+        Self {
+            bandwidth: Some(40_000),
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(30),
+            max_fragment: Some(1013),
+            ..Self::default()
+        }
+    }
+}
+
+struct Wire {
+    cut: watch::Sender<bool>,
+    a: BtLinkHandle,
+    b: BtLinkHandle,
+    transferred: AtomicU64,
+    byte_limit: Option<u64>,
+}
+
+impl Wire {
+    fn reset(&self) {
+        let reason = CloseReason::from_io(&io::ErrorKind::ConnectionReset.into());
+        self.a.set_reason(reason.clone());
+        self.b.set_reason(reason);
+        if !self.cut.send_replace(true) {
+            info!("emulated link cut");
+        }
+    }
+
+    async fn cut(&self) {
+        let mut rx = self.cut.subscribe();
+        let _ = rx.wait_for(|cut| *cut).await;
+    }
+
+    fn account(&self, len: usize) {
+        let total = self.transferred.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+        if self.byte_limit.is_some_and(|limit| total >= limit) {
+            self.reset();
+        }
+    }
+}
+
+/// Creates two connected in-process links whose traffic is shaped by `emu`.
+/// Must be called from within a tokio runtime.
+pub fn emulated_pair(emu: EmulatorConfig, cfg: BtLinkConfig) -> (BtLink, BtLink) {
+    let handle_a = BtLinkHandle::new(|| {});
+    let handle_b = BtLinkHandle::new(|| {});
+    let (cut, _) = watch::channel(false);
+    let wire = Arc::new(Wire {
+        cut,
+        a: handle_a.clone(),
+        b: handle_b.clone(),
+        transferred: AtomicU64::new(0),
+        byte_limit: emu.disconnect_after_bytes,
+    });
+    let counters_a = Arc::new(LinkCounters::default());
+    let counters_b = Arc::new(LinkCounters::default());
+
+    let (tx_a, out_a) = mpsc::channel::<Bytes>(cfg.queue_bound);
+    let (in_a, rx_a) = mpsc::channel::<Bytes>(cfg.queue_bound);
+    let (tx_b, out_b) = mpsc::channel::<Bytes>(cfg.queue_bound);
+    let (in_b, rx_b) = mpsc::channel::<Bytes>(cfg.queue_bound);
+
+    spawn_direction(
+        &emu,
+        emu.seed,
+        &cfg,
+        &wire,
+        (out_a, &handle_a, &counters_a),
+        (in_b, &handle_b, &counters_b),
+    );
+    spawn_direction(
+        &emu,
+        emu.seed.wrapping_add(1),
+        &cfg,
+        &wire,
+        (out_b, &handle_b, &counters_b),
+        (in_a, &handle_a, &counters_a),
+    );
+
+    if let Some(after) = emu.disconnect_after {
+        let wire_timer = Arc::clone(&wire);
+        let timer = tokio::spawn(async move {
+            tokio::select! {
+                _ = sleep(after) => wire_timer.reset(),
+                _ = async {
+                    wire_timer.a.closed().await;
+                    wire_timer.b.closed().await;
+                } => {}
+            }
+        });
+        handle_a.add_worker(Worker::Task(timer));
+    }
+
+    if let Some(period) = cfg.stats_interval {
+        let stats_a = tokio::spawn(stats_loop(handle_a.clone(), counters_a, period));
+        handle_a.add_worker(Worker::Task(stats_a));
+        let stats_b = tokio::spawn(stats_loop(handle_b.clone(), counters_b, period));
+        handle_b.add_worker(Worker::Task(stats_b));
+    }
+
+    (
+        BtLink {
+            tx: tx_a,
+            rx: rx_a,
+            handle: handle_a,
+        },
+        BtLink {
+            tx: tx_b,
+            rx: rx_b,
+            handle: handle_b,
+        },
+    )
+}
+
+type Endpoint<'a, C> = (C, &'a BtLinkHandle, &'a Arc<LinkCounters>);
+
+/// One direction is two tasks: a pacer that fragments writes and holds them
+/// back to the bandwidth cap, and a deliverer that releases each fragment
+/// once its latency has elapsed.
+fn spawn_direction(
+    emu: &EmulatorConfig,
+    seed: u64,
+    cfg: &BtLinkConfig,
+    wire: &Arc<Wire>,
+    (input, src, src_counters): Endpoint<'_, mpsc::Receiver<Bytes>>,
+    (output, dst, dst_counters): Endpoint<'_, mpsc::Sender<Bytes>>,
+) {
+    let (staged_tx, staged_rx) = mpsc::channel::<(Instant, Bytes)>(cfg.queue_bound);
+
+    let pacer = {
+        let emu = emu.clone();
+        let wire = Arc::clone(wire);
+        let src = src.clone();
+        let dst = dst.clone();
+        let counters = Arc::clone(src_counters);
+        let batcher = Batcher::new(input, cfg);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = pace(batcher, staged_tx, &emu, seed, &wire, &counters) => {}
+                _ = src.closing() => dst.set_reason(CloseReason::PeerEof),
+                _ = dst.closing() => {}
+                _ = wire.cut() => {}
+            }
+        })
+    };
+    src.add_worker(Worker::Task(pacer));
+
+    let deliverer = {
+        let wire = Arc::clone(wire);
+        let src = src.clone();
+        let dst = dst.clone();
+        let counters = Arc::clone(dst_counters);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = deliver(staged_rx, output, &dst, &counters) => {}
+                _ = src.closing() => dst.set_reason(CloseReason::PeerEof),
+                _ = dst.closing() => {}
+                _ = wire.cut() => {}
+            }
+        })
+    };
+    src.add_worker(Worker::Task(deliverer));
+}
+
+async fn pace(
+    mut input: Batcher,
+    staged: mpsc::Sender<(Instant, Bytes)>,
+    emu: &EmulatorConfig,
+    seed: u64,
+    wire: &Wire,
+    counters: &LinkCounters,
+) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut next_free = Instant::now();
+    let mut last_arrival = Instant::now();
+    while let Some(mut chunk) = input.next().await {
+        counters.count_write(chunk.len());
+        while !chunk.is_empty() {
+            let len = match emu.max_fragment {
+                Some(max) => rng.gen_range(1..=max.max(1)).min(chunk.len()),
+                None => chunk.len(),
+            };
+            let fragment = chunk.split_to(len);
+            if let Some(bandwidth) = emu.bandwidth {
+                let airtime = Duration::from_secs_f64(len as f64 / bandwidth.max(1) as f64);
+                next_free = next_free.max(Instant::now()) + airtime;
+                sleep_until(next_free).await;
+            }
+            wire.account(len);
+            let jitter = if emu.jitter.is_zero() {
+                Duration::ZERO
+            } else {
+                Duration::from_nanos(rng.gen_range(0..=emu.jitter.as_nanos() as u64))
+            };
+            let arrival = (Instant::now() + emu.latency + jitter).max(last_arrival);
+            last_arrival = arrival;
+            if staged.send((arrival, fragment)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn deliver(
+    mut staged: mpsc::Receiver<(Instant, Bytes)>,
+    output: mpsc::Sender<Bytes>,
+    dst: &BtLinkHandle,
+    counters: &LinkCounters,
+) {
+    loop {
+        let next = tokio::select! {
+            next = staged.recv() => next,
+            _ = output.closed() => {
+                dst.set_reason(CloseReason::LocalShutdown);
+                return;
+            }
+        };
+        let Some((arrival, fragment)) = next else {
+            break;
+        };
+        sleep_until(arrival).await;
+        counters
+            .rx_bytes
+            .fetch_add(fragment.len() as u64, Ordering::Relaxed);
+        if output.send(fragment).await.is_err() {
+            dst.set_reason(CloseReason::LocalShutdown);
+            return;
+        }
+    }
+    dst.set_reason(CloseReason::PeerEof);
+}
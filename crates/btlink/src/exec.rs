@@ -0,0 +1,178 @@
+use crate::async_io::spawn_async_io;
+use crate::link::{BtLink, BtLinkConfig, Worker};
+use bytes::Bytes;
+use common::error::{BtProxyError, Result};
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Spawns `command` through the platform shell and uses its stdin/stdout as
+/// the link, e.g. `ssh host btproxy-server --transport stdio`. The child's
+/// stderr is passed through; closing the link kills the child.
+pub async fn spawn_command(command: &str, cfg: BtLinkConfig) -> Result<BtLink> {
+    info!(%command, "spawning transport command");
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| BtProxyError::Protocol("transport command has no stdin".to_string()))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| BtProxyError::Protocol("transport command has no stdout".to_string()))?;
+    info!(pid = child.id(), "transport command started");
+
+    let link = spawn_async_io(stdout, stdin, cfg);
+    let handle = link.handle.clone();
+    let reaper = tokio::spawn(async move {
+        tokio::select! {
+            status = child.wait() => match status {
+                Ok(status) if status.success() => info!(%status, "transport command exited"),
+                Ok(status) => warn!(%status, "transport command exited"),
+                Err(err) => warn!(?err, "failed to wait for transport command"),
+            },
+            _ = handle.closing() => {
+                let _ = child.kill().await;
+            }
+        }
+    });
+    link.handle.add_worker(Worker::Task(reaper));
+    Ok(link)
+}
+
+/// Serves the link on this process's own stdin/stdout. Anything else the
+/// process prints must go to stderr.
+pub fn stdio_link(cfg: BtLinkConfig) -> BtLink {
+    info!("serving link on stdin/stdout");
+    let stdin = read_on_thread(std::io::stdin(), cfg.max_chunk);
+    spawn_async_io(stdin, tokio::io::stdout(), cfg)
+}
+
+/// Reads `reader` on a thread of its own. `tokio::io::stdin()` reads on the
+/// blocking pool, and a read nothing answers there holds up runtime
+/// shutdown; this thread is detached instead, so it is simply left parked
+/// in `read` until the process exits.
+fn read_on_thread<R>(mut reader: R, max_chunk: usize) -> ThreadReader
+where
+    R: Read + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(1);
+    std::thread::spawn(move || loop {
+        let mut buf = vec![0; max_chunk];
+        let res = match reader.read(&mut buf) {
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(Bytes::from(buf))
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        };
+        let stop = !matches!(&res, Ok(chunk) if !chunk.is_empty());
+        if tx.blocking_send(res).is_err() || stop {
+            break;
+        }
+    });
+    ThreadReader {
+        rx,
+        pending: Bytes::new(),
+    }
+}
+
+/// The async end of `read_on_thread`.
+struct ThreadReader {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    /// Part of a chunk that did not fit the caller's buffer.
+    pending: Bytes,
+}
+
+impl AsyncRead for ThreadReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pending.is_empty() {
+            match ready!(self.rx.poll_recv(cx)) {
+                Some(Ok(chunk)) => self.pending = chunk,
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+                // The thread saw EOF.
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn thread_reader_delivers_everything_then_eof() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let writer = {
+            let data = data.clone();
+            std::thread::spawn(move || theirs.write_all(&data))
+        };
+        let mut received = Vec::new();
+        read_on_thread(ours, 4096)
+            .read_to_end(&mut received)
+            .await
+            .unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(received == data);
+    }
+
+    #[test]
+    fn parked_reader_does_not_hold_up_runtime_shutdown() {
+        // Nothing is ever written to the other end.
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let link = runtime.block_on(async {
+                let stdin = read_on_thread(ours, 4096);
+                let link = spawn_async_io(stdin, tokio::io::sink(), BtLinkConfig::default());
+                link.close().await;
+                link
+            });
+            drop(runtime);
+            drop(link);
+            let _ = done_tx.send(());
+        });
+        done_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("runtime shutdown waited for the reader");
+    }
+}
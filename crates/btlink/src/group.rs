@@ -0,0 +1,215 @@
+use crate::link::{BtLink, BtLinkHandle, CloseReason};
+use bytes::Bytes;
+use common::error::{BtProxyError, Result};
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::{mpsc, watch};
+use tracing::info;
+
+pub type LinkId = u32;
+
+struct Member {
+    id: LinkId,
+    tx: mpsc::Sender<Bytes>,
+    handle: BtLinkHandle,
+}
+
+struct GroupInner {
+    members: Mutex<Vec<Member>>,
+    next_id: AtomicU32,
+    rotate: AtomicUsize,
+    /// Bumped on every join and leave.
+    generation: watch::Sender<u64>,
+    /// Set once the last member has left; the group cannot be reused.
+    closed: watch::Sender<Option<CloseReason>>,
+}
+
+/// A set of links carrying one session. Members leave on their own when
+/// their link closes; the group is closed once the last one is gone.
+///
+/// The group only owns the sending side. `add` hands the receiving side back
+/// so the caller can decode each link with its own framing state.
+#[derive(Clone)]
+pub struct LinkGroup {
+    inner: Arc<GroupInner>,
+}
+
+impl Default for LinkGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkGroup {
+    pub fn new() -> Self {
+        let (generation, _) = watch::channel(0);
+        let (closed, _) = watch::channel(None);
+        Self {
+            inner: Arc::new(GroupInner {
+                members: Mutex::new(Vec::new()),
+                next_id: AtomicU32::new(1),
+                rotate: AtomicUsize::new(0),
+                generation,
+                closed,
+            }),
+        }
+    }
+
+    /// Adds `link` to the group. Must be called from within a tokio runtime.
+    pub fn add(&self, link: BtLink) -> Result<(LinkId, mpsc::Receiver<Bytes>)> {
+        if self.is_closed() {
+            return Err(BtProxyError::Io(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "link group closed",
+            )));
+        }
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let handle = link.handle.clone();
+        self.inner.members.lock().unwrap().push(Member {
+            id,
+            tx: link.tx,
+            handle: link.handle,
+        });
+        self.inner.generation.send_modify(|gen| *gen += 1);
+        info!(link = id, members = self.len(), "link joined group");
+
+        let weak: Weak<GroupInner> = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let reason = handle.closed().await;
+            if let Some(inner) = weak.upgrade() {
+                LinkGroup { inner }.remove(id, reason);
+            }
+        });
+        Ok((id, link.rx))
+    }
+
+    fn remove(&self, id: LinkId, reason: CloseReason) {
+        let remaining = {
+            let mut members = self.inner.members.lock().unwrap();
+            members.retain(|member| member.id != id);
+            members.len()
+        };
+        self.inner.generation.send_modify(|gen| *gen += 1);
+        info!(link = id, %reason, members = remaining, "link left group");
+        if remaining == 0 {
+            self.inner.closed.send_if_modified(|closed| {
+                if closed.is_none() {
+                    *closed = Some(reason);
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.members.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, id: LinkId) -> bool {
+        self.inner
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|member| member.id == id)
+    }
+
+    /// Picks the member with the most free queue space, rotating between
+    /// equally loaded ones so idle links share the traffic.
+    pub fn pick(&self) -> Option<(LinkId, mpsc::Sender<Bytes>)> {
+        let members = self.inner.members.lock().unwrap();
+        if members.is_empty() {
+            return None;
+        }
+        let start = self.inner.rotate.fetch_add(1, Ordering::Relaxed) % members.len();
+        let best = (0..members.len())
+            .map(|offset| &members[(start + offset) % members.len()])
+            .filter(|member| !member.tx.is_closed())
+            .max_by_key(|member| member.tx.capacity())?;
+        Some((best.id, best.tx.clone()))
+    }
+
+    pub fn sender(&self, id: LinkId) -> Option<mpsc::Sender<Bytes>> {
+        self.inner
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|member| member.id == id)
+            .map(|member| member.tx.clone())
+    }
+
+    pub fn handle(&self, id: LinkId) -> Option<BtLinkHandle> {
+        self.inner
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|member| member.id == id)
+            .map(|member| member.handle.clone())
+    }
+
+    /// Resolves once every member's writer has taken all queued chunks.
+    pub async fn flushed(&self) {
+        let senders: Vec<mpsc::Sender<Bytes>> = self
+            .inner
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|member| member.tx.clone())
+            .collect();
+        for tx in senders {
+            // Fails only if the link is gone, which leaves nothing to flush.
+            let _ = tx.reserve_many(tx.max_capacity()).await;
+        }
+    }
+
+    /// Changes whenever a link joins or leaves.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.inner.generation.subscribe()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.borrow().is_some()
+    }
+
+    /// Resolves once every link has left, with the reason the last one
+    /// closed.
+    pub async fn closed(&self) -> CloseReason {
+        let mut rx = self.inner.closed.subscribe();
+        let reason = match rx.wait_for(|reason| reason.is_some()).await {
+            Ok(reason) => reason.clone(),
+            Err(_) => None,
+        };
+        reason.unwrap_or(CloseReason::LocalShutdown)
+    }
+
+    /// Closes every member and waits for the group to close.
+    pub async fn close(&self) -> CloseReason {
+        self.close_with(CloseReason::LocalShutdown).await
+    }
+
+    /// Like `close`, giving every member `reason`.
+    pub async fn close_with(&self, reason: CloseReason) -> CloseReason {
+        let handles: Vec<BtLinkHandle> = self
+            .inner
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|member| member.handle.clone())
+            .collect();
+        for handle in handles {
+            handle.close_with(reason.clone()).await;
+        }
+        self.closed().await
+    }
+}
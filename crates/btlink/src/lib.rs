@@ -1,13 +1,28 @@
+pub mod async_io;
+pub mod emulator;
+pub mod exec;
+pub mod group;
 pub mod link;
+pub mod listener;
+pub mod tcp;
 
 #[cfg(target_os = "linux")]
 pub mod linux;
 #[cfg(target_os = "windows")]
 pub mod windows;
 
-pub use link::{BtLink, BtLinkConfig};
+pub use async_io::spawn_async_io;
+pub use emulator::{emulated_pair, EmulatorConfig};
+pub use exec::{spawn_command, stdio_link};
+pub use group::{LinkGroup, LinkId};
+pub use link::{BtLink, BtLinkConfig, BtLinkHandle, CloseReason};
+pub use listener::LinkListener;
+pub use tcp::{accept_tcp, connect_tcp, TcpLinkListener};
 
 #[cfg(target_os = "linux")]
-pub use linux::{accept_linux_rfcomm, connect_linux_rfcomm};
+pub use linux::{
+    accept_linux_rfcomm, accept_linux_uds, connect_linux_rfcomm, connect_linux_uds, open_linux_tty,
+    spawn_async_fd, RfcommListener, TtyConfig, UdsListener,
+};
 #[cfg(target_os = "windows")]
-pub use windows::{accept_windows_rfcomm, connect_windows_rfcomm};
+pub use windows::{accept_windows_rfcomm, connect_windows_rfcomm, RfcommListener};
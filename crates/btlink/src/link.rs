@@ -1,15 +1,19 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use common::error::{BtProxyError, Result};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, Mutex,
 };
 use std::thread;
 use std::time::{Duration, Instant};
-use tokio::sync::mpsc;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout_at};
 use tracing::{debug, info};
 
 #[derive(Debug, Clone)]
@@ -17,6 +21,11 @@ pub struct BtLinkConfig {
     pub max_chunk: usize,
     pub queue_bound: usize,
     pub stats_interval: Option<Duration>,
+    /// Queued chunks are gathered into one write of up to this many bytes;
+    /// 0 writes each chunk on its own.
+    pub write_batch: usize,
+    /// How long a write that is not full waits for more chunks.
+    pub write_delay: Option<Duration>,
 }
 
 impl Default for BtLinkConfig {
@@ -25,6 +34,48 @@ impl Default for BtLinkConfig {
             max_chunk: 4096,
             queue_bound: 256,
             stats_interval: Some(Duration::from_secs(5)),
+            write_batch: 4096,
+            write_delay: None,
+        }
+    }
+}
+
+/// Why a link stopped carrying bytes. The first cause observed wins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The peer closed its side of the stream.
+    PeerEof,
+    /// A read or write on the underlying socket failed.
+    Io {
+        kind: io::ErrorKind,
+        errno: Option<i32>,
+    },
+    /// The owner called `close()` or dropped its end of the channels.
+    LocalShutdown,
+    /// The link still looked open but the peer stopped answering.
+    Timeout,
+}
+
+impl CloseReason {
+    pub fn from_io(err: &io::Error) -> Self {
+        CloseReason::Io {
+            kind: err.kind(),
+            errno: err.raw_os_error(),
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::PeerEof => write!(f, "peer eof"),
+            CloseReason::Io {
+                kind,
+                errno: Some(errno),
+            } => write!(f, "io error: {} (errno {})", kind, errno),
+            CloseReason::Io { kind, errno: None } => write!(f, "io error: {}", kind),
+            CloseReason::LocalShutdown => write!(f, "local shutdown"),
+            CloseReason::Timeout => write!(f, "peer timed out"),
         }
     }
 }
@@ -32,117 +83,397 @@ impl Default for BtLinkConfig {
 pub struct BtLink {
     pub tx: mpsc::Sender<Bytes>,
     pub rx: mpsc::Receiver<Bytes>,
+    pub handle: BtLinkHandle,
+}
+
+/// Cloneable control side of a `BtLink`, kept by the owner after `tx`/`rx`
+/// have been moved into its own tasks.
+#[derive(Clone)]
+pub struct BtLinkHandle {
+    shared: Arc<LinkShared>,
+}
+
+struct LinkShared {
+    reason: watch::Sender<Option<CloseReason>>,
+    closing: watch::Sender<bool>,
+    shutdown: Box<dyn Fn() + Send + Sync>,
+    workers: Mutex<Vec<Worker>>,
+}
+
+pub(crate) enum Worker {
+    Thread(thread::JoinHandle<()>),
+    Task(JoinHandle<()>),
+}
+
+impl BtLinkHandle {
+    /// `shutdown` must unblock any pending read or write on the transport,
+    /// typically `shutdown(SHUT_RDWR)` on the socket.
+    pub(crate) fn new<F>(shutdown: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let (reason, _) = watch::channel(None);
+        let (closing, _) = watch::channel(false);
+        Self {
+            shared: Arc::new(LinkShared {
+                reason,
+                closing,
+                shutdown: Box::new(shutdown),
+                workers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub(crate) fn add_worker(&self, worker: Worker) {
+        self.shared
+            .workers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(worker);
+    }
+
+    pub(crate) fn set_reason(&self, reason: CloseReason) {
+        self.shared.reason.send_if_modified(|current| {
+            if current.is_none() {
+                *current = Some(reason);
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Records a fatal I/O error and tears the transport down so the other
+    /// direction does not stay parked on a dead socket. Also raises the
+    /// closing signal, which is what stops the other direction on fds that
+    /// have no shutdown.
+    pub(crate) fn fail(&self, err: &io::Error) {
+        self.set_reason(CloseReason::from_io(err));
+        self.shared.closing.send_replace(true);
+        (self.shared.shutdown)();
+    }
+
+    /// Resolves once `close()` has been requested.
+    pub(crate) async fn closing(&self) {
+        let mut rx = self.shared.closing.subscribe();
+        let _ = rx.wait_for(|closing| *closing).await;
+    }
+
+    pub fn reason(&self) -> Option<CloseReason> {
+        self.shared.reason.borrow().clone()
+    }
+
+    /// Waits until the link has stopped and returns why.
+    pub async fn closed(&self) -> CloseReason {
+        let mut rx = self.shared.reason.subscribe();
+        let reason = match rx.wait_for(|reason| reason.is_some()).await {
+            Ok(reason) => reason.clone(),
+            Err(_) => None,
+        };
+        reason.unwrap_or(CloseReason::LocalShutdown)
+    }
+
+    /// Shuts the transport down in both directions and waits until every
+    /// reader, writer and stats worker has exited.
+    pub async fn close(&self) -> CloseReason {
+        self.close_with(CloseReason::LocalShutdown).await
+    }
+
+    /// Like `close`, recording `reason` unless the link already stopped for
+    /// another one.
+    pub async fn close_with(&self, reason: CloseReason) -> CloseReason {
+        self.set_reason(reason);
+        self.shared.closing.send_replace(true);
+        (self.shared.shutdown)();
+        let workers = std::mem::take(
+            &mut *self
+                .shared
+                .workers
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()),
+        );
+        for worker in workers {
+            match worker {
+                Worker::Thread(handle) => {
+                    let _ = tokio::task::spawn_blocking(move || handle.join()).await;
+                }
+                Worker::Task(handle) => {
+                    let _ = handle.await;
+                }
+            }
+        }
+        self.closed().await
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct LinkCounters {
+    pub rx_bytes: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub tx_writes: AtomicU64,
+}
+
+impl LinkCounters {
+    pub fn count_write(&self, len: usize) {
+        self.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.tx_writes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Hands a link writer its next write: whatever is queued, joined into one
+/// buffer of up to `write_batch` bytes, so small frames share a packet.
+pub(crate) struct Batcher {
+    rx: mpsc::Receiver<Bytes>,
+    max: usize,
+    delay: Option<Duration>,
+    /// A chunk that did not fit into the previous batch.
+    held: Option<Bytes>,
+}
+
+impl Batcher {
+    pub fn new(rx: mpsc::Receiver<Bytes>, cfg: &BtLinkConfig) -> Self {
+        Self {
+            rx,
+            max: cfg.write_batch,
+            delay: cfg.write_delay,
+            held: None,
+        }
+    }
+
+    /// Returns `None` once the sender is gone and nothing is left.
+    pub async fn next(&mut self) -> Option<Bytes> {
+        let first = match self.held.take() {
+            Some(chunk) => chunk,
+            None => self.rx.recv().await?,
+        };
+        if first.len() >= self.max {
+            return Some(first);
+        }
+        let deadline = self.delay.map(|delay| tokio::time::Instant::now() + delay);
+        let mut batch: Option<BytesMut> = None;
+        loop {
+            let chunk = match self.rx.try_recv() {
+                Ok(chunk) => chunk,
+                Err(TryRecvError::Empty) => match deadline {
+                    Some(deadline) => match timeout_at(deadline, self.rx.recv()).await {
+                        Ok(Some(chunk)) => chunk,
+                        _ => break,
+                    },
+                    None => break,
+                },
+                Err(TryRecvError::Disconnected) => break,
+            };
+            let len = batch.as_ref().map_or(first.len(), BytesMut::len);
+            if len + chunk.len() > self.max {
+                self.held = Some(chunk);
+                break;
+            }
+            batch
+                .get_or_insert_with(|| {
+                    let mut batch = BytesMut::with_capacity(self.max);
+                    batch.extend_from_slice(&first);
+                    batch
+                })
+                .extend_from_slice(&chunk);
+        }
+        Some(batch.map_or(first, BytesMut::freeze))
+    }
+}
+
+pub(crate) struct ThroughputLog {
+    last_rx: u64,
+    last_tx: u64,
+    last_writes: u64,
+    last_at: Instant,
+}
+
+impl ThroughputLog {
+    pub fn new() -> Self {
+        Self {
+            last_rx: 0,
+            last_tx: 0,
+            last_writes: 0,
+            last_at: Instant::now(),
+        }
+    }
+
+    pub fn tick(&mut self, counters: &LinkCounters) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_at);
+        let rx_total = counters.rx_bytes.load(Ordering::Relaxed);
+        let tx_total = counters.tx_bytes.load(Ordering::Relaxed);
+        let writes_total = counters.tx_writes.load(Ordering::Relaxed);
+        let rx_delta = rx_total.saturating_sub(self.last_rx);
+        let tx_delta = tx_total.saturating_sub(self.last_tx);
+        self.last_rx = rx_total;
+        self.last_tx = tx_total;
+        let writes_delta = writes_total.saturating_sub(self.last_writes);
+        self.last_writes = writes_total;
+        self.last_at = now;
+
+        let elapsed_secs = elapsed.as_secs_f64().max(0.001);
+        let rx_rate = rx_delta as f64 / elapsed_secs;
+        let tx_rate = tx_delta as f64 / elapsed_secs;
+        info!(
+            rx_bytes = rx_total,
+            tx_bytes = tx_total,
+            rx_bps = rx_rate,
+            tx_bps = tx_rate,
+            tx_writes = writes_total,
+            tx_bytes_per_write = tx_delta.checked_div(writes_delta).unwrap_or(0),
+            "btlink throughput"
+        );
+    }
+}
+
+/// Logs throughput every `period` until the link has a close reason.
+pub(crate) async fn stats_loop(
+    handle: BtLinkHandle,
+    counters: Arc<LinkCounters>,
+    period: Duration,
+) {
+    let mut ticker = interval(period);
+    ticker.tick().await;
+    let mut log = ThroughputLog::new();
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => log.tick(&counters),
+            _ = handle.closed() => break,
+        }
+    }
 }
 
 impl BtLink {
+    /// Bridges a blocking stream through reader/writer threads. Must be
+    /// called from within a tokio runtime.
     pub fn spawn<S>(stream: S, cfg: BtLinkConfig) -> Result<Self>
     where
         S: BtStream,
     {
+        let runtime = Handle::try_current()
+            .map_err(|_| BtProxyError::Unsupported("btlink needs a tokio runtime".to_string()))?;
         let mut reader = stream.try_clone()?;
+        let control = Mutex::new(stream.try_clone()?);
         let mut writer = stream;
-        let (tx_outgoing, mut rx_outgoing) = mpsc::channel::<Bytes>(cfg.queue_bound);
+        let (tx_outgoing, rx_outgoing) = mpsc::channel::<Bytes>(cfg.queue_bound);
         let (tx_incoming, rx_incoming) = mpsc::channel::<Bytes>(cfg.queue_bound);
 
         let max_chunk = cfg.max_chunk;
-        let tx_incoming_clone = tx_incoming.clone();
-
-        let rx_bytes = Arc::new(AtomicU64::new(0));
-        let tx_bytes = Arc::new(AtomicU64::new(0));
-        let stats_interval = cfg.stats_interval;
-        let rx_bytes_reader = Arc::clone(&rx_bytes);
-        thread::spawn(move || {
-            let mut buf = vec![0u8; max_chunk];
+        let counters = Arc::new(LinkCounters::default());
+        let handle = BtLinkHandle::new(move || {
+            if let Ok(control) = control.lock() {
+                let _ = control.shutdown(Shutdown::Both);
+            }
+        });
+
+        let counters_reader = Arc::clone(&counters);
+        let handle_reader = handle.clone();
+        let runtime_reader = runtime.clone();
+        let reader_thread = thread::spawn(move || {
             loop {
+                // A fresh buffer per read hands the mux a chunk it owns
+                // alone, so it can decode in place instead of copying.
+                let mut buf = BytesMut::zeroed(max_chunk);
                 match reader.read(&mut buf) {
                     Ok(0) => {
                         debug!("btlink reader eof");
+                        handle_reader.set_reason(CloseReason::PeerEof);
                         break;
                     }
                     Ok(n) => {
-                        rx_bytes_reader.fetch_add(n as u64, Ordering::Relaxed);
-                        if tx_incoming_clone
-                            .blocking_send(Bytes::copy_from_slice(&buf[..n]))
-                            .is_err()
-                        {
+                        counters_reader
+                            .rx_bytes
+                            .fetch_add(n as u64, Ordering::Relaxed);
+                        buf.truncate(n);
+                        let chunk = buf.freeze();
+                        let sent = runtime_reader.block_on(async {
+                            tokio::select! {
+                                res = tx_incoming.send(chunk) => res.is_ok(),
+                                _ = handle_reader.closing() => false,
+                            }
+                        });
+                        if !sent {
+                            handle_reader.set_reason(CloseReason::LocalShutdown);
                             break;
                         }
                     }
                     Err(err) => {
                         debug!(?err, "btlink reader error");
+                        handle_reader.fail(&err);
                         break;
                     }
                 }
             }
         });
+        handle.add_worker(Worker::Thread(reader_thread));
 
-        let tx_bytes_writer = Arc::clone(&tx_bytes);
-        thread::spawn(move || {
-            while let Some(chunk) = rx_outgoing.blocking_recv() {
-                tx_bytes_writer.fetch_add(chunk.len() as u64, Ordering::Relaxed);
-                if let Err(err) = writer.write_all(&chunk) {
-                    debug!(?err, "btlink writer error");
+        let counters_writer = Arc::clone(&counters);
+        let handle_writer = handle.clone();
+        let mut batcher = Batcher::new(rx_outgoing, &cfg);
+        let writer_thread = thread::spawn(move || {
+            loop {
+                let next = runtime.block_on(async {
+                    tokio::select! {
+                        chunk = batcher.next() => chunk,
+                        _ = handle_writer.closing() => None,
+                    }
+                });
+                let Some(chunk) = next else {
                     break;
+                };
+                counters_writer.count_write(chunk.len());
+                if let Err(err) = writer.write_all(&chunk).and_then(|_| writer.flush()) {
+                    debug!(?err, "btlink writer error");
+                    handle_writer.fail(&err);
+                    return;
                 }
             }
+            let _ = writer.shutdown(Shutdown::Write);
         });
+        handle.add_worker(Worker::Thread(writer_thread));
 
-        if let Some(interval) = stats_interval {
-            let rx_bytes_stats = Arc::clone(&rx_bytes);
-            let tx_bytes_stats = Arc::clone(&tx_bytes);
-            thread::spawn(move || {
-                let mut last_rx = 0u64;
-                let mut last_tx = 0u64;
-                let mut last_at = Instant::now();
-                loop {
-                    thread::sleep(interval);
-                    let now = Instant::now();
-                    let elapsed = now.duration_since(last_at);
-                    let rx_total = rx_bytes_stats.load(Ordering::Relaxed);
-                    let tx_total = tx_bytes_stats.load(Ordering::Relaxed);
-                    let rx_delta = rx_total.saturating_sub(last_rx);
-                    let tx_delta = tx_total.saturating_sub(last_tx);
-                    last_rx = rx_total;
-                    last_tx = tx_total;
-                    last_at = now;
-
-                    let elapsed_secs = elapsed.as_secs_f64().max(0.001);
-                    let rx_rate = rx_delta as f64 / elapsed_secs;
-                    let tx_rate = tx_delta as f64 / elapsed_secs;
-                    info!(
-                        rx_bytes = rx_total,
-                        tx_bytes = tx_total,
-                        rx_bps = rx_rate,
-                        tx_bps = tx_rate,
-                        "btlink throughput"
-                    );
-                }
-            });
+        if let Some(period) = cfg.stats_interval {
+            let stats = tokio::spawn(stats_loop(handle.clone(), counters, period));
+            handle.add_worker(Worker::Task(stats));
         }
 
         Ok(Self {
             tx: tx_outgoing,
             rx: rx_incoming,
+            handle,
         })
     }
+
+    /// See [`BtLinkHandle::close`].
+    pub async fn close(&self) -> CloseReason {
+        self.handle.close().await
+    }
+
+    /// See [`BtLinkHandle::closed`].
+    pub async fn closed(&self) -> CloseReason {
+        self.handle.closed().await
+    }
 }
 
 pub trait BtStream: Read + Write + Send + 'static {
     fn try_clone(&self) -> Result<Self>
     where
         Self: Sized;
-}
 
-impl BtStream for File {
-    fn try_clone(&self) -> Result<Self> {
-        Ok(self.try_clone()?)
-    }
+    /// Half- or fully closes the stream. `close()` relies on this to wake a
+    /// reader thread parked in `read`, so a stream that cannot be shut down
+    /// (a tty, a pipe) belongs on `spawn_async_fd` instead.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
 }
 
 impl BtStream for TcpStream {
     fn try_clone(&self) -> Result<Self> {
         Ok(self.try_clone()?)
     }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
 }
 
 pub fn unsupported<T>(msg: &str) -> Result<T> {
@@ -0,0 +1,196 @@
+use crate::link::{
+    stats_loop, Batcher, BtLink, BtLinkConfig, BtLinkHandle, CloseReason, LinkCounters, Worker,
+};
+use bytes::{Bytes, BytesMut};
+use common::error::Result;
+use std::io;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::sync::{atomic::Ordering, Arc};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Drives a connected stream fd (RFCOMM socket, socketpair, ...) from tokio
+/// tasks instead of blocking threads.
+///
+/// The fd is switched to non-blocking mode and registered with the reactor.
+/// Dropping both halves of the returned `BtLink` stops the tasks, shuts the
+/// socket down and closes the fd. `close()` works for fds that are not
+/// sockets too: both tasks stop on the closing signal, even mid-write, and
+/// the fd is closed once the last of them has exited. Must be called from
+/// within a tokio runtime.
+pub fn spawn_async_fd(fd: OwnedFd, cfg: BtLinkConfig) -> Result<BtLink> {
+    set_nonblocking(fd.as_raw_fd())?;
+    let io = Arc::new(AsyncFd::new(fd)?);
+    let (tx_outgoing, rx_outgoing) = mpsc::channel::<Bytes>(cfg.queue_bound);
+    let (tx_incoming, rx_incoming) = mpsc::channel::<Bytes>(cfg.queue_bound);
+    let counters = Arc::new(LinkCounters::default());
+
+    let weak_io = Arc::downgrade(&io);
+    let handle = BtLinkHandle::new(move || {
+        if let Some(io) = weak_io.upgrade() {
+            shutdown(io.as_raw_fd(), libc::SHUT_RDWR);
+        }
+    });
+
+    let reader = tokio::spawn(read_loop(
+        Arc::clone(&io),
+        tx_incoming,
+        cfg.max_chunk,
+        Arc::clone(&counters),
+        handle.clone(),
+    ));
+    handle.add_worker(Worker::Task(reader));
+    let writer = tokio::spawn(write_loop(
+        io,
+        Batcher::new(rx_outgoing, &cfg),
+        Arc::clone(&counters),
+        handle.clone(),
+    ));
+    handle.add_worker(Worker::Task(writer));
+
+    if let Some(period) = cfg.stats_interval {
+        let stats = tokio::spawn(stats_loop(handle.clone(), counters, period));
+        handle.add_worker(Worker::Task(stats));
+    }
+
+    Ok(BtLink {
+        tx: tx_outgoing,
+        rx: rx_incoming,
+        handle,
+    })
+}
+
+async fn read_loop(
+    io: Arc<AsyncFd<OwnedFd>>,
+    tx: mpsc::Sender<Bytes>,
+    max_chunk: usize,
+    counters: Arc<LinkCounters>,
+    handle: BtLinkHandle,
+) {
+    loop {
+        let mut guard = tokio::select! {
+            _ = tx.closed() => {
+                handle.set_reason(CloseReason::LocalShutdown);
+                break;
+            }
+            _ = handle.closing() => break,
+            guard = io.readable() => match guard {
+                Ok(guard) => guard,
+                Err(err) => {
+                    handle.fail(&err);
+                    break;
+                }
+            },
+        };
+        // See `BtLink::spawn` for why every read gets a fresh buffer.
+        let mut buf = BytesMut::with_capacity(max_chunk);
+        match guard.try_io(|inner| read_fd(inner.as_raw_fd(), &mut buf, max_chunk)) {
+            Ok(Ok(0)) => {
+                debug!("btlink reader eof");
+                handle.set_reason(CloseReason::PeerEof);
+                break;
+            }
+            Ok(Ok(n)) => {
+                counters.rx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                let sent = tokio::select! {
+                    res = tx.send(buf.freeze()) => res.is_ok(),
+                    _ = handle.closing() => false,
+                };
+                if !sent {
+                    handle.set_reason(CloseReason::LocalShutdown);
+                    break;
+                }
+            }
+            Ok(Err(err)) => {
+                debug!(?err, "btlink reader error");
+                handle.fail(&err);
+                break;
+            }
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+async fn write_loop(
+    io: Arc<AsyncFd<OwnedFd>>,
+    mut batcher: Batcher,
+    counters: Arc<LinkCounters>,
+    handle: BtLinkHandle,
+) {
+    loop {
+        let chunk = tokio::select! {
+            chunk = batcher.next() => chunk,
+            _ = handle.closing() => None,
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        counters.count_write(chunk.len());
+        // A peer that stopped reading (a tty nobody drains, a full socket)
+        // must not keep `close()` waiting, so the write yields to closing.
+        let written = tokio::select! {
+            res = write_all(&io, &chunk) => res,
+            _ = handle.closing() => return,
+        };
+        if let Err(err) = written {
+            debug!(?err, "btlink writer error");
+            handle.fail(&err);
+            return;
+        }
+    }
+    shutdown(io.as_raw_fd(), libc::SHUT_WR);
+}
+
+async fn write_all(io: &AsyncFd<OwnedFd>, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        let mut guard = io.writable().await?;
+        match guard.try_io(|inner| write_fd(inner.as_raw_fd(), data)) {
+            Ok(Ok(0)) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(Ok(n)) => data = &data[n..],
+            Ok(Err(err)) => return Err(err),
+            Err(_would_block) => continue,
+        }
+    }
+    Ok(())
+}
+
+fn read_fd(fd: RawFd, buf: &mut BytesMut, max: usize) -> io::Result<usize> {
+    let spare = buf.spare_capacity_mut();
+    let len = spare.len().min(max);
+    let ret = unsafe { libc::read(fd, spare.as_mut_ptr() as *mut libc::c_void, len) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let n = ret as usize;
+    unsafe { buf.set_len(buf.len() + n) };
+    Ok(n)
+}
+
+fn write_fd(fd: RawFd, data: &[u8]) -> io::Result<usize> {
+    let ret = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn shutdown(fd: RawFd, how: libc::c_int) {
+    // Not every fd is a socket. ENOTSOCK means there is nothing to shut
+    // down; `close()` and `fail()` raise the closing signal, which stops
+    // both tasks and drops their hold on the fd.
+    unsafe {
+        libc::shutdown(fd, how);
+    }
+}
@@ -1,3 +1,9 @@
+mod async_fd;
 mod rfcomm;
+mod tty;
+mod uds;
 
-pub use rfcomm::{accept_linux_rfcomm, connect_linux_rfcomm};
+pub use async_fd::spawn_async_fd;
+pub use rfcomm::{accept_linux_rfcomm, connect_linux_rfcomm, RfcommListener};
+pub use tty::{open_linux_tty, TtyConfig};
+pub use uds::{accept_linux_uds, connect_linux_uds, UdsListener};
@@ -1,8 +1,9 @@
+use super::async_fd::spawn_async_fd;
 use crate::link::{unsupported, BtLink, BtLinkConfig};
 use common::error::{BtProxyError, Result};
-use std::fs::File;
 use std::io;
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use tokio::io::unix::AsyncFd;
 use tracing::info;
 
 const AF_BLUETOOTH: i32 = 31;
@@ -30,16 +31,67 @@ fn parse_bdaddr(addr: &str) -> Result<[u8; 6]> {
     Ok(bytes)
 }
 
-fn socket_fd() -> Result<RawFd> {
-    let fd = unsafe { libc::socket(AF_BLUETOOTH, SOCK_STREAM, BTPROTO_RFCOMM) };
+fn socket_fd() -> Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            AF_BLUETOOTH,
+            SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            BTPROTO_RFCOMM,
+        )
+    };
     if fd < 0 {
         return Err(BtProxyError::Io(io::Error::last_os_error()));
     }
-    Ok(fd)
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
 }
 
-fn file_from_fd(fd: RawFd) -> File {
-    unsafe { File::from_raw_fd(fd) }
+fn socket_error(fd: RawFd) -> io::Result<()> {
+    let mut err: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            &mut err as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+    Ok(())
+}
+
+fn accept_fd(fd: RawFd) -> io::Result<(OwnedFd, SockAddrRc)> {
+    let mut peer = SockAddrRc {
+        rc_family: 0,
+        rc_bdaddr: [0; 6],
+        rc_channel: 0,
+    };
+    let mut len = std::mem::size_of::<SockAddrRc>() as libc::socklen_t;
+    let client_fd = unsafe {
+        libc::accept4(
+            fd,
+            &mut peer as *mut SockAddrRc as *mut libc::sockaddr,
+            &mut len,
+            libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        )
+    };
+    if client_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((unsafe { OwnedFd::from_raw_fd(client_fd) }, peer))
+}
+
+fn format_bdaddr(bytes: &[u8; 6]) -> String {
+    format!(
+        "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+        bytes[5], bytes[4], bytes[3], bytes[2], bytes[1], bytes[0]
+    )
 }
 
 pub async fn connect_linux_rfcomm(addr: &str, channel: u8, cfg: BtLinkConfig) -> Result<BtLink> {
@@ -53,45 +105,78 @@ pub async fn connect_linux_rfcomm(addr: &str, channel: u8, cfg: BtLinkConfig) ->
     info!(bt_addr = %addr, channel, "connecting rfcomm");
     let ret = unsafe {
         libc::connect(
-            fd,
+            fd.as_raw_fd(),
             &sockaddr as *const SockAddrRc as *const libc::sockaddr,
             std::mem::size_of::<SockAddrRc>() as u32,
         )
     };
-    if ret < 0 {
-        return Err(BtProxyError::Io(io::Error::last_os_error()));
-    }
+    let fd = if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(BtProxyError::Io(err));
+        }
+        let pending = AsyncFd::new(fd)?;
+        let _ = pending.writable().await?;
+        socket_error(pending.as_raw_fd())?;
+        pending.into_inner()
+    } else {
+        fd
+    };
     info!("rfcomm connected");
-    Ok(BtLink::spawn(file_from_fd(fd), cfg)?)
+    spawn_async_fd(fd, cfg)
 }
 
-pub async fn accept_linux_rfcomm(channel: u8, cfg: BtLinkConfig) -> Result<BtLink> {
-    let fd = socket_fd()?;
-    let sockaddr = SockAddrRc {
-        rc_family: AF_BLUETOOTH as libc::sa_family_t,
-        rc_bdaddr: [0; 6],
-        rc_channel: channel,
-    };
-    info!(channel, "rfcomm listening");
-    let ret = unsafe {
-        libc::bind(
-            fd,
-            &sockaddr as *const SockAddrRc as *const libc::sockaddr,
-            std::mem::size_of::<SockAddrRc>() as u32,
-        )
-    };
-    if ret < 0 {
-        return Err(BtProxyError::Io(io::Error::last_os_error()));
-    }
-    if unsafe { libc::listen(fd, 1) } < 0 {
-        return Err(BtProxyError::Io(io::Error::last_os_error()));
+/// A bound RFCOMM server socket that keeps accepting clients.
+pub struct RfcommListener {
+    io: AsyncFd<OwnedFd>,
+}
+
+impl RfcommListener {
+    pub fn bind(channel: u8, backlog: i32) -> Result<Self> {
+        let fd = socket_fd()?;
+        let sockaddr = SockAddrRc {
+            rc_family: AF_BLUETOOTH as libc::sa_family_t,
+            rc_bdaddr: [0; 6],
+            rc_channel: channel,
+        };
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &sockaddr as *const SockAddrRc as *const libc::sockaddr,
+                std::mem::size_of::<SockAddrRc>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(BtProxyError::Io(io::Error::last_os_error()));
+        }
+        if unsafe { libc::listen(fd.as_raw_fd(), backlog) } < 0 {
+            return Err(BtProxyError::Io(io::Error::last_os_error()));
+        }
+        info!(channel, "rfcomm listening");
+        Ok(Self {
+            io: AsyncFd::new(fd)?,
+        })
     }
-    let client_fd = unsafe { libc::accept(fd, std::ptr::null_mut(), std::ptr::null_mut()) };
-    if client_fd < 0 {
-        return Err(BtProxyError::Io(io::Error::last_os_error()));
+
+    /// Waits for the next client and returns its link and BD_ADDR.
+    pub async fn accept(&self, cfg: BtLinkConfig) -> Result<(BtLink, String)> {
+        let (client_fd, peer) = loop {
+            let mut guard = self.io.readable().await?;
+            match guard.try_io(|inner| accept_fd(inner.as_raw_fd())) {
+                Ok(result) => break result?,
+                Err(_would_block) => continue,
+            }
+        };
+        let peer = format_bdaddr(&peer.rc_bdaddr);
+        info!(%peer, "rfcomm accepted client");
+        Ok((spawn_async_fd(client_fd, cfg)?, peer))
     }
-    info!("rfcomm accepted client");
-    Ok(BtLink::spawn(file_from_fd(client_fd), cfg)?)
+}
+
+pub async fn accept_linux_rfcomm(channel: u8, cfg: BtLinkConfig) -> Result<BtLink> {
+    let listener = RfcommListener::bind(channel, 1)?;
+    let (link, _) = listener.accept(cfg).await?;
+    Ok(link)
 }
 
 #[allow(dead_code)]
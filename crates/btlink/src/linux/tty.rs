@@ -0,0 +1,94 @@
+use super::async_fd::spawn_async_fd;
+use crate::link::{BtLink, BtLinkConfig};
+use common::config::FlowControl;
+use common::error::{BtProxyError, Result};
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use tracing::info;
+
+#[derive(Debug, Clone)]
+pub struct TtyConfig {
+    pub baud: u32,
+    pub flow_control: FlowControl,
+}
+
+impl Default for TtyConfig {
+    fn default() -> Self {
+        Self {
+            baud: 115_200,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+/// Opens a serial device (`/dev/rfcommN` bound with `rfcomm bind`, a USB
+/// serial bridge, a pty slave) in raw mode and drives it like an RFCOMM
+/// socket.
+pub async fn open_linux_tty(path: &str, tty: &TtyConfig, cfg: BtLinkConfig) -> Result<BtLink> {
+    info!(%path, baud = tty.baud, flow_control = ?tty.flow_control, "opening tty");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK | libc::O_CLOEXEC)
+        .open(path)?;
+    let fd: OwnedFd = file.into();
+    configure_raw(fd.as_raw_fd(), tty)?;
+    info!("tty ready");
+    spawn_async_fd(fd, cfg)
+}
+
+fn configure_raw(fd: RawFd, tty: &TtyConfig) -> Result<()> {
+    let speed = baud_constant(tty.baud)?;
+    let mut attrs = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut attrs) } < 0 {
+        return Err(BtProxyError::Io(io::Error::last_os_error()));
+    }
+    unsafe { libc::cfmakeraw(&mut attrs) };
+    attrs.c_cflag |= libc::CLOCAL | libc::CREAD;
+    attrs.c_cflag &= !libc::CRTSCTS;
+    attrs.c_iflag &= !(libc::IXON | libc::IXOFF | libc::IXANY);
+    match tty.flow_control {
+        FlowControl::None => {}
+        FlowControl::RtsCts => attrs.c_cflag |= libc::CRTSCTS,
+        FlowControl::XonXoff => attrs.c_iflag |= libc::IXON | libc::IXOFF,
+    }
+    attrs.c_cc[libc::VMIN] = 1;
+    attrs.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::cfsetispeed(&mut attrs, speed) } < 0
+        || unsafe { libc::cfsetospeed(&mut attrs, speed) } < 0
+    {
+        return Err(BtProxyError::Io(io::Error::last_os_error()));
+    }
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &attrs) } < 0 {
+        return Err(BtProxyError::Io(io::Error::last_os_error()));
+    }
+    // Drop whatever the line buffered before we took it over.
+    unsafe { libc::tcflush(fd, libc::TCIOFLUSH) };
+    Ok(())
+}
+
+fn baud_constant(baud: u32) -> Result<libc::speed_t> {
+    let speed = match baud {
+        9_600 => libc::B9600,
+        19_200 => libc::B19200,
+        38_400 => libc::B38400,
+        57_600 => libc::B57600,
+        115_200 => libc::B115200,
+        230_400 => libc::B230400,
+        460_800 => libc::B460800,
+        921_600 => libc::B921600,
+        1_000_000 => libc::B1000000,
+        2_000_000 => libc::B2000000,
+        3_000_000 => libc::B3000000,
+        4_000_000 => libc::B4000000,
+        _ => {
+            return Err(BtProxyError::Config(format!(
+                "unsupported baud rate {}",
+                baud
+            )))
+        }
+    };
+    Ok(speed)
+}
@@ -0,0 +1,261 @@
+use super::async_fd::spawn_async_fd;
+use crate::link::{BtLink, BtLinkConfig};
+use common::error::{BtProxyError, Result};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use tokio::io::unix::AsyncFd;
+use tracing::{info, warn};
+
+/// Peer process on the other end of a Unix socket, from `SO_PEERCRED`.
+#[derive(Debug, Clone, Copy)]
+struct PeerCred {
+    pid: i32,
+    uid: u32,
+    gid: u32,
+}
+
+fn socket_fd() -> Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(BtProxyError::Io(io::Error::last_os_error()));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn sockaddr_un(path: &Path) -> Result<(libc::sockaddr_un, libc::socklen_t)> {
+    let bytes = path.as_os_str().as_bytes();
+    let mut addr = unsafe { std::mem::zeroed::<libc::sockaddr_un>() };
+    if bytes.is_empty() || bytes.len() >= addr.sun_path.len() {
+        return Err(BtProxyError::Config(format!(
+            "invalid unix socket path {}",
+            path.display()
+        )));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+    let len = std::mem::size_of::<libc::sa_family_t>() + bytes.len() + 1;
+    Ok((addr, len as libc::socklen_t))
+}
+
+fn connect_fd(fd: RawFd, path: &Path) -> Result<()> {
+    let (addr, len) = sockaddr_un(path)?;
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len,
+        )
+    };
+    if ret < 0 {
+        return Err(BtProxyError::Io(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn peer_cred(fd: RawFd) -> io::Result<PeerCred> {
+    let mut cred = unsafe { std::mem::zeroed::<libc::ucred>() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+/// Root and our own uid are always trusted, `extra` on top of them.
+fn trusted_uid(uid: u32, extra: &[u32]) -> bool {
+    uid == 0 || uid == unsafe { libc::geteuid() } || extra.contains(&uid)
+}
+
+/// Refuses sockets that another unprivileged user could have planted.
+fn check_server_socket(path: &Path, trusted_uids: &[u32]) -> Result<()> {
+    let meta = fs::metadata(path)?;
+    if !meta.file_type().is_socket() {
+        return Err(BtProxyError::Config(format!(
+            "{} is not a unix socket",
+            path.display()
+        )));
+    }
+    if !trusted_uid(meta.uid(), trusted_uids) {
+        return Err(BtProxyError::Config(format!(
+            "{} is owned by uid {}, refusing to connect",
+            path.display(),
+            meta.uid()
+        )));
+    }
+    if meta.mode() & 0o002 != 0 {
+        warn!(path = %path.display(), "unix socket is world-writable");
+    }
+    Ok(())
+}
+
+/// Connects to a server listening on `path`. The socket file and the
+/// process serving it must belong to root, to us, or to one of
+/// `trusted_uids`, e.g. a helper that runs as its own user.
+pub async fn connect_linux_uds(
+    path: &str,
+    trusted_uids: &[u32],
+    cfg: BtLinkConfig,
+) -> Result<BtLink> {
+    let path = Path::new(path);
+    info!(path = %path.display(), "connecting unix socket");
+    check_server_socket(path, trusted_uids)?;
+    let fd = socket_fd()?;
+    // Unix stream connects complete immediately or fail with EAGAIN when the
+    // backlog is full; there is no EINPROGRESS to wait for.
+    connect_fd(fd.as_raw_fd(), path)?;
+    let cred = peer_cred(fd.as_raw_fd())?;
+    if !trusted_uid(cred.uid, trusted_uids) {
+        return Err(BtProxyError::Config(format!(
+            "unix socket peer runs as uid {}, refusing",
+            cred.uid
+        )));
+    }
+    info!(pid = cred.pid, uid = cred.uid, "unix socket connected");
+    spawn_async_fd(fd, cfg)
+}
+
+/// Clears the way for `bind`: a stale socket left by a previous run is
+/// removed, a live one or anything that is not a socket is an error.
+fn prepare_path(path: &Path) -> Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(BtProxyError::Config(format!(
+            "{} exists and is not a unix socket",
+            path.display()
+        )));
+    }
+    let probe = socket_fd()?;
+    match connect_fd(probe.as_raw_fd(), path) {
+        Err(BtProxyError::Io(err)) if err.raw_os_error() == Some(libc::ECONNREFUSED) => {
+            info!(path = %path.display(), "removing stale unix socket");
+            fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(err) => Err(err),
+        Ok(()) => Err(BtProxyError::Io(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is already being served", path.display()),
+        ))),
+    }
+}
+
+/// A Unix socket server that keeps accepting clients. The socket file is
+/// removed again when the listener is dropped.
+pub struct UdsListener {
+    io: AsyncFd<OwnedFd>,
+    path: PathBuf,
+}
+
+impl UdsListener {
+    /// Binds `path` with permissions `mode` (e.g. `0o600`). The mode is
+    /// applied before `listen`, so nobody can connect while the socket still
+    /// carries umask permissions.
+    pub fn bind(path: &str, mode: u32, backlog: i32) -> Result<Self> {
+        let path = Path::new(path);
+        prepare_path(path)?;
+        let fd = socket_fd()?;
+        let (addr, len) = sockaddr_un(path)?;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                len,
+            )
+        };
+        if ret < 0 {
+            return Err(BtProxyError::Io(io::Error::last_os_error()));
+        }
+        let listener = Self {
+            io: AsyncFd::new(fd)?,
+            path: path.to_path_buf(),
+        };
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| BtProxyError::Config("unix socket path contains nul".to_string()))?;
+        if unsafe { libc::chmod(c_path.as_ptr(), mode as libc::mode_t) } < 0 {
+            return Err(BtProxyError::Io(io::Error::last_os_error()));
+        }
+        if unsafe { libc::listen(listener.io.as_raw_fd(), backlog) } < 0 {
+            return Err(BtProxyError::Io(io::Error::last_os_error()));
+        }
+        info!(path = %path.display(), mode = format!("{:o}", mode), "unix socket listening");
+        Ok(listener)
+    }
+
+    /// Waits for the next client and returns its link and a description of
+    /// the peer process.
+    pub async fn accept(&self, cfg: BtLinkConfig) -> Result<(BtLink, String)> {
+        let client_fd = loop {
+            let mut guard = self.io.readable().await?;
+            match guard.try_io(|inner| accept_fd(inner.as_raw_fd())) {
+                Ok(result) => break result?,
+                Err(_would_block) => continue,
+            }
+        };
+        let peer = match peer_cred(client_fd.as_raw_fd()) {
+            Ok(cred) => format!("pid={} uid={} gid={}", cred.pid, cred.uid, cred.gid),
+            Err(err) => {
+                warn!(?err, "unix socket peer has no credentials");
+                "unknown".to_string()
+            }
+        };
+        info!(%peer, "unix socket accepted client");
+        Ok((spawn_async_fd(client_fd, cfg)?, peer))
+    }
+}
+
+impl Drop for UdsListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub async fn accept_linux_uds(path: &str, mode: u32, cfg: BtLinkConfig) -> Result<BtLink> {
+    let listener = UdsListener::bind(path, mode, 1)?;
+    let (link, _) = listener.accept(cfg).await?;
+    Ok(link)
+}
+
+fn accept_fd(fd: RawFd) -> io::Result<OwnedFd> {
+    let client_fd = unsafe {
+        libc::accept4(
+            fd,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        )
+    };
+    if client_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(client_fd) })
+}
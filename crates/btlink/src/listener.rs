@@ -0,0 +1,32 @@
+use crate::link::{BtLink, BtLinkConfig};
+use crate::tcp::TcpLinkListener;
+use common::error::Result;
+
+#[cfg(target_os = "linux")]
+use crate::linux::{RfcommListener, UdsListener};
+#[cfg(target_os = "windows")]
+use crate::windows::RfcommListener;
+
+/// Any transport that can keep accepting links, so a server can run one
+/// accept loop regardless of what it listens on.
+pub enum LinkListener {
+    Tcp(TcpLinkListener),
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    Rfcomm(RfcommListener),
+    #[cfg(target_os = "linux")]
+    Uds(UdsListener),
+}
+
+impl LinkListener {
+    /// Waits for the next client. The string identifies the peer for logs:
+    /// a BD_ADDR, a socket address or unix peer credentials.
+    pub async fn accept(&self, cfg: BtLinkConfig) -> Result<(BtLink, String)> {
+        match self {
+            Self::Tcp(listener) => listener.accept(cfg).await,
+            #[cfg(any(target_os = "linux", target_os = "windows"))]
+            Self::Rfcomm(listener) => listener.accept(cfg).await,
+            #[cfg(target_os = "linux")]
+            Self::Uds(listener) => listener.accept(cfg).await,
+        }
+    }
+}
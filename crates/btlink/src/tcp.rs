@@ -0,0 +1,50 @@
+use crate::link::{BtLink, BtLinkConfig};
+use common::error::Result;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::info;
+
+pub async fn connect_tcp(addr: &str, cfg: BtLinkConfig) -> Result<BtLink> {
+    info!(%addr, "connecting tcp");
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    info!("tcp connected");
+    spawn_std(stream, cfg)
+}
+
+/// A TCP server that keeps accepting clients.
+pub struct TcpLinkListener {
+    inner: TcpListener,
+}
+
+impl TcpLinkListener {
+    pub async fn bind(addr: &str) -> Result<Self> {
+        let inner = TcpListener::bind(addr).await?;
+        info!(%addr, "tcp listening");
+        Ok(Self { inner })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.local_addr()?)
+    }
+
+    /// Waits for the next client and returns its link and socket address.
+    pub async fn accept(&self, cfg: BtLinkConfig) -> Result<(BtLink, String)> {
+        let (stream, peer) = self.inner.accept().await?;
+        stream.set_nodelay(true)?;
+        info!(%peer, "tcp accepted client");
+        Ok((spawn_std(stream, cfg)?, peer.to_string()))
+    }
+}
+
+pub async fn accept_tcp(addr: &str, cfg: BtLinkConfig) -> Result<BtLink> {
+    let listener = TcpLinkListener::bind(addr).await?;
+    let (link, _) = listener.accept(cfg).await?;
+    Ok(link)
+}
+
+fn spawn_std(stream: TcpStream, cfg: BtLinkConfig) -> Result<BtLink> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    BtLink::spawn(stream, cfg)
+}
@@ -1,3 +1,3 @@
 mod rfcomm;
 
-pub use rfcomm::{accept_windows_rfcomm, connect_windows_rfcomm};
+pub use rfcomm::{accept_windows_rfcomm, connect_windows_rfcomm, RfcommListener};
@@ -9,7 +9,7 @@ use windows_sys::core::GUID;
 use windows_sys::Win32::Devices::Bluetooth::{AF_BTH, BTHPROTO_RFCOMM, SOCKADDR_BTH};
 use windows_sys::Win32::Networking::WinSock::{
     accept, bind, closesocket, connect, listen, socket, WSAGetLastError, WSAStartup,
-    INVALID_SOCKET, SOCKADDR, SOCK_STREAM, SOCKET, SOCKET_ERROR, WSADATA,
+    INVALID_SOCKET, SOCKADDR, SOCKET, SOCKET_ERROR, SOCK_STREAM, WSADATA,
 };
 
 static WSA_STARTUP_RESULT: OnceLock<io::Result<()>> = OnceLock::new();
@@ -178,40 +178,86 @@ pub async fn connect_windows_rfcomm(
     Ok(BtLink::spawn(stream, cfg)?)
 }
 
-pub async fn accept_windows_rfcomm(channel: u8, cfg: BtLinkConfig) -> Result<BtLink> {
-    ensure_wsa()?;
-    let socket = unsafe { socket(AF_BTH as i32, SOCK_STREAM as i32, BTHPROTO_RFCOMM as i32) };
-    if socket == INVALID_SOCKET {
-        return Err(last_socket_error());
-    }
-    info!(channel, "rfcomm listening");
-    let sockaddr = SOCKADDR_BTH {
-        addressFamily: AF_BTH as u16,
-        btAddr: 0,
-        serviceClassId: null_guid(),
-        port: u32::from(channel),
-    };
-    let ret = unsafe {
-        bind(
-            socket,
-            &sockaddr as *const SOCKADDR_BTH as *const SOCKADDR,
-            std::mem::size_of::<SOCKADDR_BTH>() as i32,
-        )
-    };
-    if ret == SOCKET_ERROR {
-        close_socket(socket);
-        return Err(last_socket_error());
+fn format_bt_addr(addr: u64) -> String {
+    let bytes = addr.to_be_bytes();
+    format!(
+        "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+        bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]
+    )
+}
+
+/// A bound RFCOMM server socket that keeps accepting clients.
+pub struct RfcommListener {
+    socket: SOCKET,
+}
+
+impl RfcommListener {
+    pub fn bind(channel: u8, backlog: i32) -> Result<Self> {
+        ensure_wsa()?;
+        let socket = unsafe { socket(AF_BTH as i32, SOCK_STREAM as i32, BTHPROTO_RFCOMM as i32) };
+        if socket == INVALID_SOCKET {
+            return Err(last_socket_error());
+        }
+        let listener = Self { socket };
+        let sockaddr = SOCKADDR_BTH {
+            addressFamily: AF_BTH as u16,
+            btAddr: 0,
+            serviceClassId: null_guid(),
+            port: u32::from(channel),
+        };
+        let ret = unsafe {
+            bind(
+                socket,
+                &sockaddr as *const SOCKADDR_BTH as *const SOCKADDR,
+                std::mem::size_of::<SOCKADDR_BTH>() as i32,
+            )
+        };
+        if ret == SOCKET_ERROR {
+            return Err(last_socket_error());
+        }
+        if unsafe { listen(socket, backlog) } == SOCKET_ERROR {
+            return Err(last_socket_error());
+        }
+        info!(channel, "rfcomm listening");
+        Ok(listener)
     }
-    if unsafe { listen(socket, 1) } == SOCKET_ERROR {
-        close_socket(socket);
-        return Err(last_socket_error());
+
+    /// Waits for the next client and returns its link and BD_ADDR. Winsock
+    /// accept blocks, so it runs on the blocking pool.
+    pub async fn accept(&self, cfg: BtLinkConfig) -> Result<(BtLink, String)> {
+        let socket = self.socket;
+        let (client_socket, bt_addr) = tokio::task::spawn_blocking(move || {
+            let mut peer = unsafe { std::mem::zeroed::<SOCKADDR_BTH>() };
+            let mut len = std::mem::size_of::<SOCKADDR_BTH>() as i32;
+            let client_socket = unsafe {
+                accept(
+                    socket,
+                    &mut peer as *mut SOCKADDR_BTH as *mut SOCKADDR,
+                    &mut len,
+                )
+            };
+            if client_socket == INVALID_SOCKET {
+                return Err(last_socket_error());
+            }
+            Ok((client_socket, peer.btAddr))
+        })
+        .await
+        .map_err(io::Error::from)??;
+        let stream = socket_stream(client_socket)?;
+        let peer = format_bt_addr(bt_addr);
+        info!(%peer, "rfcomm accepted client");
+        Ok((BtLink::spawn(stream, cfg)?, peer))
     }
-    let client_socket = unsafe { accept(socket, std::ptr::null_mut(), std::ptr::null_mut()) };
-    close_socket(socket);
-    if client_socket == INVALID_SOCKET {
-        return Err(last_socket_error());
+}
+
+impl Drop for RfcommListener {
+    fn drop(&mut self) {
+        close_socket(self.socket);
     }
-    let stream = socket_stream(client_socket)?;
-    info!("rfcomm accepted client");
-    Ok(BtLink::spawn(stream, cfg)?)
+}
+
+pub async fn accept_windows_rfcomm(channel: u8, cfg: BtLinkConfig) -> Result<BtLink> {
+    let listener = RfcommListener::bind(channel, 1)?;
+    let (link, _) = listener.accept(cfg).await?;
+    Ok(link)
 }
@@ -0,0 +1,81 @@
+#![cfg(target_os = "linux")]
+
+use btlink::{spawn_async_fd, BtLink, BtLinkConfig, CloseReason};
+use bytes::Bytes;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::timeout;
+
+fn socketpair() -> (BtLink, BtLink) {
+    let (a, b) = UnixStream::pair().unwrap();
+    (
+        spawn_async_fd(a.into(), BtLinkConfig::default()).unwrap(),
+        spawn_async_fd(b.into(), BtLinkConfig::default()).unwrap(),
+    )
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// Writes `data` in uneven chunks to `tx` and reads as many bytes back
+/// from `rx`.
+async fn pump(tx: &Sender<Bytes>, rx: &mut Receiver<Bytes>, data: &[u8]) -> Vec<u8> {
+    let send = async {
+        for chunk in data.chunks(3001) {
+            tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+    };
+    let recv = async {
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            let chunk = rx.recv().await.expect("link closed early");
+            received.extend_from_slice(&chunk);
+        }
+        received
+    };
+    tokio::join!(send, recv).1
+}
+
+#[tokio::test]
+async fn socketpair_carries_data_both_ways() {
+    let (mut a, mut b) = socketpair();
+    let there = pattern(256 * 1024, 0);
+    let back = pattern(128 * 1024, 0x5a);
+    let (a_to_b, b_to_a) = timeout(Duration::from_secs(10), async {
+        tokio::join!(
+            pump(&a.tx, &mut b.rx, &there),
+            pump(&b.tx, &mut a.rx, &back)
+        )
+    })
+    .await
+    .expect("transfer stalled");
+    assert!(a_to_b == there);
+    assert!(b_to_a == back);
+}
+
+#[tokio::test]
+async fn dropping_a_link_is_a_local_shutdown_and_peer_eof() {
+    let (a, mut b) = socketpair();
+    let a_handle = a.handle.clone();
+    a.tx.send(Bytes::from_static(b"last words")).await.unwrap();
+    drop(a);
+    let reason = timeout(Duration::from_secs(5), a_handle.closed()).await;
+    assert_eq!(reason.unwrap(), CloseReason::LocalShutdown);
+    // What was queued before the drop still arrives, then the stream ends.
+    assert_eq!(&b.rx.recv().await.unwrap()[..], b"last words");
+    assert!(b.rx.recv().await.is_none());
+    let reason = timeout(Duration::from_secs(5), b.handle.closed()).await;
+    assert_eq!(reason.unwrap(), CloseReason::PeerEof);
+}
+
+#[tokio::test]
+async fn close_stops_both_ends() {
+    let (a, mut b) = socketpair();
+    let reason = timeout(Duration::from_secs(5), a.handle.close()).await;
+    assert_eq!(reason.unwrap(), CloseReason::LocalShutdown);
+    assert!(a.tx.send(Bytes::from_static(b"late")).await.is_err());
+    assert!(b.rx.recv().await.is_none());
+    assert_eq!(b.handle.closed().await, CloseReason::PeerEof);
+}
@@ -0,0 +1,38 @@
+use btlink::{spawn_async_io, BtLinkConfig, CloseReason};
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn close_interrupts_a_write_nobody_reads() {
+    let (ours, _theirs) = tokio::io::duplex(1024);
+    let (read, write) = tokio::io::split(ours);
+    let link = spawn_async_io(read, write, BtLinkConfig::default());
+    link.tx.send(Bytes::from(vec![0; 64 * 1024])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let reason = timeout(Duration::from_secs(5), link.close())
+        .await
+        .expect("close waited for the write");
+    assert_eq!(reason, CloseReason::LocalShutdown);
+}
+
+#[tokio::test]
+async fn close_interrupts_a_delivery_nobody_takes() {
+    let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+    let (read, write) = tokio::io::split(ours);
+    let cfg = BtLinkConfig {
+        queue_bound: 1,
+        ..BtLinkConfig::default()
+    };
+    // `link.rx` is never read, so the reader ends up waiting to hand over.
+    let link = spawn_async_io(read, write, cfg);
+    for _ in 0..8 {
+        theirs.write_all(b"unread").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let reason = timeout(Duration::from_secs(5), link.close())
+        .await
+        .expect("close waited for the consumer");
+    assert_eq!(reason, CloseReason::LocalShutdown);
+}
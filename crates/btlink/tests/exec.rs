@@ -0,0 +1,94 @@
+#![cfg(unix)]
+
+use btlink::{spawn_command, BtLinkConfig, CloseReason};
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn cat_echoes_what_the_link_sends() {
+    let mut link = spawn_command("cat", BtLinkConfig::default()).await.unwrap();
+    let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let send = async {
+        for chunk in data.chunks(3001) {
+            link.tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+    };
+    let recv = async {
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            let chunk = link.rx.recv().await.expect("link closed early");
+            received.extend_from_slice(&chunk);
+        }
+        received
+    };
+    let (_, received) = timeout(Duration::from_secs(10), async { tokio::join!(send, recv) })
+        .await
+        .expect("echo stalled");
+    assert!(received == data);
+    assert_eq!(link.close().await, CloseReason::LocalShutdown);
+}
+
+#[tokio::test]
+async fn command_exiting_ends_the_link() {
+    let mut link = spawn_command("echo hello", BtLinkConfig::default())
+        .await
+        .unwrap();
+    let chunk = timeout(Duration::from_secs(5), link.rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&chunk[..], b"hello\n");
+    let reason = timeout(Duration::from_secs(5), link.closed()).await;
+    assert_eq!(reason.unwrap(), CloseReason::PeerEof);
+}
+
+#[tokio::test]
+async fn close_kills_a_command_that_stopped_reading() {
+    let link = spawn_command("exec sleep 60", BtLinkConfig::default())
+        .await
+        .unwrap();
+    // More than the pipe holds, so the writer is stuck mid-write.
+    for _ in 0..64 {
+        if link.tx.try_send(Bytes::from(vec![0; 16 * 1024])).is_err() {
+            break;
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let reason = timeout(Duration::from_secs(5), link.close())
+        .await
+        .expect("close waited for the command");
+    assert_eq!(reason, CloseReason::LocalShutdown);
+}
+
+#[tokio::test]
+async fn write_error_stops_the_reader() {
+    // Closes its stdin but keeps stdout open, so only a write can fail.
+    let link = spawn_command("exec 0<&-; exec sleep 60", BtLinkConfig::default())
+        .await
+        .unwrap();
+    let reason = timeout(Duration::from_secs(5), async {
+        loop {
+            if link
+                .tx
+                .send(Bytes::from_static(b"anyone there?"))
+                .await
+                .is_err()
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        link.closed().await
+    })
+    .await
+    .expect("a failed write left the link open");
+    assert!(
+        !matches!(reason, CloseReason::PeerEof | CloseReason::LocalShutdown),
+        "{:?}",
+        reason
+    );
+    timeout(Duration::from_secs(5), link.close())
+        .await
+        .expect("reader still parked after the write failed");
+}
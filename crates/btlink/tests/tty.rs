@@ -0,0 +1,127 @@
+#![cfg(target_os = "linux")]
+
+use btlink::{open_linux_tty, spawn_async_fd, BtLink, BtLinkConfig, CloseReason, TtyConfig};
+use bytes::Bytes;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// A pty pair: the master and the slave's open fd and path.
+fn openpty() -> (OwnedFd, OwnedFd, String) {
+    let (mut master, mut slave) = (0, 0);
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(ret, 0, "openpty: {}", std::io::Error::last_os_error());
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd())).unwrap();
+    (master, slave, path.to_string_lossy().into_owned())
+}
+
+fn termios(fd: &OwnedFd) -> libc::termios {
+    let mut attrs = unsafe { std::mem::zeroed::<libc::termios>() };
+    assert_eq!(unsafe { libc::tcgetattr(fd.as_raw_fd(), &mut attrs) }, 0);
+    attrs
+}
+
+/// Every byte value, including CR, LF and the control characters a cooked
+/// line would act on.
+fn all_bytes(repeat: usize) -> Vec<u8> {
+    (0..repeat).flat_map(|_| 0..=255u8).collect()
+}
+
+async fn send_and_expect(from: &BtLink, to: &mut BtLink, data: &[u8]) {
+    let send = async {
+        for chunk in data.chunks(500) {
+            from.tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+    };
+    let recv = async {
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            received.extend_from_slice(&to.rx.recv().await.expect("link closed early"));
+        }
+        received
+    };
+    let received = timeout(Duration::from_secs(10), async {
+        tokio::join!(send, recv).1
+    })
+    .await
+    .expect("tty transfer stalled");
+    assert!(received == data, "bytes altered on the way");
+}
+
+#[tokio::test]
+async fn tty_is_switched_to_raw_mode() {
+    let (_master, slave, path) = openpty();
+    let _tty = open_linux_tty(&path, &TtyConfig::default(), BtLinkConfig::default())
+        .await
+        .unwrap();
+    let attrs = termios(&slave);
+    assert_eq!(attrs.c_lflag & (libc::ECHO | libc::ICANON | libc::ISIG), 0);
+    assert_eq!(
+        attrs.c_iflag & (libc::ICRNL | libc::INLCR | libc::IGNCR | libc::IXON),
+        0
+    );
+    assert_eq!(attrs.c_oflag & libc::OPOST, 0);
+    assert_eq!(attrs.c_cflag & libc::CSIZE, libc::CS8);
+    assert_eq!(unsafe { libc::cfgetospeed(&attrs) }, libc::B115200);
+}
+
+#[tokio::test]
+async fn tty_round_trip_is_byte_exact_without_echo() {
+    let (master, _slave, path) = openpty();
+    let mut master = spawn_async_fd(master, BtLinkConfig::default()).unwrap();
+    let mut tty = open_linux_tty(&path, &TtyConfig::default(), BtLinkConfig::default())
+        .await
+        .unwrap();
+    send_and_expect(&master, &mut tty, &all_bytes(16)).await;
+    // With echo on, the master would read its own bytes back before these.
+    let reply: Vec<u8> = all_bytes(8).into_iter().rev().collect();
+    send_and_expect(&tty, &mut master, &reply).await;
+    assert!(master.rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn unsupported_baud_rate_is_rejected() {
+    let (_master, _slave, path) = openpty();
+    let tty = TtyConfig {
+        baud: 12_345,
+        ..TtyConfig::default()
+    };
+    assert!(open_linux_tty(&path, &tty, BtLinkConfig::default())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn close_interrupts_a_write_nobody_reads() {
+    let (_master, slave, path) = openpty();
+    drop(slave);
+    let tty = open_linux_tty(&path, &TtyConfig::default(), BtLinkConfig::default())
+        .await
+        .unwrap();
+    // Nobody reads the master, so the pty buffer fills, the writer parks in
+    // its write and the queue in front of it backs up.
+    let chunk = Bytes::from(vec![0x55; 4096]);
+    let blocked = timeout(Duration::from_secs(10), async {
+        while timeout(Duration::from_millis(200), tty.tx.send(chunk.clone()))
+            .await
+            .is_ok()
+        {}
+    })
+    .await;
+    assert!(blocked.is_ok(), "tty never pushed back");
+
+    let reason = timeout(Duration::from_secs(5), tty.handle.close())
+        .await
+        .expect("close hung on the blocked writer");
+    assert_eq!(reason, CloseReason::LocalShutdown);
+    assert!(tty.tx.send(chunk).await.is_err());
+}
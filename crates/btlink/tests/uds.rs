@@ -0,0 +1,107 @@
+#![cfg(target_os = "linux")]
+
+use btlink::{connect_linux_uds, BtLinkConfig, UdsListener};
+use bytes::Bytes;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// A fresh directory for one test's socket.
+fn socket_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("btproxy-uds-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn stale_socket_is_replaced_and_served() {
+    let dir = socket_dir("stale");
+    let path = dir.join("link.sock");
+    // A listener that went away without cleaning up.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let listener = UdsListener::bind(path.to_str().unwrap(), 0o600, 1).unwrap();
+    let (client, accepted) = tokio::join!(
+        connect_linux_uds(path.to_str().unwrap(), &[], BtLinkConfig::default()),
+        listener.accept(BtLinkConfig::default())
+    );
+    let client = client.unwrap();
+    let (mut server, peer) = accepted.unwrap();
+    // The peer is this very process.
+    let uid = std::fs::metadata("/proc/self").unwrap().uid();
+    assert!(
+        peer.starts_with(&format!("pid={} uid={} ", std::process::id(), uid)),
+        "{}",
+        peer
+    );
+    client.tx.send(Bytes::from_static(b"hello")).await.unwrap();
+    let chunk = timeout(Duration::from_secs(5), server.rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&chunk[..], b"hello");
+
+    drop(listener);
+    assert!(!path.exists(), "socket left behind");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn live_socket_and_other_files_are_left_alone() {
+    let dir = socket_dir("busy");
+    let path = dir.join("link.sock");
+    let _live = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    let err = UdsListener::bind(path.to_str().unwrap(), 0o600, 1)
+        .err()
+        .expect("bound over a live socket");
+    assert!(err.to_string().contains("already being served"), "{}", err);
+    assert!(path.exists());
+
+    let file = dir.join("notes.txt");
+    std::fs::write(&file, b"keep me").unwrap();
+    assert!(UdsListener::bind(file.to_str().unwrap(), 0o600, 1).is_err());
+    assert_eq!(std::fs::read(&file).unwrap(), b"keep me");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn socket_gets_its_mode_regardless_of_umask() {
+    let dir = socket_dir("mode");
+    for mode in [0o600, 0o660] {
+        let path = dir.join(format!("link-{:o}.sock", mode));
+        let _listener = UdsListener::bind(path.to_str().unwrap(), mode, 1).unwrap();
+        let actual = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(actual & 0o777, mode);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn socket_owned_by_a_stranger_needs_their_uid_trusted() {
+    const STRANGER: u32 = 4242;
+    if unsafe { libc::geteuid() } != 0 {
+        // Only root can hand the socket to someone else.
+        return;
+    }
+    let dir = socket_dir("owner");
+    let path = dir.join("link.sock");
+    let listener = UdsListener::bind(path.to_str().unwrap(), 0o666, 1).unwrap();
+    std::os::unix::fs::chown(&path, Some(STRANGER), None).unwrap();
+
+    let err = connect_linux_uds(path.to_str().unwrap(), &[], BtLinkConfig::default())
+        .await
+        .err()
+        .expect("connected to a socket a stranger owns");
+    assert!(err.to_string().contains("owned by uid 4242"), "{}", err);
+
+    let (client, accepted) = tokio::join!(
+        connect_linux_uds(path.to_str().unwrap(), &[STRANGER], BtLinkConfig::default()),
+        listener.accept(BtLinkConfig::default())
+    );
+    client.unwrap();
+    accepted.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
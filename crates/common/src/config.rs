@@ -1,18 +1,130 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Transport {
+    Rfcomm,
+    Tcp,
+    Tty,
+    Exec,
+    Stdio,
+    Uds,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FlowControl {
+    None,
+    RtsCts,
+    XonXoff,
+}
+
+/// What the server does when a client connects while another is active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ClientPolicy {
+    /// Serve both, each with its own session.
+    Allow,
+    /// Turn the new client away.
+    Reject,
+    /// Close the existing sessions and serve the new client.
+    KickOld,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ClientCommand {
+    /// Generate a client key: FILE gets the private key, FILE.pub the line
+    /// to add to the server's authorized_keys.
+    Keygen {
+        #[arg(
+            long,
+            short = 'f',
+            value_name = "FILE",
+            default_value = "btproxy_ed25519"
+        )]
+        file: PathBuf,
+        /// Stored with the key and shown in the server's logs.
+        #[arg(long, short = 'C', default_value = "")]
+        comment: String,
+    },
+}
 
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about)]
 pub struct ClientConfig {
+    #[command(subcommand)]
+    pub command: Option<ClientCommand>,
     #[arg(long, default_value = "127.0.0.1:18080")]
     pub listen: String,
+    #[arg(long, value_enum, default_value = "rfcomm")]
+    pub transport: Transport,
     #[arg(long)]
-    pub bt_addr: String,
+    pub bt_addr: Option<String>,
     #[arg(long)]
     pub uuid: Option<String>,
     #[arg(long)]
     pub channel: Option<u8>,
     #[arg(long)]
+    pub server_addr: Option<String>,
+    #[arg(long)]
+    pub tty: Option<String>,
+    #[arg(long, default_value = "115200")]
+    pub baud: u32,
+    #[arg(long, value_enum, default_value = "none")]
+    pub flow_control: FlowControl,
+    #[arg(long)]
+    pub exec: Option<String>,
+    #[arg(long)]
+    pub socket_path: Option<String>,
+    /// Uid besides root and our own that may own the unix socket and the
+    /// server behind it. Repeatable.
+    #[arg(long = "socket-owner", value_name = "UID")]
+    pub socket_owners: Vec<u32>,
+    /// Extra link to bond into the session, as `TRANSPORT:TARGET`, e.g.
+    /// `tcp:10.0.0.2:18888`, `rfcomm:23`, `uds:/run/btproxy.sock`. Repeatable.
+    #[arg(long = "bond", value_name = "SPEC")]
+    pub bond: Vec<String>,
+    /// Stream priority by destination, as `PATTERN=LEVEL` with LEVEL one of
+    /// `bulk`, `normal`, `interactive`, e.g. `*.example.com=interactive`.
+    /// The first matching rule wins. Repeatable.
+    #[arg(long = "priority", value_name = "RULE")]
+    pub priority: Vec<String>,
+    #[arg(long)]
     pub psk: Option<String>,
+    /// Private key file to sign the handshake with, from `keygen`.
+    #[arg(long)]
+    pub key: Option<String>,
+    #[arg(long, default_value = "false")]
+    pub checksum: bool,
+    /// Compress DATA with `zstd` or `deflate` when the peer supports it;
+    /// comma-separated, most preferred first.
+    #[arg(long = "compress", value_name = "ALG", value_delimiter = ',')]
+    pub compress: Vec<String>,
+    /// Receive window per stream in bytes, advertised to the peer.
+    #[arg(long, default_value = "262144")]
+    pub stream_window: u32,
+    /// Bytes of queued frames gathered into one link write; 0 writes each
+    /// frame on its own.
+    #[arg(long, default_value = "4096")]
+    pub write_batch: usize,
+    /// How long a link write that is not full waits for more frames; 0
+    /// sends at once.
+    #[arg(long, default_value = "0")]
+    pub write_delay_ms: u64,
+    #[arg(long, default_value = "10000")]
+    pub keepalive_ms: u32,
+    /// Unanswered keepalives after which the session is dropped; 0 never
+    /// gives up.
+    #[arg(long, default_value = "3")]
+    pub keepalive_misses: u32,
+    /// Drop the session when nothing arrives for this long; 0 disables.
+    #[arg(long, default_value = "0")]
+    pub idle_timeout_ms: u32,
+    /// How long open streams may keep running after a shutdown request.
+    #[arg(long, default_value = "5000")]
+    pub drain_timeout_ms: u64,
+    /// How long to wait for the server to accept a new stream; 0 waits
+    /// forever.
+    #[arg(long, default_value = "15000")]
+    pub open_timeout_ms: u32,
     #[arg(long, default_value = "info")]
     pub log: String,
 }
@@ -20,8 +132,30 @@ pub struct ClientConfig {
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about)]
 pub struct ServerConfig {
+    #[arg(long, value_enum, default_value = "rfcomm")]
+    pub transport: Transport,
     #[arg(long, default_value = "22")]
     pub channel: u8,
+    #[arg(long, default_value = "127.0.0.1:18888")]
+    pub listen: String,
+    #[arg(long)]
+    pub tty: Option<String>,
+    #[arg(long, default_value = "115200")]
+    pub baud: u32,
+    #[arg(long, value_enum, default_value = "none")]
+    pub flow_control: FlowControl,
+    #[arg(long)]
+    pub exec: Option<String>,
+    #[arg(long)]
+    pub socket_path: Option<String>,
+    /// Octal permissions for the unix socket created by `--transport uds`.
+    #[arg(long, default_value = "600", value_parser = parse_mode)]
+    pub socket_mode: u32,
+    #[arg(long, value_enum, default_value = "kick-old")]
+    pub client_policy: ClientPolicy,
+    /// Accept bonded sessions whose extra links join an existing session.
+    #[arg(long, default_value = "false")]
+    pub bond: bool,
     #[arg(long, default_value = "127.0.0.1:7891")]
     pub clash_socks: String,
     #[arg(long)]
@@ -32,6 +166,54 @@ pub struct ServerConfig {
     pub direct: bool,
     #[arg(long)]
     pub psk: Option<String>,
+    /// Only accept clients whose key is listed in this file. It is read
+    /// again for every connection, so removing a line locks that key out.
+    #[arg(long)]
+    pub authorized_keys: Option<String>,
+    #[arg(long, default_value = "false")]
+    pub checksum: bool,
+    /// Compress DATA with `zstd` or `deflate` when the peer supports it;
+    /// comma-separated, most preferred first.
+    #[arg(long = "compress", value_name = "ALG", value_delimiter = ',')]
+    pub compress: Vec<String>,
+    /// Receive window per stream in bytes, advertised to the peer.
+    #[arg(long, default_value = "262144")]
+    pub stream_window: u32,
+    /// Bytes of queued frames gathered into one link write; 0 writes each
+    /// frame on its own.
+    #[arg(long, default_value = "4096")]
+    pub write_batch: usize,
+    /// How long a link write that is not full waits for more frames; 0
+    /// sends at once.
+    #[arg(long, default_value = "0")]
+    pub write_delay_ms: u64,
+    #[arg(long, default_value = "10000")]
+    pub keepalive_ms: u32,
+    /// Unanswered keepalives after which the session is dropped; 0 never
+    /// gives up.
+    #[arg(long, default_value = "3")]
+    pub keepalive_misses: u32,
+    /// Drop the session when nothing arrives for this long; 0 disables.
+    #[arg(long, default_value = "0")]
+    pub idle_timeout_ms: u32,
+    /// How long open streams may keep running after a shutdown request.
+    #[arg(long, default_value = "5000")]
+    pub drain_timeout_ms: u64,
+    /// How long a stream the server opens waits for the client to accept
+    /// it; 0 waits forever.
+    #[arg(long, default_value = "15000")]
+    pub open_timeout_ms: u32,
+    /// How long a new connection may take to finish the handshake; 0 waits
+    /// forever.
+    #[arg(long, default_value = "10000")]
+    pub handshake_timeout_ms: u64,
     #[arg(long, default_value = "info")]
     pub log: String,
 }
+
+fn parse_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("invalid octal mode {}", value))
+}
@@ -2,8 +2,10 @@ pub mod config;
 pub mod error;
 pub mod logging;
 pub mod net;
+pub mod signal;
 
 pub use config::*;
 pub use error::*;
 pub use logging::*;
 pub use net::*;
+pub use signal::*;
@@ -1,6 +1,10 @@
 use tracing_subscriber::{fmt, EnvFilter};
 
+/// Logs go to stderr so stdout stays free for the stdio transport.
 pub fn init_tracing(level: &str) {
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
-    let _ = fmt().with_env_filter(filter).try_init();
+    let _ = fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .try_init();
 }
@@ -0,0 +1,183 @@
+//! Link writes needed for chatty traffic with and without write batching.
+//!
+//! Each session runs over loopback TCP; every `write()` on the socket is
+//! counted, since on RFCOMM each one costs at least one packet. Many streams
+//! trickle small writes at once, the pattern of interactive and
+//! request/response traffic.
+//!
+//! Run with `cargo bench -p mux --bench batching`.
+
+use btlink::link::BtStream;
+use btlink::{BtLink, BtLinkConfig};
+use mux::{MuxConfig, MuxSession, Role, TargetAddr};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const STREAMS: usize = 32;
+const WRITES: usize = 200;
+const WRITE_LEN: usize = 48;
+
+/// A TCP stream that counts the writes and bytes its clones issue.
+struct Counted {
+    inner: TcpStream,
+    counts: Arc<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    writes: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Read for Counted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.counts.writes.fetch_add(1, Ordering::Relaxed);
+        self.counts.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl BtStream for Counted {
+    fn try_clone(&self) -> common::error::Result<Self> {
+        Ok(Self {
+            inner: self.inner.try_clone()?,
+            counts: Arc::clone(&self.counts),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    client.set_nodelay(true).unwrap();
+    server.set_nodelay(true).unwrap();
+    (client, server)
+}
+
+struct Run {
+    writes: u64,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+async fn run(write_batch: usize, write_delay: Option<Duration>) -> Run {
+    let (a, b) = tcp_pair();
+    let counts = Arc::new(Counts::default());
+    let link_cfg = BtLinkConfig {
+        write_batch,
+        write_delay,
+        stats_interval: None,
+        ..BtLinkConfig::default()
+    };
+    let link = |inner| {
+        let stream = Counted {
+            inner,
+            counts: Arc::clone(&counts),
+        };
+        BtLink::spawn(stream, link_cfg.clone()).unwrap()
+    };
+    let (a, b) = (link(a), link(b));
+    let mux_cfg = MuxConfig {
+        write_batch,
+        ..MuxConfig::default()
+    };
+    let (client, server) = tokio::join!(
+        MuxSession::start(a, mux_cfg.clone(), Role::Client),
+        MuxSession::start(b, mux_cfg, Role::Server),
+    );
+    let (client, server) = (client.unwrap(), server.unwrap());
+
+    let started = Instant::now();
+    let sink = tokio::spawn(async move {
+        let mut readers = Vec::new();
+        for _ in 0..STREAMS {
+            let (_, mut stream) = server.accept_stream().await.unwrap();
+            server.send_open_ok(stream.stream_id).await.unwrap();
+            readers.push(tokio::spawn(async move {
+                let mut data = Vec::new();
+                stream.read_to_end(&mut data).await.unwrap();
+                assert_eq!(data.len(), WRITES * WRITE_LEN);
+            }));
+        }
+        for reader in readers {
+            reader.await.unwrap();
+        }
+    });
+    let mut writers = Vec::new();
+    for _ in 0..STREAMS {
+        let client = client.clone();
+        writers.push(tokio::spawn(async move {
+            let mut stream = client
+                .open_stream(TargetAddr::Domain("example.com".to_string(), 80))
+                .await
+                .unwrap();
+            for _ in 0..WRITES {
+                stream.write_all(&[b'x'; WRITE_LEN]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            stream.shutdown().await.unwrap();
+        }));
+    }
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    sink.await.unwrap();
+    Run {
+        writes: counts.writes.load(Ordering::Relaxed),
+        bytes: counts.bytes.load(Ordering::Relaxed),
+        elapsed: started.elapsed(),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let payload = (STREAMS * WRITES * WRITE_LEN) as f64;
+    println!(
+        "{} streams x {} writes of {} bytes over loopback tcp",
+        STREAMS, WRITES, WRITE_LEN
+    );
+    println!(
+        "{:<24} {:>8} {:>10} {:>12} {:>18} {:>10}",
+        "config", "writes", "bytes", "bytes/write", "writes/payload KiB", "elapsed"
+    );
+    for (name, batch, delay) in [
+        ("unbatched", 0, None),
+        ("batch 4096", 4096, None),
+        (
+            "batch 4096, delay 2ms",
+            4096,
+            Some(Duration::from_millis(2)),
+        ),
+    ] {
+        let run = run(batch, delay).await;
+        println!(
+            "{:<24} {:>8} {:>10} {:>12.1} {:>18.2} {:>8.0}ms",
+            name,
+            run.writes,
+            run.bytes,
+            run.bytes as f64 / run.writes as f64,
+            run.writes as f64 / (payload / 1024.0),
+            run.elapsed.as_secs_f64() * 1000.0
+        );
+    }
+}
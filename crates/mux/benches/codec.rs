@@ -0,0 +1,129 @@
+//! Frame codec and session throughput.
+//!
+//! Run with `cargo bench -p mux --bench codec`; `-- --save-baseline NAME`
+//! and `-- --baseline NAME` compare two builds.
+
+use btlink::{spawn_async_io, BtLinkConfig};
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use mux::codec::FrameCodec;
+use mux::{Frame, MuxConfig, MuxSession, Role, TargetAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+use tokio_util::codec::Decoder;
+
+const SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+/// Frames per decode iteration, so the buffer handling is part of it.
+const BATCH: usize = 64;
+const TRANSFER: usize = 8 * 1024 * 1024;
+
+fn codec(checksum: bool) -> FrameCodec {
+    let mut codec = FrameCodec::new(65536);
+    codec.set_checksum(checksum);
+    codec
+}
+
+fn data(size: usize) -> Frame {
+    Frame::Data {
+        stream_id: 1,
+        payload: Bytes::from(vec![0x5a; size]),
+    }
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for checksum in [false, true] {
+        let codec = codec(checksum);
+        for size in SIZES {
+            let frame = data(size);
+            group.throughput(Throughput::Bytes(size as u64));
+            let name = format!("{}/{}", if checksum { "checked" } else { "plain" }, size);
+            group.bench_function(name, |b| b.iter(|| codec.encode_frame(&frame).unwrap()));
+        }
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for checksum in [false, true] {
+        for size in SIZES {
+            let mut wire = BytesMut::new();
+            for _ in 0..BATCH {
+                wire.extend_from_slice(&codec(checksum).encode_frame(&data(size)).unwrap());
+            }
+            group.throughput(Throughput::Bytes((size * BATCH) as u64));
+            let name = format!("{}/{}", if checksum { "checked" } else { "plain" }, size);
+            group.bench_function(name, |b| {
+                b.iter_batched(
+                    || (codec(checksum), wire.clone()),
+                    |(mut codec, mut wire)| {
+                        while let Some(frame) = codec.decode(&mut wire).unwrap() {
+                            std::hint::black_box(frame);
+                        }
+                    },
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Pushes `TRANSFER` bytes through one stream of a session over an
+/// in-memory link.
+async fn transfer(psk: Option<&[u8]>) {
+    let (a, b) = tokio::io::duplex(256 * 1024);
+    let (a_read, a_write) = tokio::io::split(a);
+    let (b_read, b_write) = tokio::io::split(b);
+    let link_cfg = BtLinkConfig {
+        stats_interval: None,
+        ..BtLinkConfig::default()
+    };
+    let cfg = MuxConfig {
+        psk: psk.map(<[u8]>::to_vec),
+        ..MuxConfig::default()
+    };
+    let (client, server) = tokio::join!(
+        MuxSession::start(
+            spawn_async_io(a_read, a_write, link_cfg.clone()),
+            cfg.clone(),
+            Role::Client
+        ),
+        MuxSession::start(spawn_async_io(b_read, b_write, link_cfg), cfg, Role::Server),
+    );
+    let (client, server) = (client.unwrap(), server.unwrap());
+    let sink = tokio::spawn(async move {
+        let (_, mut stream) = server.accept_stream().await.unwrap();
+        server.send_open_ok(stream.stream_id).await.unwrap();
+        let mut buf = vec![0; 64 * 1024];
+        let mut received = 0;
+        while received < TRANSFER {
+            received += stream.read(&mut buf).await.unwrap();
+        }
+    });
+    let mut stream = client
+        .open_stream(TargetAddr::Domain("example.com".to_string(), 80))
+        .await
+        .unwrap();
+    let chunk = vec![0x5a; 64 * 1024];
+    for _ in 0..TRANSFER / chunk.len() {
+        stream.write_all(&chunk).await.unwrap();
+    }
+    sink.await.unwrap();
+}
+
+fn session(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("session");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(TRANSFER as u64));
+    group.bench_function("plain", |b| b.iter(|| runtime.block_on(transfer(None))));
+    group.bench_function("encrypted", |b| {
+        b.iter(|| runtime.block_on(transfer(Some(b"benchmark-psk"))))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode, session);
+criterion_main!(benches);
@@ -1,22 +1,321 @@
+use crate::crypto::{LinkCipher, SEAL_OVERHEAD};
 use crate::frame::Frame;
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use common::error::{BtProxyError, Result};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::debug;
+
+/// Marker in front of every checksummed frame, used to find the next frame
+/// boundary after corruption.
+pub const SYNC: [u8; 2] = [0xA5, 0x5A];
+
+/// `SYNC | LEN(u32be) | HCRC(u16be)` in front of `TYPE | PAYLOAD`.
+const CHECKED_HEADER: usize = 2 + 4 + 2;
+/// Trailing `CRC32(u32be)` over `LEN | HCRC | TYPE | PAYLOAD`.
+const CHECKED_TRAILER: usize = 4;
 
 pub fn try_decode(buffer: &mut BytesMut, max_frame: usize) -> Result<Option<Frame>> {
+    match take_body(buffer, max_frame)? {
+        Some(body) => decode_body(body.freeze()).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Splits one `LEN | BODY` off the front of `buffer`.
+fn take_body(buffer: &mut BytesMut, max_len: usize) -> Result<Option<BytesMut>> {
     if buffer.len() < 4 {
         return Ok(None);
     }
     let mut length_buf = &buffer[..4];
     let len = length_buf.get_u32() as usize;
-    if len > max_frame {
-        return Err(BtProxyError::Protocol("frame too large".to_string()));
+    if len == 0 || len > max_len {
+        return Err(BtProxyError::Protocol("bad frame length".to_string()));
     }
     if buffer.len() < 4 + len {
+        // Room for the rest of the frame in one go, rather than growing
+        // chunk by chunk.
+        buffer.reserve(4 + len - buffer.len());
         return Ok(None);
     }
     buffer.advance(4);
-    let frame_type = buffer.get_u8();
-    let payload_len = len - 1;
-    let payload = buffer.split_to(payload_len);
-    Frame::decode(frame_type, &payload).map(Some)
+    Ok(Some(buffer.split_to(len)))
+}
+
+/// Decodes `TYPE | PAYLOAD`.
+fn decode_body(body: Bytes) -> Result<Frame> {
+    match body.first() {
+        Some(&frame_type) => Frame::decode(frame_type, body.slice(1..)),
+        None => Err(BtProxyError::Protocol("empty frame".to_string())),
+    }
+}
+
+/// Link framing for one session. Starts in the plain `LEN | TYPE | PAYLOAD`
+/// format used by HELLO; once both peers advertise `FLAG_CHECKSUM` every
+/// frame is wrapped with a sync marker and CRC32 so corrupt frames can be
+/// dropped without losing the rest of the stream. With a cipher set,
+/// `TYPE | PAYLOAD` is sealed before it is framed.
+///
+/// Frames are written straight into the output buffer and sealed there;
+/// decoded DATA payloads are slices of the receive buffer.
+#[derive(Debug, Clone)]
+pub struct FrameCodec {
+    max_frame: usize,
+    checksum: bool,
+    /// Shared by clones, which encode for the same link.
+    cipher: Option<Arc<LinkCipher>>,
+    errors: Arc<AtomicU64>,
+    resyncing: bool,
+    /// Set when a frame may have been dropped since `take_lost`.
+    lost: bool,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame: usize) -> Self {
+        Self {
+            max_frame,
+            checksum: false,
+            cipher: None,
+            errors: Arc::new(AtomicU64::new(0)),
+            resyncing: false,
+            lost: false,
+        }
+    }
+
+    pub fn set_max_frame(&mut self, max_frame: usize) {
+        self.max_frame = max_frame;
+    }
+
+    pub fn set_checksum(&mut self, enabled: bool) {
+        self.checksum = enabled;
+    }
+
+    pub fn checksum(&self) -> bool {
+        self.checksum
+    }
+
+    pub(crate) fn set_cipher(&mut self, cipher: Option<Arc<LinkCipher>>) {
+        self.cipher = cipher;
+    }
+
+    pub(crate) fn cipher(&self) -> Option<Arc<LinkCipher>> {
+        self.cipher.clone()
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Corrupt frames and garbage bytes dropped so far, shared by clones.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Whether a frame may have been lost since the last call. Sealed links
+    /// know for sure from the counters; checksummed ones without a cipher
+    /// assume every corrupt stretch took a frame with it.
+    pub(crate) fn take_lost(&mut self) -> bool {
+        std::mem::take(&mut self.lost)
+    }
+
+    pub fn encode_frame(&self, frame: &Frame) -> Result<Bytes> {
+        let mut buf = BytesMut::new();
+        self.encode_into(frame, &mut buf)?;
+        Ok(buf.freeze())
+    }
+
+    /// Appends one framed (and sealed) frame to `dst`, leaving it as it was
+    /// on error.
+    pub fn encode_into(&self, frame: &Frame, dst: &mut BytesMut) -> Result<()> {
+        if self.cipher.is_none() && !self.checksum {
+            return frame.encode_into(dst);
+        }
+        let start = dst.len();
+        let res = self.put_framed(frame, dst);
+        if res.is_err() {
+            dst.truncate(start);
+        }
+        res
+    }
+
+    /// Writes the header with placeholders, the body behind it, then fills
+    /// in lengths and checksums.
+    fn put_framed(&self, frame: &Frame, dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(CHECKED_HEADER + SEAL_OVERHEAD + frame.size_hint() + CHECKED_TRAILER);
+        let start = dst.len();
+        let header = if self.checksum { CHECKED_HEADER } else { 4 };
+        dst.put_bytes(0, header);
+        let body = dst.len();
+        match &self.cipher {
+            Some(cipher) => {
+                dst.put_bytes(0, 8);
+                frame.encode_body(dst)?;
+                cipher.seal_in_place(dst, body)?;
+            }
+            None => frame.encode_body(dst)?,
+        }
+        let len = ((dst.len() - body) as u32).to_be_bytes();
+        if !self.checksum {
+            dst[start..body].copy_from_slice(&len);
+            return Ok(());
+        }
+        dst[start..start + 2].copy_from_slice(&SYNC);
+        dst[start + 2..start + 6].copy_from_slice(&len);
+        dst[start + 6..body].copy_from_slice(&header_check(&len).to_be_bytes());
+        let crc = crc32fast::hash(&dst[start + 2..]);
+        dst.put_u32(crc);
+        Ok(())
+    }
+
+    /// Largest body a frame may carry on the link.
+    fn max_len(&self) -> usize {
+        match self.cipher {
+            Some(_) => self.max_frame + SEAL_OVERHEAD,
+            None => self.max_frame,
+        }
+    }
+
+    /// Unseals a frame body if the link is encrypted.
+    fn open(&mut self, body: BytesMut) -> Option<Bytes> {
+        match &self.cipher {
+            Some(cipher) => {
+                let (body, skipped) = cipher.open(body)?;
+                if skipped > 0 {
+                    debug!(skipped, "sealed frames missing");
+                    self.lost = true;
+                }
+                Some(body)
+            }
+            None => Some(body.freeze()),
+        }
+    }
+
+    /// Counts a dropped frame or stretch of garbage.
+    fn count_error(&mut self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        if self.cipher.is_none() {
+            self.lost = true;
+        }
+    }
+
+    /// Discards bytes up to the next sync marker. Returns false when no full
+    /// marker is buffered yet; a trailing half marker is kept.
+    fn seek_sync(&mut self, buffer: &mut BytesMut) -> bool {
+        if buffer.starts_with(&SYNC) {
+            return true;
+        }
+        let found = buffer.windows(SYNC.len()).position(|w| w == SYNC);
+        let skip = match found {
+            Some(pos) => pos,
+            None if buffer.last() == Some(&SYNC[0]) => buffer.len() - 1,
+            None => buffer.len(),
+        };
+        if skip > 0 {
+            // Garbage right after a dropped frame belongs to the same error.
+            if !self.resyncing {
+                self.count_error();
+                self.resyncing = true;
+            }
+            debug!(skipped = skip, "resynchronising frame stream");
+            buffer.advance(skip);
+        }
+        found.is_some()
+    }
+
+    fn drop_frame(&mut self, buffer: &mut BytesMut, why: &str) {
+        if !self.resyncing {
+            self.count_error();
+            self.resyncing = true;
+        }
+        debug!(reason = why, "dropping corrupt frame");
+        // Skip just this marker; the real next frame may start inside what
+        // the corrupt header claimed as its body.
+        buffer.advance(1);
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = BtProxyError;
+
+    /// Plain mode fails on an oversized frame since the stream cannot be
+    /// resynchronised; checksummed mode never fails, it skips ahead instead.
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Frame>> {
+        if !self.checksum {
+            let Some(body) = take_body(buffer, self.max_len())? else {
+                return Ok(None);
+            };
+            return match self.open(body) {
+                Some(body) => decode_body(body).map(Some),
+                None => Err(BtProxyError::Auth(
+                    "frame failed authentication".to_string(),
+                )),
+            };
+        }
+        loop {
+            if !self.seek_sync(buffer) {
+                return Ok(None);
+            }
+            if buffer.len() < CHECKED_HEADER {
+                return Ok(None);
+            }
+            let len = (&buffer[2..6]).get_u32() as usize;
+            let check = (&buffer[6..8]).get_u16();
+            if check != header_check(&buffer[2..6]) || len == 0 || len > self.max_len() {
+                self.drop_frame(buffer, "bad frame header");
+                continue;
+            }
+            let total = CHECKED_HEADER + len + CHECKED_TRAILER;
+            if buffer.len() < total {
+                buffer.reserve(total - buffer.len());
+                return Ok(None);
+            }
+            let expected = (&buffer[total - CHECKED_TRAILER..total]).get_u32();
+            if crc32fast::hash(&buffer[2..total - CHECKED_TRAILER]) != expected {
+                self.drop_frame(buffer, "frame checksum mismatch");
+                continue;
+            }
+            buffer.advance(CHECKED_HEADER);
+            let body = buffer.split_to(len);
+            buffer.advance(CHECKED_TRAILER);
+            self.resyncing = false;
+            let Some(body) = self.open(body) else {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                debug!("dropping frame that failed authentication");
+                continue;
+            };
+            match decode_body(body) {
+                Ok(frame) => return Ok(Some(frame)),
+                Err(err) => {
+                    // Genuine but not understood, so a frame is gone.
+                    self.errors.fetch_add(1, Ordering::Relaxed);
+                    self.lost = true;
+                    debug!(?err, "dropping undecodable frame");
+                }
+            }
+        }
+    }
+}
+
+impl Encoder<&Frame> for FrameCodec {
+    type Error = BtProxyError;
+
+    fn encode(&mut self, frame: &Frame, dst: &mut BytesMut) -> Result<()> {
+        self.encode_into(frame, dst)
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = BtProxyError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<()> {
+        self.encode_into(&frame, dst)
+    }
+}
+
+fn header_check(len: &[u8]) -> u16 {
+    crc32fast::hash(len) as u16
 }
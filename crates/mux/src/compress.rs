@@ -0,0 +1,232 @@
+use crate::frame::Compression;
+use bytes::Bytes;
+use common::error::{BtProxyError, Result};
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+use std::sync::atomic::{AtomicU64, Ordering};
+use zstd::stream::raw::{Decoder, Encoder, InBuffer, Operation, OutBuffer};
+use zstd::zstd_safe::{CParameter, DParameter};
+
+/// Fast levels: the link, not the CPU, is the bottleneck, but barely.
+const ZSTD_LEVEL: i32 = 1;
+/// A 64 KiB window keeps a stream's contexts small.
+const ZSTD_WINDOW_LOG: u32 = 16;
+/// Frames skipped after a payload that did not shrink, doubling per miss.
+const MAX_BACKOFF: u32 = 64;
+
+// Each stream compresses its DATA with one streaming context per direction,
+// so later frames refer back to earlier ones. Every compressed frame is
+// flushed and decodes on its own once the frames before it have. Frames of
+// a stream are compressed by the session writer in the order they go out
+// (retransmits reuse the compressed frame), and decompressed by the
+// dispatcher in the same order.
+//
+// A payload that does not shrink goes out as plain DATA instead, which
+// resets the context on both sides; the sender then leaves the next few
+// frames uncompressed, so TLS and other random-looking streams cost little.
+
+/// Sending half of a stream's compression.
+pub(crate) struct StreamCompressor {
+    algorithm: Compression,
+    context: Option<Deflater>,
+    skip: u32,
+    backoff: u32,
+}
+
+impl StreamCompressor {
+    pub(crate) fn new(algorithm: Compression) -> Self {
+        Self {
+            algorithm,
+            context: None,
+            skip: 0,
+            backoff: 0,
+        }
+    }
+
+    /// Returns the compressed payload, or `None` if `data` should go out as
+    /// plain DATA.
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            self.context = None;
+            return None;
+        }
+        let context = match &mut self.context {
+            Some(context) => context,
+            None => self.context.insert(Deflater::new(self.algorithm).ok()?),
+        };
+        // Output that would not beat the plain frame is useless, so the
+        // buffer stops there.
+        match context.compress(data, data.len().saturating_sub(1)) {
+            Some(out) => {
+                self.backoff = 0;
+                Some(out)
+            }
+            None => {
+                self.context = None;
+                self.backoff = (self.backoff * 2).clamp(1, MAX_BACKOFF);
+                self.skip = self.backoff;
+                None
+            }
+        }
+    }
+}
+
+/// Receiving half of a stream's compression.
+pub(crate) struct StreamDecompressor {
+    algorithm: Compression,
+    context: Option<Inflater>,
+}
+
+impl StreamDecompressor {
+    pub(crate) fn new(algorithm: Compression) -> Self {
+        Self {
+            algorithm,
+            context: None,
+        }
+    }
+
+    /// The peer sent plain DATA and starts over with a fresh context.
+    pub(crate) fn reset(&mut self) {
+        self.context = None;
+    }
+
+    /// Fails if `data` does not decompress to exactly `len` bytes.
+    pub(crate) fn decompress(&mut self, data: &[u8], len: usize) -> Result<Bytes> {
+        let context = match &mut self.context {
+            Some(context) => context,
+            None => self.context.insert(Inflater::new(self.algorithm)?),
+        };
+        let out = context.decompress(data, len);
+        if out.is_none() {
+            self.context = None;
+        }
+        out.map(Bytes::from).ok_or_else(|| {
+            BtProxyError::Protocol(format!("{:?} payload does not decompress", self.algorithm))
+        })
+    }
+}
+
+/// DATA payload bytes before and after compression.
+#[derive(Default)]
+pub(crate) struct CompressionCounters {
+    pub(crate) sent: AtomicU64,
+    pub(crate) sent_wire: AtomicU64,
+    pub(crate) received: AtomicU64,
+    pub(crate) received_wire: AtomicU64,
+}
+
+impl CompressionCounters {
+    pub(crate) fn count_sent(&self, plain: usize, wire: usize) {
+        self.sent.fetch_add(plain as u64, Ordering::Relaxed);
+        self.sent_wire.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_received(&self, plain: usize, wire: usize) {
+        self.received.fetch_add(plain as u64, Ordering::Relaxed);
+        self.received_wire.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+}
+
+enum Deflater {
+    Zstd(Encoder<'static>),
+    Deflate(Compress),
+}
+
+impl Deflater {
+    fn new(algorithm: Compression) -> Result<Self> {
+        Ok(match algorithm {
+            Compression::Zstd => {
+                let mut encoder = Encoder::new(ZSTD_LEVEL)?;
+                encoder.set_parameter(CParameter::WindowLog(ZSTD_WINDOW_LOG))?;
+                Deflater::Zstd(encoder)
+            }
+            Compression::Deflate => {
+                Deflater::Deflate(Compress::new(flate2::Compression::fast(), false))
+            }
+        })
+    }
+
+    /// Compresses and flushes `data`, or gives up once the output would
+    /// exceed `limit` bytes. The context is unusable after giving up.
+    fn compress(&mut self, data: &[u8], limit: usize) -> Option<Vec<u8>> {
+        let mut out = vec![0; limit];
+        match self {
+            Deflater::Zstd(encoder) => {
+                let mut input = InBuffer::around(data);
+                let mut output = OutBuffer::around(&mut out[..]);
+                encoder.run(&mut input, &mut output).ok()?;
+                if input.pos() < data.len() || encoder.flush(&mut output).ok()? > 0 {
+                    return None;
+                }
+                let len = output.pos();
+                out.truncate(len);
+            }
+            Deflater::Deflate(compress) => {
+                let before = (compress.total_in(), compress.total_out());
+                let status = compress
+                    .compress(data, &mut out, FlushCompress::Sync)
+                    .ok()?;
+                let read = (compress.total_in() - before.0) as usize;
+                let written = (compress.total_out() - before.1) as usize;
+                // A full buffer may hide more pending output.
+                if status != Status::Ok || read < data.len() || written == limit {
+                    return None;
+                }
+                out.truncate(written);
+            }
+        }
+        Some(out)
+    }
+}
+
+enum Inflater {
+    Zstd(Decoder<'static>),
+    Deflate(Decompress),
+}
+
+impl Inflater {
+    fn new(algorithm: Compression) -> Result<Self> {
+        Ok(match algorithm {
+            Compression::Zstd => {
+                let mut decoder = Decoder::new()?;
+                decoder.set_parameter(DParameter::WindowLogMax(ZSTD_WINDOW_LOG))?;
+                Inflater::Zstd(decoder)
+            }
+            Compression::Deflate => Inflater::Deflate(Decompress::new(false)),
+        })
+    }
+
+    /// Output is capped at `len`, so a hostile peer cannot inflate a frame
+    /// past what it announced.
+    fn decompress(&mut self, data: &[u8], len: usize) -> Option<Vec<u8>> {
+        let mut out = vec![0; len];
+        match self {
+            Inflater::Zstd(decoder) => {
+                let mut input = InBuffer::around(data);
+                let mut output = OutBuffer::around(&mut out[..]);
+                while input.pos() < data.len() {
+                    let before = (input.pos(), output.pos());
+                    decoder.run(&mut input, &mut output).ok()?;
+                    if (input.pos(), output.pos()) == before {
+                        return None;
+                    }
+                }
+                if output.pos() < len {
+                    return None;
+                }
+            }
+            Inflater::Deflate(decompress) => {
+                let before = (decompress.total_in(), decompress.total_out());
+                decompress
+                    .decompress(data, &mut out, FlushDecompress::Sync)
+                    .ok()?;
+                let read = (decompress.total_in() - before.0) as usize;
+                let written = (decompress.total_out() - before.1) as usize;
+                if read < data.len() || written < len {
+                    return None;
+                }
+            }
+        }
+        Some(out)
+    }
+}
@@ -0,0 +1,110 @@
+use crate::frame::Frame;
+use crate::session::Role;
+use bytes::{Buf, Bytes, BytesMut};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use common::error::{BtProxyError, Result};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes sealing adds to a frame: the counter in front, the tag behind.
+pub(crate) const SEAL_OVERHEAD: usize = 8 + TAG_LEN;
+const TAG_LEN: usize = 16;
+
+const CLIENT_KEY_LABEL: &[u8] = b"btproxy-v1 client to server";
+const SERVER_KEY_LABEL: &[u8] = b"btproxy-v1 server to client";
+
+/// ChaCha20-Poly1305 state of one link. Each direction has its own key, so
+/// the two counters never produce the same nonce under one key.
+pub(crate) struct LinkCipher {
+    seal: ChaCha20Poly1305,
+    open: ChaCha20Poly1305,
+    /// Counter of the next frame we seal.
+    sent: AtomicU64,
+    /// Lowest counter still accepted from the peer; anything below is a
+    /// replay.
+    next_recv: AtomicU64,
+}
+
+impl LinkCipher {
+    /// Derives the link keys with HKDF from the shared secret (PSK and/or
+    /// X25519 result), salted with a hash of the HELLO and HELLO_ACK. Both
+    /// carry a random salt, and tampering with either leaves the sides with
+    /// different keys.
+    pub(crate) fn derive(secret: &[u8], role: Role, hello: &Frame, ack: &Frame) -> Result<Self> {
+        let mut transcript = Sha256::new();
+        transcript.update(hello.encode()?);
+        transcript.update(ack.encode()?);
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript.finalize()), secret);
+        let mut client_key = [0u8; 32];
+        let mut server_key = [0u8; 32];
+        hkdf.expand(CLIENT_KEY_LABEL, &mut client_key)
+            .and_then(|_| hkdf.expand(SERVER_KEY_LABEL, &mut server_key))
+            .map_err(|_| BtProxyError::Protocol("key derivation failed".to_string()))?;
+        let (seal, open) = match role {
+            Role::Client => (client_key, server_key),
+            Role::Server => (server_key, client_key),
+        };
+        Ok(Self {
+            seal: ChaCha20Poly1305::new(Key::from_slice(&seal)),
+            open: ChaCha20Poly1305::new(Key::from_slice(&open)),
+            sent: AtomicU64::new(0),
+            next_recv: AtomicU64::new(0),
+        })
+    }
+
+    /// Seals the frame body at `buf[start + 8..]` in place into
+    /// `COUNTER(u64be) | CIPHERTEXT | TAG`; the 8 bytes at `start` are
+    /// reserved for the counter.
+    pub(crate) fn seal_in_place(&self, buf: &mut BytesMut, start: usize) -> Result<()> {
+        let counter = self.sent.fetch_add(1, Ordering::Relaxed);
+        buf[start..start + 8].copy_from_slice(&counter.to_be_bytes());
+        let tag = self
+            .seal
+            .encrypt_in_place_detached(&nonce(counter), &[], &mut buf[start + 8..])
+            .map_err(|_| BtProxyError::Protocol("frame encryption failed".to_string()))?;
+        buf.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Decrypts a sealed frame body in place, along with how many frames the
+    /// peer sealed before it that never arrived. Returns `None` for a
+    /// forged, corrupt or replayed frame. Counters may skip, since the
+    /// checksummed framing drops corrupt frames, but never go back.
+    pub(crate) fn open(&self, mut sealed: BytesMut) -> Option<(Bytes, u64)> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return None;
+        }
+        let counter = u64::from_be_bytes(sealed[..8].try_into().ok()?);
+        let expected = self.next_recv.load(Ordering::Relaxed);
+        if counter < expected {
+            return None;
+        }
+        let tag_at = sealed.len() - TAG_LEN;
+        let tag = Tag::clone_from_slice(&sealed[tag_at..]);
+        self.open
+            .decrypt_in_place_detached(&nonce(counter), &[], &mut sealed[8..tag_at], &tag)
+            .ok()?;
+        self.next_recv.store(counter + 1, Ordering::Relaxed);
+        sealed.truncate(tag_at);
+        sealed.advance(8);
+        Some((sealed.freeze(), counter - expected))
+    }
+}
+
+impl fmt::Debug for LinkCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LinkCipher")
+            .field("sent", &self.sent)
+            .field("next_recv", &self.next_recv)
+            .finish_non_exhaustive()
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::from(nonce)
+}
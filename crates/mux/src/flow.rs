@@ -0,0 +1,202 @@
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::sync::{Notify, Semaphore};
+use tokio_util::sync::PollSemaphore;
+
+/// Largest DATA payload sent in one frame.
+const MAX_DATA: usize = 16 * 1024;
+
+/// Frame bytes around a DATA payload: SEQ header, type, stream id, length.
+const DATA_OVERHEAD: usize = 16;
+
+/// Largest DATA payload that fits in a frame of `max_frame` bytes.
+pub(crate) fn max_data(max_frame: u32) -> usize {
+    (max_frame as usize)
+        .saturating_sub(DATA_OVERHEAD)
+        .min(MAX_DATA)
+}
+
+/// Flow-control state of one stream.
+///
+/// Sending is limited by credit: bytes the peer's receive window still has
+/// room for, topped up by its WINDOW_UPDATE frames. Receiving counts the
+/// bytes the peer may still send and hands credit back as the consumer
+/// reads. A peer that did not advertise a window gets no WINDOW_UPDATE;
+/// instead the session waits for the consumer before delivering more, as it
+/// always did.
+pub(crate) struct StreamFlow {
+    /// `None` when the peer does not do flow control.
+    credit: Option<Arc<Semaphore>>,
+    peer_window: u32,
+    window: u32,
+    max_data: usize,
+    recv: Mutex<RecvWindow>,
+    drained: Notify,
+}
+
+struct RecvWindow {
+    /// Bytes the peer may still send. Goes negative only if it overruns.
+    available: i64,
+    /// Bytes read since credit was last handed back.
+    consumed: u32,
+}
+
+impl StreamFlow {
+    pub(crate) fn new(window: u32, peer_window: Option<u32>, max_data: usize) -> Self {
+        Self {
+            credit: peer_window.map(|peer| Arc::new(Semaphore::new(peer as usize))),
+            peer_window: peer_window.unwrap_or(0),
+            window,
+            max_data,
+            recv: Mutex::new(RecvWindow {
+                available: window as i64,
+                consumed: 0,
+            }),
+            drained: Notify::new(),
+        }
+    }
+
+    /// Whether both sides exchange WINDOW_UPDATE on this stream.
+    pub(crate) fn negotiated(&self) -> bool {
+        self.credit.is_some()
+    }
+
+    /// Largest DATA payload that can ever be covered by credit.
+    pub(crate) fn max_chunk(&self) -> usize {
+        if self.negotiated() {
+            (self.peer_window as usize).min(self.max_data)
+        } else {
+            self.max_data
+        }
+    }
+
+    /// Waits until `len` bytes may be sent. Returns false once the stream
+    /// is closed.
+    pub(crate) async fn reserve(&self, len: usize) -> bool {
+        let Some(credit) = &self.credit else {
+            return true;
+        };
+        match credit.acquire_many(len as u32).await {
+            Ok(permits) => {
+                permits.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// A holder for credit a polled writer takes ahead of its bytes.
+    pub(crate) fn reserve_credit(&self) -> CreditReserve {
+        CreditReserve {
+            credit: self.credit.clone().map(PollSemaphore::new),
+            held: 0,
+        }
+    }
+
+    /// Adds credit from a WINDOW_UPDATE. Returns false if the peer grants
+    /// more than a window can hold.
+    pub(crate) fn grant(&self, increment: u32) -> bool {
+        let Some(credit) = &self.credit else {
+            return true;
+        };
+        if credit.available_permits() as u64 + increment as u64 > u32::MAX as u64 {
+            return false;
+        }
+        credit.add_permits(increment as usize);
+        true
+    }
+
+    /// Fails pending and future sends.
+    pub(crate) fn close(&self) {
+        if let Some(credit) = &self.credit {
+            credit.close();
+        }
+        self.drained.notify_one();
+    }
+
+    /// Waits until the consumer has room for more data. Only used for peers
+    /// that do not respect a window.
+    pub(crate) async fn wait_room(&self) {
+        loop {
+            if self.recv.lock().unwrap().available > 0 {
+                return;
+            }
+            self.drained.notified().await;
+        }
+    }
+
+    /// Accounts for `len` bytes arriving from the peer. Returns false if a
+    /// flow-controlled peer sent more than its window.
+    pub(crate) fn receive(&self, len: usize) -> bool {
+        let mut recv = self.recv.lock().unwrap();
+        recv.available -= len as i64;
+        recv.available >= 0 || !self.negotiated()
+    }
+
+    /// Accounts for `len` bytes read by the consumer. Returns the increment
+    /// to send in a WINDOW_UPDATE once half the window has been read.
+    pub(crate) fn consume(&self, len: usize) -> Option<u32> {
+        let mut recv = self.recv.lock().unwrap();
+        if !self.negotiated() {
+            recv.available += len as i64;
+            drop(recv);
+            self.drained.notify_one();
+            return None;
+        }
+        recv.consumed += len as u32;
+        if recv.consumed < (self.window / 2).max(1) {
+            return None;
+        }
+        let increment = std::mem::take(&mut recv.consumed);
+        recv.available += increment as i64;
+        Some(increment)
+    }
+}
+
+/// Credit a writer took from its stream but has not spent yet. Lets
+/// `poll_write` wait for credit without committing to any bytes: credit
+/// taken by a poll that then returns `Pending` is kept for the next one.
+pub(crate) struct CreditReserve {
+    /// `None` when the peer does not do flow control.
+    credit: Option<PollSemaphore>,
+    held: usize,
+}
+
+impl CreditReserve {
+    /// Waits until some credit is held and returns how much, up to `want`.
+    /// Returns `None` once the stream is closed.
+    pub(crate) fn poll_reserve(
+        &mut self,
+        cx: &mut Context<'_>,
+        want: usize,
+    ) -> Poll<Option<usize>> {
+        let Some(credit) = &mut self.credit else {
+            return Poll::Ready(Some(want));
+        };
+        if self.held == 0 {
+            let Some(permit) = ready!(credit.poll_acquire(cx)) else {
+                return Poll::Ready(None);
+            };
+            permit.forget();
+            self.held = 1;
+        }
+        // Top up with whatever is free rather than waiting for all of it.
+        let more = want
+            .saturating_sub(self.held)
+            .min(credit.available_permits());
+        if more > 0 {
+            if let Ok(permits) = credit.clone_inner().try_acquire_many(more as u32) {
+                permits.forget();
+                self.held += more;
+            }
+        }
+        Poll::Ready(Some(self.held.min(want)))
+    }
+
+    /// Uses up `len` bytes of the reserved credit.
+    pub(crate) fn spend(&mut self, len: usize) {
+        if self.credit.is_some() {
+            self.held -= len;
+        }
+    }
+}
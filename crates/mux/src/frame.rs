@@ -1,21 +1,77 @@
 use bytes::{BufMut, Bytes, BytesMut};
 use common::error::{BtProxyError, Result};
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum FrameType {
     Hello = 0x01,
     HelloAck = 0x02,
+    Join = 0x03,
+    Auth = 0x04,
+    AuthKey = 0x05,
     Open = 0x10,
     OpenOk = 0x11,
     OpenErr = 0x12,
     Data = 0x20,
     Fin = 0x21,
     Rst = 0x22,
+    WindowUpdate = 0x23,
+    CompressedData = 0x24,
     Ping = 0x30,
     Pong = 0x31,
+    GoAway = 0x32,
+    Seq = 0x40,
+    Ack = 0x41,
 }
 
+/// HELLO flag bits. Capability bits are used only when both sides
+/// advertise them.
+pub const FLAG_PSK: u16 = 0x0001;
+/// Capability: DATA payloads may be compressed; the HELLO lists the
+/// algorithms.
+pub const FLAG_COMPRESS: u16 = 0x0002;
+pub const FLAG_CHECKSUM: u16 = 0x0004;
+/// The session may span several links; frames are sequenced and acked.
+pub const FLAG_BOND: u16 = 0x0008;
+/// This link joins an existing bonded session; a JOIN frame follows.
+pub const FLAG_JOIN: u16 = 0x0010;
+/// The HELLO carries the sender's initial per-stream receive window.
+pub const FLAG_WINDOW: u16 = 0x0020;
+/// The sender understands GOAWAY.
+pub const FLAG_GOAWAY: u16 = 0x0040;
+/// The sender opens odd stream ids as client and even ones as server, so
+/// either side may open streams.
+pub const FLAG_SPLIT_IDS: u16 = 0x0080;
+/// Every frame after the handshake is sealed with keys derived from the
+/// PSK; the HELLO carries a key salt.
+pub const FLAG_ENCRYPT: u16 = 0x0100;
+/// Both sides prove the PSK over the other's challenge: the server in
+/// HELLO_ACK, the client in a following AUTH frame. The HELLO itself carries
+/// no HMAC.
+pub const FLAG_MUTUAL_AUTH: u16 = 0x0200;
+/// The client signs the handshake with an Ed25519 key in a following
+/// AUTH_KEY frame. The HELLO carries an ephemeral X25519 key, and the
+/// session keys also cover the exchange.
+pub const FLAG_PUBKEY: u16 = 0x0400;
+/// Capability: OPEN may carry the first bytes of the stream.
+pub const FLAG_EARLY_DATA: u16 = 0x0800;
+/// Capability: UDP associations over the mux.
+pub const FLAG_UDP: u16 = 0x1000;
+
+/// GOAWAY codes.
+pub const GOAWAY_NO_ERROR: u16 = 0;
+pub const GOAWAY_PROTOCOL_ERROR: u16 = 1;
+/// The sender is shutting down or restarting; reconnect later.
+pub const GOAWAY_SHUTDOWN: u16 = 2;
+/// A newer session from the same client took over.
+pub const GOAWAY_REPLACED: u16 = 3;
+/// The server does not take another client right now.
+pub const GOAWAY_REJECTED: u16 = 4;
+/// Sent instead of HELLO_ACK: the peers share no protocol version, or the
+/// client's frame size is too small.
+pub const GOAWAY_INCOMPATIBLE: u16 = 5;
+
 #[derive(Debug, Clone)]
 pub struct HelloFrame {
     pub version: u16,
@@ -24,15 +80,42 @@ pub struct HelloFrame {
     pub keepalive_ms: u32,
     pub nonce: u64,
     pub hmac: Option<[u8; 32]>,
+    /// Bytes the sender will buffer per stream before the peer must wait
+    /// for a WINDOW_UPDATE. Present with `FLAG_WINDOW`.
+    pub initial_window: Option<u32>,
+    /// Random challenge covered by the peer's PSK proof and input to the
+    /// session keys. Present with `FLAG_ENCRYPT`.
+    pub key_salt: Option<[u8; 16]>,
+    /// Ephemeral X25519 public key. Present with `FLAG_PUBKEY`.
+    pub kex: Option<[u8; 32]>,
+    /// Compression algorithm ids, most preferred first; HELLO_ACK carries
+    /// the one the server picked. Present with `FLAG_COMPRESS`.
+    pub compression: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Frame {
-    Hello(HelloFrame),
-    HelloAck(HelloFrame),
+    /// Boxed so the rare HELLO does not size every frame.
+    Hello(Box<HelloFrame>),
+    HelloAck(Box<HelloFrame>),
+    /// First frame on an extra link of a bonded session. The token is the
+    /// nonce of the HELLO that created the session.
+    Join {
+        token: u64,
+    },
+    /// The client's PSK proof over HELLO and HELLO_ACK; ends the handshake.
+    Auth {
+        proof: [u8; 32],
+    },
+    /// The client's Ed25519 key and its signature over HELLO and HELLO_ACK.
+    AuthKey {
+        public_key: [u8; 32],
+        signature: [u8; 64],
+    },
     Open {
         stream_id: u32,
         target: TargetAddr,
+        priority: Priority,
     },
     OpenOk {
         stream_id: u32,
@@ -46,6 +129,13 @@ pub enum Frame {
         stream_id: u32,
         payload: Bytes,
     },
+    /// DATA compressed with the stream's context for the negotiated
+    /// algorithm. `len` is the payload length before compression.
+    CompressedData {
+        stream_id: u32,
+        len: u16,
+        payload: Bytes,
+    },
     Fin {
         stream_id: u32,
     },
@@ -53,12 +143,33 @@ pub enum Frame {
         stream_id: u32,
         code: u16,
     },
+    /// The receiver has consumed `increment` more bytes of the stream.
+    WindowUpdate {
+        stream_id: u32,
+        increment: u32,
+    },
     Ping {
         nonce: u64,
     },
     Pong {
         nonce: u64,
     },
+    /// The sender accepts no stream above `last_stream_id` and closes the
+    /// session once the remaining ones are done.
+    GoAway {
+        last_stream_id: u32,
+        code: u16,
+    },
+    /// Envelope carrying the session-wide sequence number of a frame on a
+    /// bonded session.
+    Seq {
+        seq: u64,
+        frame: Box<Frame>,
+    },
+    /// Every sequenced frame below `next_seq` has arrived.
+    Ack {
+        next_seq: u64,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -68,9 +179,110 @@ pub enum TargetAddr {
     IpV6([u8; 16], u16),
 }
 
+/// How the write path ranks a stream against the others. Carried in OPEN so
+/// both directions of the stream use it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum Priority {
+    Bulk = 0,
+    #[default]
+    Normal = 1,
+    Interactive = 2,
+}
+
+impl Priority {
+    pub(crate) const LEVELS: usize = 3;
+
+    /// Unknown values from newer peers fall back to `Normal`.
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Priority::Bulk,
+            2 => Priority::Interactive,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// DATA compression algorithms, by their id in HELLO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Compression {
+    Zstd = 1,
+    Deflate = 2,
+}
+
+impl Compression {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = BtProxyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "zstd" => Ok(Compression::Zstd),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(BtProxyError::Config(format!("unknown compression: {}", s))),
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = BtProxyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "bulk" | "low" => Ok(Priority::Bulk),
+            "normal" => Ok(Priority::Normal),
+            "interactive" | "high" => Ok(Priority::Interactive),
+            _ => Err(BtProxyError::Config(format!("unknown priority: {}", s))),
+        }
+    }
+}
+
 impl Frame {
     pub fn encode(&self) -> Result<Bytes> {
-        let mut payload = BytesMut::new();
+        let mut buf = BytesMut::new();
+        self.encode_into(&mut buf)?;
+        Ok(buf.freeze())
+    }
+
+    /// Appends `LEN | TYPE | PAYLOAD` to `dst`, leaving it as it was on
+    /// error.
+    pub fn encode_into(&self, dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(self.size_hint());
+        let start = dst.len();
+        dst.put_u32(0);
+        if let Err(err) = self.encode_body(dst) {
+            dst.truncate(start);
+            return Err(err);
+        }
+        let len = (dst.len() - start - 4) as u32;
+        dst[start..start + 4].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+
+    /// Roughly the encoded size, so buffers are allocated once.
+    pub(crate) fn size_hint(&self) -> usize {
+        match self {
+            Frame::Data { payload, .. } | Frame::CompressedData { payload, .. } => {
+                16 + payload.len()
+            }
+            Frame::Seq { frame, .. } => 8 + frame.size_hint(),
+            _ => 64,
+        }
+    }
+
+    /// Appends `TYPE | PAYLOAD` to `dst`.
+    pub(crate) fn encode_body(&self, payload: &mut BytesMut) -> Result<()> {
+        let start = payload.len();
+        payload.put_u8(0);
         let frame_type = match self {
             Frame::Hello(frame) => {
                 payload.put_u16(frame.version);
@@ -81,6 +293,19 @@ impl Frame {
                 if let Some(hmac) = frame.hmac {
                     payload.extend_from_slice(&hmac);
                 }
+                if let Some(window) = frame.initial_window {
+                    payload.put_u32(window);
+                }
+                if let Some(salt) = frame.key_salt {
+                    payload.extend_from_slice(&salt);
+                }
+                if let Some(kex) = frame.kex {
+                    payload.extend_from_slice(&kex);
+                }
+                if frame.flags & FLAG_COMPRESS != 0 {
+                    payload.put_u8(frame.compression.len() as u8);
+                    payload.extend_from_slice(&frame.compression);
+                }
                 FrameType::Hello
             }
             Frame::HelloAck(frame) => {
@@ -92,9 +317,42 @@ impl Frame {
                 if let Some(hmac) = frame.hmac {
                     payload.extend_from_slice(&hmac);
                 }
+                if let Some(window) = frame.initial_window {
+                    payload.put_u32(window);
+                }
+                if let Some(salt) = frame.key_salt {
+                    payload.extend_from_slice(&salt);
+                }
+                if let Some(kex) = frame.kex {
+                    payload.extend_from_slice(&kex);
+                }
+                if frame.flags & FLAG_COMPRESS != 0 {
+                    payload.put_u8(frame.compression.len() as u8);
+                    payload.extend_from_slice(&frame.compression);
+                }
                 FrameType::HelloAck
             }
-            Frame::Open { stream_id, target } => {
+            Frame::Join { token } => {
+                payload.put_u64(*token);
+                FrameType::Join
+            }
+            Frame::Auth { proof } => {
+                payload.extend_from_slice(proof);
+                FrameType::Auth
+            }
+            Frame::AuthKey {
+                public_key,
+                signature,
+            } => {
+                payload.extend_from_slice(public_key);
+                payload.extend_from_slice(signature);
+                FrameType::AuthKey
+            }
+            Frame::Open {
+                stream_id,
+                target,
+                priority,
+            } => {
                 payload.put_u32(*stream_id);
                 match target {
                     TargetAddr::Domain(host, port) => {
@@ -114,6 +372,7 @@ impl Frame {
                         payload.put_u16(*port);
                     }
                 }
+                payload.put_u8(*priority as u8);
                 FrameType::Open
             }
             Frame::OpenOk { stream_id } => {
@@ -140,6 +399,16 @@ impl Frame {
                 payload.extend_from_slice(data);
                 FrameType::Data
             }
+            Frame::CompressedData {
+                stream_id,
+                len,
+                payload: data,
+            } => {
+                payload.put_u32(*stream_id);
+                payload.put_u16(*len);
+                payload.extend_from_slice(data);
+                FrameType::CompressedData
+            }
             Frame::Fin { stream_id } => {
                 payload.put_u32(*stream_id);
                 FrameType::Fin
@@ -149,6 +418,14 @@ impl Frame {
                 payload.put_u16(*code);
                 FrameType::Rst
             }
+            Frame::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                payload.put_u32(*stream_id);
+                payload.put_u32(*increment);
+                FrameType::WindowUpdate
+            }
             Frame::Ping { nonce } => {
                 payload.put_u64(*nonce);
                 FrameType::Ping
@@ -157,35 +434,71 @@ impl Frame {
                 payload.put_u64(*nonce);
                 FrameType::Pong
             }
+            Frame::GoAway {
+                last_stream_id,
+                code,
+            } => {
+                payload.put_u32(*last_stream_id);
+                payload.put_u16(*code);
+                FrameType::GoAway
+            }
+            Frame::Seq { seq, frame } => {
+                if matches!(**frame, Frame::Seq { .. }) {
+                    return Err(BtProxyError::Protocol("nested seq frame".to_string()));
+                }
+                payload.put_u64(*seq);
+                // Inner TYPE | PAYLOAD without its length prefix.
+                frame.encode_body(payload)?;
+                FrameType::Seq
+            }
+            Frame::Ack { next_seq } => {
+                payload.put_u64(*next_seq);
+                FrameType::Ack
+            }
         };
-        let total_len = 1 + payload.len();
-        let mut buf = BytesMut::with_capacity(4 + total_len);
-        buf.put_u32(total_len as u32);
-        buf.put_u8(frame_type as u8);
-        buf.extend_from_slice(&payload);
-        Ok(buf.freeze())
+        payload[start] = frame_type as u8;
+        Ok(())
     }
 
-    pub fn decode(frame_type: u8, payload: &[u8]) -> Result<Frame> {
-        let mut cursor = std::io::Cursor::new(payload);
+    /// DATA payloads are slices of `payload`, not copies. A payload shorter
+    /// than its frame type needs is a protocol error.
+    pub fn decode(frame_type: u8, payload: Bytes) -> Result<Frame> {
         match frame_type {
             0x01 | 0x02 => {
-                if payload.len() < 20 {
-                    return Err(BtProxyError::Protocol("hello too short".to_string()));
-                }
-                use bytes::Buf;
-                let version = cursor.get_u16();
-                let flags = cursor.get_u16();
-                let max_frame = cursor.get_u32();
-                let keepalive_ms = cursor.get_u32();
-                let nonce = cursor.get_u64();
-                let hmac = if cursor.remaining() >= 32 {
-                    let mut buf = [0u8; 32];
-                    cursor.copy_to_slice(&mut buf);
-                    Some(buf)
+                let mut fields = Fields::new(&payload, "hello");
+                let version = fields.u16()?;
+                let flags = fields.u16()?;
+                let max_frame = fields.u32()?;
+                let keepalive_ms = fields.u32()?;
+                let nonce = fields.u64()?;
+                // A mutual-auth HELLO leaves the proof to AUTH.
+                let has_hmac =
+                    flags & FLAG_PSK != 0 && (frame_type == 0x02 || flags & FLAG_MUTUAL_AUTH == 0);
+                let hmac = if has_hmac && fields.remaining() >= 32 {
+                    Some(fields.array()?)
+                } else {
+                    None
+                };
+                let initial_window = if flags & FLAG_WINDOW != 0 && fields.remaining() >= 4 {
+                    Some(fields.u32()?)
                 } else {
                     None
                 };
+                let key_salt = if flags & FLAG_ENCRYPT != 0 && fields.remaining() >= 16 {
+                    Some(fields.array()?)
+                } else {
+                    None
+                };
+                let kex = if flags & FLAG_PUBKEY != 0 && fields.remaining() >= 32 {
+                    Some(fields.array()?)
+                } else {
+                    None
+                };
+                let mut compression = Vec::new();
+                if flags & FLAG_COMPRESS != 0 && fields.remaining() >= 1 {
+                    let count = (fields.u8()? as usize).min(fields.remaining());
+                    compression.extend_from_slice(fields.take(count)?);
+                }
                 let frame = HelloFrame {
                     version,
                     flags,
@@ -193,56 +506,77 @@ impl Frame {
                     keepalive_ms,
                     nonce,
                     hmac,
+                    initial_window,
+                    key_salt,
+                    kex,
+                    compression,
                 };
                 if frame_type == 0x01 {
-                    Ok(Frame::Hello(frame))
+                    Ok(Frame::Hello(Box::new(frame)))
                 } else {
-                    Ok(Frame::HelloAck(frame))
+                    Ok(Frame::HelloAck(Box::new(frame)))
                 }
             }
+            0x03 => {
+                let token = Fields::new(&payload, "join").u64()?;
+                Ok(Frame::Join { token })
+            }
+            0x04 => {
+                let proof = payload[..]
+                    .try_into()
+                    .map_err(|_| BtProxyError::Protocol("invalid auth frame".to_string()))?;
+                Ok(Frame::Auth { proof })
+            }
+            0x05 => {
+                if payload.len() != 96 {
+                    return Err(BtProxyError::Protocol("invalid auth key frame".to_string()));
+                }
+                let mut public_key = [0u8; 32];
+                let mut signature = [0u8; 64];
+                public_key.copy_from_slice(&payload[..32]);
+                signature.copy_from_slice(&payload[32..]);
+                Ok(Frame::AuthKey {
+                    public_key,
+                    signature,
+                })
+            }
             0x10 => {
-                use bytes::Buf;
-                let stream_id = cursor.get_u32();
-                let addr_type = cursor.get_u8();
-                let target = match addr_type {
+                let mut fields = Fields::new(&payload, "open");
+                let stream_id = fields.u32()?;
+                let target = match fields.u8()? {
                     1 => {
-                        let len = cursor.get_u16() as usize;
-                        let mut host_bytes = vec![0u8; len];
-                        cursor.copy_to_slice(&mut host_bytes);
-                        let port = cursor.get_u16();
-                        TargetAddr::Domain(String::from_utf8_lossy(&host_bytes).to_string(), port)
-                    }
-                    2 => {
-                        let mut ip = [0u8; 4];
-                        cursor.copy_to_slice(&mut ip);
-                        let port = cursor.get_u16();
-                        TargetAddr::IpV4(ip, port)
-                    }
-                    3 => {
-                        let mut ip = [0u8; 16];
-                        cursor.copy_to_slice(&mut ip);
-                        let port = cursor.get_u16();
-                        TargetAddr::IpV6(ip, port)
+                        let len = fields.u16()? as usize;
+                        let host = String::from_utf8_lossy(fields.take(len)?).to_string();
+                        TargetAddr::Domain(host, fields.u16()?)
                     }
+                    2 => TargetAddr::IpV4(fields.array()?, fields.u16()?),
+                    3 => TargetAddr::IpV6(fields.array()?, fields.u16()?),
                     _ => {
                         return Err(BtProxyError::Protocol("invalid addr type".to_string()));
                     }
                 };
-                Ok(Frame::Open { stream_id, target })
+                // Peers that predate priorities end the frame here.
+                let priority = if fields.remaining() > 0 {
+                    Priority::from_u8(fields.u8()?)
+                } else {
+                    Priority::Normal
+                };
+                Ok(Frame::Open {
+                    stream_id,
+                    target,
+                    priority,
+                })
             }
             0x11 => {
-                use bytes::Buf;
-                let stream_id = cursor.get_u32();
+                let stream_id = Fields::new(&payload, "open ok").u32()?;
                 Ok(Frame::OpenOk { stream_id })
             }
             0x12 => {
-                use bytes::Buf;
-                let stream_id = cursor.get_u32();
-                let code = cursor.get_u16();
-                let msg_len = cursor.get_u16() as usize;
-                let mut msg_bytes = vec![0u8; msg_len];
-                cursor.copy_to_slice(&mut msg_bytes);
-                let message = String::from_utf8_lossy(&msg_bytes).to_string();
+                let mut fields = Fields::new(&payload, "open err");
+                let stream_id = fields.u32()?;
+                let code = fields.u16()?;
+                let msg_len = fields.u16()? as usize;
+                let message = String::from_utf8_lossy(fields.take(msg_len)?).to_string();
                 Ok(Frame::OpenErr {
                     stream_id,
                     code,
@@ -250,38 +584,127 @@ impl Frame {
                 })
             }
             0x20 => {
-                use bytes::Buf;
-                let stream_id = cursor.get_u32();
-                let data_len = cursor.get_u16() as usize;
-                let mut data = vec![0u8; data_len];
-                cursor.copy_to_slice(&mut data);
+                let mut fields = Fields::new(&payload, "data");
+                let stream_id = fields.u32()?;
+                let len = fields.u16()? as usize;
+                fields.take(len)?;
                 Ok(Frame::Data {
                     stream_id,
-                    payload: Bytes::from(data),
+                    payload: payload.slice(6..6 + len),
                 })
             }
             0x21 => {
-                use bytes::Buf;
-                let stream_id = cursor.get_u32();
+                let stream_id = Fields::new(&payload, "fin").u32()?;
                 Ok(Frame::Fin { stream_id })
             }
             0x22 => {
-                use bytes::Buf;
-                let stream_id = cursor.get_u32();
-                let code = cursor.get_u16();
+                let mut fields = Fields::new(&payload, "rst");
+                let stream_id = fields.u32()?;
+                let code = fields.u16()?;
                 Ok(Frame::Rst { stream_id, code })
             }
+            0x23 => {
+                let mut fields = Fields::new(&payload, "window update");
+                let stream_id = fields.u32()?;
+                let increment = fields.u32()?;
+                Ok(Frame::WindowUpdate {
+                    stream_id,
+                    increment,
+                })
+            }
+            0x24 => {
+                let mut fields = Fields::new(&payload, "compressed data");
+                let stream_id = fields.u32()?;
+                let len = fields.u16()?;
+                Ok(Frame::CompressedData {
+                    stream_id,
+                    len,
+                    payload: payload.slice(6..),
+                })
+            }
             0x30 => {
-                use bytes::Buf;
-                let nonce = cursor.get_u64();
+                let nonce = Fields::new(&payload, "ping").u64()?;
                 Ok(Frame::Ping { nonce })
             }
             0x31 => {
-                use bytes::Buf;
-                let nonce = cursor.get_u64();
+                let nonce = Fields::new(&payload, "pong").u64()?;
                 Ok(Frame::Pong { nonce })
             }
+            0x32 => {
+                let mut fields = Fields::new(&payload, "goaway");
+                let last_stream_id = fields.u32()?;
+                let code = fields.u16()?;
+                Ok(Frame::GoAway {
+                    last_stream_id,
+                    code,
+                })
+            }
+            0x40 => {
+                let mut fields = Fields::new(&payload, "seq");
+                let seq = fields.u64()?;
+                let inner = fields.u8()?;
+                if inner == 0x40 {
+                    return Err(BtProxyError::Protocol("nested seq frame".to_string()));
+                }
+                let frame = Frame::decode(inner, payload.slice(9..))?;
+                Ok(Frame::Seq {
+                    seq,
+                    frame: Box::new(frame),
+                })
+            }
+            0x41 => {
+                let next_seq = Fields::new(&payload, "ack").u64()?;
+                Ok(Frame::Ack { next_seq })
+            }
             _ => Err(BtProxyError::Protocol("unknown frame type".to_string())),
         }
     }
 }
+
+/// Big-endian reads from a frame payload that fail, rather than panic, when
+/// the payload ends early.
+struct Fields<'a> {
+    buf: &'a [u8],
+    frame: &'static str,
+}
+
+impl<'a> Fields<'a> {
+    fn new(buf: &'a [u8], frame: &'static str) -> Self {
+        Self { buf, frame }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(BtProxyError::Protocol(format!("{} too short", self.frame)));
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        self.array().map(u16::from_be_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.array().map(u32::from_be_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.array().map(u64::from_be_bytes)
+    }
+}
@@ -1,53 +1,367 @@
-use crate::frame::{Frame, HelloFrame};
+use crate::frame::{
+    Compression, Frame, HelloFrame, FLAG_COMPRESS, FLAG_ENCRYPT, FLAG_GOAWAY, FLAG_MUTUAL_AUTH,
+    FLAG_PSK, FLAG_PUBKEY, FLAG_SPLIT_IDS, FLAG_WINDOW, GOAWAY_INCOMPATIBLE,
+};
+use crate::keys::{AuthorizedKey, AuthorizedKeys, ClientKey, PublicKey};
+use crate::session::{MuxConfig, Role};
 use common::error::{BtProxyError, Result};
 use hmac::{Hmac, Mac};
 use rand::RngCore;
 use sha2::Sha256;
+use x25519_dalek::EphemeralSecret;
+
+/// Newest protocol version this build speaks, sent in HELLO. HELLO_ACK
+/// carries the version both sides agreed on, which is bound into the PSK
+/// proofs and key signatures.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version this build still speaks.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Smallest frame size either side may advertise; OPEN and OPEN_ERR must
+/// always fit.
+pub const MIN_MAX_FRAME: u32 = 1024;
+
+/// Capabilities this build always advertises.
+const CAPABILITIES: u16 = FLAG_WINDOW | FLAG_GOAWAY | FLAG_SPLIT_IDS;
 
 const HMAC_LABEL: &[u8] = b"btproxy-v1";
+const SIGNATURE_LABEL: &[u8] = b"btproxy-v1 client key";
 
-pub fn build_hello(max_frame: u32, keepalive_ms: u32, psk: Option<&[u8]>) -> Frame {
+// PSK handshake: HELLO and HELLO_ACK each carry a fresh random challenge
+// (nonce and key salt). The server proves the PSK in HELLO_ACK over the
+// client's HELLO and its own reply; the client answers with AUTH over both
+// frames, server proof included. Each proof is bound to the sender's role
+// and the agreed protocol version, so one side's proof is never valid for the
+// other and none is valid outside its own handshake.
+//
+// Key handshake: HELLO and HELLO_ACK also carry ephemeral X25519 keys, and
+// the client signs both frames with its Ed25519 key in AUTH_KEY. The
+// signature covers the exchange, so it cannot be replayed or relayed into
+// another handshake. The server itself is only authenticated by a PSK.
+
+/// Limits, PSK and compression come from `cfg`; `flags` adds the
+/// session-level bits.
+pub fn build_hello(cfg: &MuxConfig, flags: u16, kex: Option<[u8; 32]>) -> Frame {
+    let psk = cfg.psk.as_deref();
+    let compression: Vec<u8> = cfg.compression.iter().map(|alg| *alg as u8).collect();
     let mut rng = rand::thread_rng();
-    let nonce = rng.next_u64();
-    let hmac = psk.map(|key| compute_hmac(key, nonce));
-    Frame::Hello(HelloFrame {
-        version: 1,
-        flags: if psk.is_some() { 1 } else { 0 },
-        max_frame,
+    Frame::Hello(Box::new(HelloFrame {
+        version: PROTOCOL_VERSION,
+        flags: with_compression(with_auth_flags(flags, psk, kex), &compression) | CAPABILITIES,
+        max_frame: cfg.max_frame as u32,
+        keepalive_ms: cfg.keepalive_ms,
+        nonce: rng.next_u64(),
+        hmac: None,
+        initial_window: Some(cfg.initial_window),
+        key_salt: (psk.is_some() || kex.is_some()).then(rand::random),
+        kex,
+        compression,
+    }))
+}
+
+/// Like `build_hello`; also picks the first compression algorithm in the
+/// client's list that `cfg` allows.
+pub fn build_hello_ack(
+    cfg: &MuxConfig,
+    flags: u16,
+    kex: Option<[u8; 32]>,
+    hello: &HelloFrame,
+) -> Result<Frame> {
+    let psk = cfg.psk.as_deref();
+    let compression: Vec<u8> = hello
+        .compression
+        .iter()
+        .copied()
+        .filter(|id| cfg.compression.iter().any(|alg| *alg as u8 == *id))
+        .take(1)
+        .collect();
+    let mut ack = HelloFrame {
+        version: hello.version.min(PROTOCOL_VERSION),
+        flags: with_compression(with_auth_flags(flags, psk, kex), &compression) | CAPABILITIES,
+        max_frame: cfg.max_frame as u32,
+        keepalive_ms: cfg.keepalive_ms,
+        nonce: hello.nonce,
+        hmac: None,
+        initial_window: Some(cfg.initial_window),
+        key_salt: (psk.is_some() || kex.is_some()).then(rand::random),
+        kex,
+        compression,
+    };
+    if let Some(key) = psk {
+        ack.hmac = Some(compute_proof(key, Role::Server, hello, &ack)?);
+    }
+    Ok(Frame::HelloAck(Box::new(ack)))
+}
+
+/// What both ends of a link agreed on in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u16,
+    /// Flags both sides advertised.
+    pub flags: u16,
+    pub max_frame: u32,
+    pub keepalive_ms: u32,
+    /// DATA compression, if both sides enabled a common algorithm.
+    pub compression: Option<Compression>,
+}
+
+impl Negotiated {
+    pub fn has(&self, flag: u16) -> bool {
+        self.flags & flag == flag
+    }
+}
+
+/// Settles the link parameters from our HELLO or HELLO_ACK and the peer's:
+/// the older version, the common flags, the smaller frame size and the
+/// shorter keepalive interval.
+pub fn negotiate(ours: &HelloFrame, peer: &HelloFrame) -> Result<Negotiated> {
+    let version = ours.version.min(peer.version);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(BtProxyError::Unsupported(format!(
+            "peer speaks protocol version {}, this build needs {} to {}",
+            peer.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        )));
+    }
+    if peer.max_frame < MIN_MAX_FRAME {
+        return Err(BtProxyError::Unsupported(format!(
+            "peer max frame {} is below {}",
+            peer.max_frame, MIN_MAX_FRAME
+        )));
+    }
+    // Zero means the peer has no preference.
+    let keepalive_ms = match peer.keepalive_ms {
+        0 => ours.keepalive_ms,
+        peer => peer.min(ours.keepalive_ms),
+    };
+    let flags = ours.flags & peer.flags;
+    // HELLO_ACK lists only the server's pick, so both sides find the same.
+    let compression = ours
+        .compression
+        .iter()
+        .find(|id| peer.compression.contains(id))
+        .and_then(|id| Compression::from_u8(*id))
+        .filter(|_| flags & FLAG_COMPRESS != 0);
+    Ok(Negotiated {
+        version,
+        flags,
+        max_frame: ours.max_frame.min(peer.max_frame),
         keepalive_ms,
-        nonce,
-        hmac,
+        compression,
     })
 }
 
-pub fn build_hello_ack(max_frame: u32, keepalive_ms: u32, psk: Option<&[u8]>, nonce: u64) -> Frame {
-    let hmac = psk.map(|key| compute_hmac(key, nonce));
-    Frame::HelloAck(HelloFrame {
-        version: 1,
-        flags: if psk.is_some() { 1 } else { 0 },
-        max_frame,
-        keepalive_ms,
-        nonce,
-        hmac,
+/// Client side: the error for a GOAWAY the server sent instead of
+/// HELLO_ACK.
+pub fn refusal(code: u16) -> BtProxyError {
+    if code == GOAWAY_INCOMPATIBLE {
+        BtProxyError::Unsupported(format!(
+            "server refused an incompatible client (protocol version {} to {})",
+            MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ))
+    } else {
+        BtProxyError::Protocol(format!("server refused the handshake (code {})", code))
+    }
+}
+
+/// Checks that the peer's HELLO or HELLO_ACK asks for the same kind of
+/// handshake we do. `pubkey` is whether we use client keys.
+pub fn check_peer_flags(psk: Option<&[u8]>, pubkey: bool, peer: &HelloFrame) -> Result<()> {
+    let peer_psk = peer.flags & FLAG_PSK != 0;
+    let peer_pubkey = peer.flags & FLAG_PUBKEY != 0;
+    let reason = if psk.is_none() && peer_psk {
+        "peer requires a PSK, none is configured"
+    } else if psk.is_some() && !peer_psk {
+        "peer did not offer PSK authentication"
+    } else if psk.is_some() && peer.flags & FLAG_MUTUAL_AUTH == 0 {
+        "peer uses the legacy PSK handshake"
+    } else if !pubkey && peer_pubkey {
+        "peer uses client keys, none are configured"
+    } else if pubkey && !peer_pubkey {
+        "peer does not use client keys"
+    } else if (psk.is_some() || pubkey)
+        && (peer.flags & FLAG_ENCRYPT == 0 || peer.key_salt.is_none())
+    {
+        "peer does not support encryption"
+    } else if pubkey && peer.kex.is_none() {
+        "peer sent no key exchange"
+    } else {
+        return Ok(());
+    };
+    Err(BtProxyError::Auth(reason.to_string()))
+}
+
+/// Client side: checks the server's proof in HELLO_ACK.
+pub fn verify_hello_ack(psk: Option<&[u8]>, hello: &HelloFrame, ack: &HelloFrame) -> Result<()> {
+    let Some(key) = psk else {
+        return Ok(());
+    };
+    if ack.nonce != hello.nonce {
+        return Err(BtProxyError::Auth(
+            "hello ack answers a different hello".to_string(),
+        ));
+    }
+    let proof = ack
+        .hmac
+        .ok_or_else(|| BtProxyError::Auth("hello ack carries no proof".to_string()))?;
+    let unsigned = HelloFrame {
+        hmac: None,
+        ..ack.clone()
+    };
+    if !verify_proof(key, Role::Server, hello, &unsigned, &proof)? {
+        return Err(BtProxyError::Auth(
+            "server proof mismatch (wrong PSK?)".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Client side: answers the server's challenge.
+pub fn build_auth(psk: &[u8], hello: &HelloFrame, ack: &HelloFrame) -> Result<Frame> {
+    let proof = compute_proof(psk, Role::Client, hello, ack)?;
+    Ok(Frame::Auth { proof })
+}
+
+/// Server side: checks the client's AUTH against this handshake.
+pub fn verify_auth(
+    psk: &[u8],
+    hello: &HelloFrame,
+    ack: &HelloFrame,
+    proof: &[u8; 32],
+) -> Result<()> {
+    if !verify_proof(psk, Role::Client, hello, ack, proof)? {
+        return Err(BtProxyError::Auth(
+            "client proof mismatch (wrong PSK?)".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Client side: signs the handshake with the client key.
+pub fn build_auth_key(key: &ClientKey, hello: &HelloFrame, ack: &HelloFrame) -> Result<Frame> {
+    Ok(Frame::AuthKey {
+        public_key: *key.public_key().as_bytes(),
+        signature: key.sign(&signed_transcript(hello, ack)?),
     })
 }
 
-pub fn verify_hmac(psk: Option<&[u8]>, frame: &HelloFrame) -> Result<()> {
-    if let Some(key) = psk {
-        let expected = compute_hmac(key, frame.nonce);
-        if frame.hmac != Some(expected) {
-            return Err(BtProxyError::Auth("invalid hmac".to_string()));
+/// Server side: checks the client's AUTH_KEY and returns the matching
+/// entry of `authorized_keys`.
+pub fn verify_auth_key(
+    keys: &AuthorizedKeys,
+    hello: &HelloFrame,
+    ack: &HelloFrame,
+    public_key: &[u8; 32],
+    signature: &[u8; 64],
+) -> Result<AuthorizedKey> {
+    let key = PublicKey::from_bytes(*public_key)?;
+    if !key.verify(&signed_transcript(hello, ack)?, signature) {
+        return Err(BtProxyError::Auth(format!(
+            "signature by key {} does not verify",
+            key
+        )));
+    }
+    keys.get(&key)
+        .cloned()
+        .ok_or_else(|| BtProxyError::Auth(format!("key {} is not authorized", key)))
+}
+
+/// Ephemeral X25519 key of one handshake.
+pub(crate) struct KeyExchange {
+    secret: EphemeralSecret,
+    public: [u8; 32],
+}
+
+impl KeyExchange {
+    pub(crate) fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let public = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        Self { secret, public }
+    }
+
+    pub(crate) fn public(&self) -> [u8; 32] {
+        self.public
+    }
+
+    /// Refuses peer keys that force a known result.
+    pub(crate) fn agree(self, peer: [u8; 32]) -> Result<[u8; 32]> {
+        let shared = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(peer));
+        if !shared.was_contributory() {
+            return Err(BtProxyError::Auth(
+                "peer sent a weak key exchange".to_string(),
+            ));
         }
+        Ok(shared.to_bytes())
     }
-    Ok(())
 }
 
-fn compute_hmac(key: &[u8], nonce: u64) -> [u8; 32] {
+/// A PSK turns on encryption and the mutual handshake; a key exchange turns
+/// on encryption and client keys.
+fn with_auth_flags(flags: u16, psk: Option<&[u8]>, kex: Option<[u8; 32]>) -> u16 {
+    let mut flags = flags & !(FLAG_PSK | FLAG_ENCRYPT | FLAG_MUTUAL_AUTH | FLAG_PUBKEY);
+    if psk.is_some() {
+        flags |= FLAG_PSK | FLAG_ENCRYPT | FLAG_MUTUAL_AUTH;
+    }
+    if kex.is_some() {
+        flags |= FLAG_PUBKEY | FLAG_ENCRYPT;
+    }
+    flags
+}
+
+fn with_compression(flags: u16, compression: &[u8]) -> u16 {
+    if compression.is_empty() {
+        flags & !FLAG_COMPRESS
+    } else {
+        flags | FLAG_COMPRESS
+    }
+}
+
+fn signed_transcript(hello: &HelloFrame, ack: &HelloFrame) -> Result<Vec<u8>> {
+    let mut message = SIGNATURE_LABEL.to_vec();
+    message.extend_from_slice(&ack.version.to_be_bytes());
+    for frame in transcript(hello, ack) {
+        message.extend_from_slice(&frame.encode()?);
+    }
+    Ok(message)
+}
+
+fn transcript(hello: &HelloFrame, ack: &HelloFrame) -> [Frame; 2] {
+    [
+        Frame::Hello(Box::new(hello.clone())),
+        Frame::HelloAck(Box::new(ack.clone())),
+    ]
+}
+
+fn proof_mac(key: &[u8], role: Role, hello: &HelloFrame, ack: &HelloFrame) -> Result<Hmac<Sha256>> {
     let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac key");
-    mac.update(&nonce.to_be_bytes());
     mac.update(HMAC_LABEL);
-    let result = mac.finalize().into_bytes();
+    mac.update(match role {
+        Role::Client => b"client",
+        Role::Server => b"server",
+    });
+    mac.update(&ack.version.to_be_bytes());
+    // Encoded frames are length-prefixed, so the concatenation is unambiguous.
+    for frame in transcript(hello, ack) {
+        mac.update(&frame.encode()?);
+    }
+    Ok(mac)
+}
+
+fn compute_proof(key: &[u8], role: Role, hello: &HelloFrame, ack: &HelloFrame) -> Result<[u8; 32]> {
+    let result = proof_mac(key, role, hello, ack)?.finalize().into_bytes();
     let mut out = [0u8; 32];
     out.copy_from_slice(&result);
-    out
+    Ok(out)
+}
+
+fn verify_proof(
+    key: &[u8],
+    role: Role,
+    hello: &HelloFrame,
+    ack: &HelloFrame,
+    proof: &[u8; 32],
+) -> Result<bool> {
+    Ok(proof_mac(key, role, hello, ack)?
+        .verify_slice(proof)
+        .is_ok())
 }
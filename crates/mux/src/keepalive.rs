@@ -1,14 +1,148 @@
 use crate::frame::Frame;
-use tokio::sync::mpsc;
-use tokio::time::{interval, Duration};
+use crate::sched::Scheduler;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::time::{interval, sleep_until, Duration, Instant};
 
-pub async fn keepalive_task(tx: mpsc::Sender<Frame>, interval_ms: u32) {
+/// PINGs remembered for matching PONGs. With `max_missed` off, a silent peer
+/// would otherwise grow the list by one entry every interval.
+const MAX_OUTSTANDING: usize = 64;
+
+/// PING/PONG bookkeeping of one session.
+pub(crate) struct Keepalive {
+    state: Mutex<State>,
+}
+
+struct State {
+    /// PINGs still waiting for their PONG, oldest first.
+    outstanding: VecDeque<(u64, Instant)>,
+    /// PINGs dropped from `outstanding` to keep it bounded; still missed.
+    forgotten: u32,
+    srtt: Option<Duration>,
+    last_rx: Instant,
+}
+
+impl Keepalive {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                outstanding: VecDeque::new(),
+                forgotten: 0,
+                srtt: None,
+                last_rx: Instant::now(),
+            }),
+        }
+    }
+
+    /// Records that the peer sent something.
+    pub(crate) fn touch(&self) {
+        self.state.lock().unwrap().last_rx = Instant::now();
+    }
+
+    /// Matches a PONG to its PING and folds the round trip into the smoothed
+    /// RTT. PINGs sent before it are given up on.
+    pub(crate) fn pong(&self, nonce: u64) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let index = state.outstanding.iter().position(|(n, _)| *n == nonce)?;
+        let (_, sent) = state.outstanding.drain(..=index).next_back()?;
+        state.forgotten = 0;
+        let sample = sent.elapsed();
+        state.srtt = Some(match state.srtt {
+            Some(srtt) => (srtt * 7 + sample) / 8,
+            None => sample,
+        });
+        Some(sample)
+    }
+
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        self.state.lock().unwrap().srtt
+    }
+
+    /// PINGs sent since the last PONG.
+    pub(crate) fn missed(&self) -> u32 {
+        let state = self.state.lock().unwrap();
+        (state.outstanding.len() as u32).saturating_add(state.forgotten)
+    }
+
+    fn ping(&self, nonce: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.outstanding.len() == MAX_OUTSTANDING {
+            state.outstanding.pop_front();
+            state.forgotten = state.forgotten.saturating_add(1);
+        }
+        state.outstanding.push_back((nonce, Instant::now()));
+    }
+
+    fn last_rx(&self) -> Instant {
+        self.state.lock().unwrap().last_rx
+    }
+}
+
+/// Sends a PING every `interval_ms`. Returns why the peer looks dead once
+/// `max_missed` PINGs in a row went unanswered or nothing arrived for
+/// `idle_timeout_ms`; either limit is off when zero. Returns `None` if the
+/// session went away first.
+pub(crate) async fn keepalive_task(
+    tx: Arc<Scheduler>,
+    keepalive: Arc<Keepalive>,
+    interval_ms: u32,
+    max_missed: u32,
+    idle_timeout_ms: u32,
+) -> Option<String> {
     let mut ticker = interval(Duration::from_millis(interval_ms as u64));
+    let idle_timeout = Duration::from_millis(idle_timeout_ms as u64);
     loop {
-        ticker.tick().await;
-        let nonce = rand::random::<u64>();
-        if tx.send(Frame::Ping { nonce }).await.is_err() {
-            break;
+        let idle_deadline = keepalive.last_rx() + idle_timeout;
+        tokio::select! {
+            _ = ticker.tick() => {
+                let missed = keepalive.missed();
+                if max_missed > 0 && missed >= max_missed {
+                    return Some(format!("{} pings unanswered", missed));
+                }
+                let nonce = rand::random::<u64>();
+                keepalive.ping(nonce);
+                if tx.send_control(Frame::Ping { nonce }).await.is_err() {
+                    return None;
+                }
+            }
+            _ = sleep_until(idle_deadline), if idle_timeout_ms > 0 => {
+                if keepalive.last_rx().elapsed() >= idle_timeout {
+                    return Some(format!("nothing received for {:?}", idle_timeout));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unanswered_pings_are_bounded_but_still_counted() {
+        let keepalive = Keepalive::new();
+        for nonce in 0..1000 {
+            keepalive.ping(nonce);
+        }
+        assert_eq!(keepalive.missed(), 1000);
+        let state = keepalive.state.lock().unwrap();
+        assert_eq!(state.outstanding.len(), MAX_OUTSTANDING);
+        assert_eq!(
+            state.outstanding.front().unwrap().0,
+            1000 - MAX_OUTSTANDING as u64
+        );
+    }
+
+    #[test]
+    fn pong_for_a_recent_ping_clears_the_count() {
+        let keepalive = Keepalive::new();
+        for nonce in 0..1000 {
+            keepalive.ping(nonce);
         }
+        // Too old to be remembered.
+        assert_eq!(keepalive.pong(3), None);
+        assert!(keepalive.pong(999).is_some());
+        assert_eq!(keepalive.missed(), 0);
+        assert!(keepalive.rtt().is_some());
     }
 }
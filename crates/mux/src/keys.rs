@@ -0,0 +1,414 @@
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use common::error::{BtProxyError, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Key type tag on public key lines.
+pub const KEY_TYPE: &str = "btproxy-ed25519";
+const SECRET_KEY_TYPE: &str = "btproxy-ed25519-secret";
+
+/// An Ed25519 public key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Result<Self> {
+        VerifyingKey::from_bytes(&bytes)
+            .map(|_| Self(bytes))
+            .map_err(|_| BtProxyError::Auth("invalid public key".to_string()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// `SHA256:` followed by the unpadded base64 of the key's hash, as
+    /// OpenSSH prints it.
+    pub fn fingerprint(&self) -> String {
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(self.0)))
+    }
+
+    /// The line to put into the server's `authorized_keys`.
+    pub fn to_line(&self, comment: &str) -> String {
+        let line = format!("{} {}", KEY_TYPE, STANDARD.encode(self.0));
+        if comment.is_empty() {
+            line
+        } else {
+            format!("{} {}", line, comment)
+        }
+    }
+
+    pub(crate) fn verify(&self, message: &[u8], signature: &[u8; 64]) -> bool {
+        VerifyingKey::from_bytes(&self.0)
+            .and_then(|key| key.verify_strict(message, &Signature::from_bytes(signature)))
+            .is_ok()
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self.fingerprint())
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.fingerprint())
+    }
+}
+
+/// A client's private key.
+#[derive(Clone)]
+pub struct ClientKey {
+    signing: SigningKey,
+    pub comment: String,
+}
+
+impl ClientKey {
+    pub fn generate(comment: &str) -> Self {
+        Self {
+            signing: SigningKey::generate(&mut rand::rngs::OsRng),
+            comment: comment.to_string(),
+        }
+    }
+
+    /// Reads a key written by `save`. On unix, refuses a file that group or
+    /// others may access, as ssh does.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(path)
+                .map_err(|err| {
+                    BtProxyError::Config(format!("cannot read key {}: {}", path.display(), err))
+                })?
+                .permissions()
+                .mode();
+            if mode & 0o077 != 0 {
+                return Err(BtProxyError::Config(format!(
+                    "key {} is accessible by others (mode {:o}), chmod it to 600",
+                    path.display(),
+                    mode & 0o777
+                )));
+            }
+        }
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            BtProxyError::Config(format!("cannot read key {}: {}", path.display(), err))
+        })?;
+        Self::parse(&text).map_err(|err| {
+            BtProxyError::Config(format!("{}: {}", path.display(), config_message(err)))
+        })
+    }
+
+    /// Parses `btproxy-ed25519-secret BASE64 [comment]`; `#` lines are
+    /// skipped.
+    pub fn parse(text: &str) -> Result<Self> {
+        let line = text
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or_else(|| BtProxyError::Config("empty key file".to_string()))?;
+        let mut parts = line.splitn(3, char::is_whitespace);
+        if parts.next() != Some(SECRET_KEY_TYPE) {
+            return Err(BtProxyError::Config(format!(
+                "not a {} key",
+                SECRET_KEY_TYPE
+            )));
+        }
+        let seed = decode_key(parts.next().unwrap_or_default())?;
+        Ok(Self {
+            signing: SigningKey::from_bytes(&seed),
+            comment: parts.next().unwrap_or_default().trim().to_string(),
+        })
+    }
+
+    /// Writes the private key to `path` (mode 0600 on unix) and the public
+    /// key to `path.pub`. Refuses to replace either file, and leaves
+    /// neither behind when it fails. Returns the path of the public key.
+    pub fn save(&self, path: &Path) -> Result<PathBuf> {
+        let mut public_path = path.as_os_str().to_owned();
+        public_path.push(".pub");
+        let public_path = PathBuf::from(public_path);
+        let secret = format!(
+            "# btproxy client key, keep it private\n{} {} {}\n",
+            SECRET_KEY_TYPE,
+            STANDARD.encode(self.signing.to_bytes()),
+            self.comment
+        );
+        write_new(path, secret.as_bytes(), 0o600)?;
+        let public = format!("{}\n", self.public_key().to_line(&self.comment));
+        if let Err(err) = write_new(&public_path, public.as_bytes(), 0o644) {
+            // Otherwise the next attempt would trip over the secret half.
+            let _ = std::fs::remove_file(path);
+            return Err(err);
+        }
+        Ok(public_path)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.signing.verifying_key().to_bytes())
+    }
+
+    pub(crate) fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.signing.sign(message).to_bytes()
+    }
+}
+
+impl fmt::Debug for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientKey")
+            .field("public", &self.public_key())
+            .field("comment", &self.comment)
+            .finish()
+    }
+}
+
+/// Per-key restrictions from the options column of `authorized_keys`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyOptions {
+    /// `name="..."`: how the key shows up in logs instead of its comment.
+    pub name: Option<String>,
+    /// `from="pattern,..."`: peer addresses the key may connect from, as
+    /// globs over the address the server logs. Empty allows any.
+    pub from: Vec<String>,
+    /// `no-bond`: the key may not add links to a bonded session.
+    pub no_bond: bool,
+}
+
+/// One entry of `authorized_keys`.
+#[derive(Debug, Clone)]
+pub struct AuthorizedKey {
+    pub key: PublicKey,
+    pub comment: String,
+    pub options: KeyOptions,
+}
+
+impl AuthorizedKey {
+    /// The `name` option, else the comment, else the fingerprint.
+    pub fn label(&self) -> String {
+        match (&self.options.name, self.comment.is_empty()) {
+            (Some(name), _) => name.clone(),
+            (None, false) => self.comment.clone(),
+            (None, true) => self.key.fingerprint(),
+        }
+    }
+
+    pub fn allows_from(&self, peer: &str) -> bool {
+        self.options.from.is_empty()
+            || self
+                .options
+                .from
+                .iter()
+                .any(|pattern| glob_match(pattern, peer))
+    }
+}
+
+impl fmt::Display for AuthorizedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.label(), self.key.fingerprint())
+    }
+}
+
+/// The client keys a server accepts, one per line:
+/// `[options] btproxy-ed25519 BASE64 [comment]`. Blank lines and lines
+/// starting with `#` are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizedKeys {
+    keys: Vec<AuthorizedKey>,
+}
+
+impl AuthorizedKeys {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            BtProxyError::Config(format!("cannot read {}: {}", path.display(), err))
+        })?;
+        Self::parse(&text).map_err(|err| {
+            BtProxyError::Config(format!("{}: {}", path.display(), config_message(err)))
+        })
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut keys = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key = parse_authorized(line).map_err(|err| {
+                BtProxyError::Config(format!("line {}: {}", index + 1, config_message(err)))
+            })?;
+            keys.push(key);
+        }
+        Ok(Self { keys })
+    }
+
+    pub fn get(&self, key: &PublicKey) -> Option<&AuthorizedKey> {
+        self.keys.iter().find(|entry| entry.key == *key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+fn parse_authorized(line: &str) -> Result<AuthorizedKey> {
+    // Options come first if the key type follows them or they look like
+    // options; otherwise the first word is reported as the key type.
+    let (options, rest) = split_options(line);
+    let rest = rest.trim_start();
+    let (options, rest) = if !line.starts_with(KEY_TYPE)
+        && (rest.starts_with(KEY_TYPE) || options.contains(['=', '"']))
+    {
+        (parse_options(options)?, rest)
+    } else {
+        (KeyOptions::default(), line)
+    };
+    let mut parts = rest.splitn(3, char::is_whitespace);
+    match parts.next() {
+        Some(KEY_TYPE) => {}
+        Some(other) => {
+            return Err(BtProxyError::Config(format!(
+                "unknown key type {:?}",
+                other
+            )))
+        }
+        None => return Err(BtProxyError::Config("missing key".to_string())),
+    }
+    let key = PublicKey::from_bytes(decode_key(parts.next().unwrap_or_default())?)?;
+    Ok(AuthorizedKey {
+        key,
+        comment: parts.next().unwrap_or_default().trim().to_string(),
+        options,
+    })
+}
+
+/// Splits off the options column: everything up to the first whitespace
+/// outside double quotes.
+fn split_options(line: &str) -> (&str, &str) {
+    let mut quoted = false;
+    for (index, ch) in line.char_indices() {
+        match ch {
+            '"' => quoted = !quoted,
+            ch if ch.is_whitespace() && !quoted => return line.split_at(index),
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+fn parse_options(text: &str) -> Result<KeyOptions> {
+    let mut options = KeyOptions::default();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (option, tail) = next_option(rest)?;
+        rest = tail;
+        match option.split_once('=') {
+            None if option == "no-bond" => options.no_bond = true,
+            Some(("name", value)) => options.name = Some(unquote(value)?.to_string()),
+            Some(("from", value)) => options.from.extend(
+                unquote(value)?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|pattern| !pattern.is_empty())
+                    .map(str::to_string),
+            ),
+            _ => return Err(BtProxyError::Config(format!("unknown option {:?}", option))),
+        }
+    }
+    Ok(options)
+}
+
+/// Takes one comma-separated option, keeping commas inside quotes.
+fn next_option(text: &str) -> Result<(&str, &str)> {
+    let mut quoted = false;
+    for (index, ch) in text.char_indices() {
+        match ch {
+            '"' => quoted = !quoted,
+            ',' if !quoted => return Ok((&text[..index], &text[index + 1..])),
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(BtProxyError::Config("unterminated quote".to_string()));
+    }
+    Ok((text, ""))
+}
+
+fn unquote(value: &str) -> Result<&str> {
+    match value.strip_prefix('"') {
+        Some(inner) => inner
+            .strip_suffix('"')
+            .ok_or_else(|| BtProxyError::Config("unterminated quote".to_string())),
+        None => Ok(value),
+    }
+}
+
+fn decode_key(text: &str) -> Result<[u8; 32]> {
+    STANDARD
+        .decode(text.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| BtProxyError::Config("malformed key".to_string()))
+}
+
+fn config_message(err: BtProxyError) -> String {
+    match err {
+        BtProxyError::Config(msg) | BtProxyError::Auth(msg) => msg,
+        err => err.to_string(),
+    }
+}
+
+/// `*` matches any run of characters, `?` any single one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&ch| ch == '*')
+}
+
+fn write_new(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(path).map_err(|err| {
+        BtProxyError::Config(format!("cannot create {}: {}", path.display(), err))
+    })?;
+    if let Err(err) = file.write_all(contents) {
+        drop(file);
+        let _ = std::fs::remove_file(path);
+        return Err(err.into());
+    }
+    Ok(())
+}
@@ -1,7 +1,12 @@
 pub mod codec;
+mod compress;
+mod crypto;
+mod flow;
 pub mod frame;
 pub mod handshake;
 pub mod keepalive;
+pub mod keys;
+mod sched;
 pub mod session;
 pub mod stream;
 
@@ -0,0 +1,480 @@
+use crate::frame::{Frame, Priority};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::Notify;
+
+/// Control frames queued before senders have to wait.
+const CONTROL_QUEUE: usize = 64;
+/// DATA/FIN frames queued per stream before its sender has to wait.
+const STREAM_QUEUE: usize = 4;
+/// Frames each priority level, lowest first, may send per round while
+/// higher levels have data too.
+const WEIGHTS: [u32; Priority::LEVELS] = [1, 4, 16];
+
+/// Decides which frame the writer sends next.
+///
+/// Control frames (OPEN, RST, PING, PONG, WINDOW_UPDATE, ...) always go
+/// first. DATA and FIN wait in a queue per stream; the writer takes one frame
+/// at a time from each stream of a priority level, round-robin. Higher levels
+/// go first but only up to their weight per round, so bulk streams slow down
+/// rather than stall.
+///
+/// Only registered streams may queue DATA and FIN. An RST unregisters its
+/// stream, so a send that raced with it cannot queue data behind the RST.
+pub(crate) struct Scheduler {
+    queues: Mutex<Queues>,
+    /// Wakes the writer.
+    ready: Notify,
+    /// Wakes senders waiting for queue space.
+    space: Notify,
+}
+
+#[derive(Default)]
+struct Queues {
+    control: VecDeque<Frame>,
+    streams: HashMap<u32, VecDeque<Frame>>,
+    /// Streams that may still queue DATA or FIN.
+    writers: HashSet<u32>,
+    /// Streams with queued frames, in round-robin order, by priority.
+    ready: [VecDeque<u32>; Priority::LEVELS],
+    /// Frames each level may still send this round.
+    credits: [u32; Priority::LEVELS],
+    /// Writers polling for queue space.
+    space_wakers: Vec<Waker>,
+    closed: bool,
+}
+
+impl Scheduler {
+    pub(crate) fn new() -> Self {
+        Self {
+            queues: Mutex::new(Queues::default()),
+            ready: Notify::new(),
+            space: Notify::new(),
+        }
+    }
+
+    /// Queues a frame that does not belong to a stream's data, or that has
+    /// to overtake it. An RST discards whatever its stream still had queued.
+    pub(crate) async fn send_control(&self, frame: Frame) -> Result<(), SendError<Frame>> {
+        self.push(frame, |queues, frame| {
+            if queues.control.len() >= CONTROL_QUEUE {
+                return Some(frame);
+            }
+            if let Frame::Rst { stream_id, .. } = frame {
+                queues.discard(stream_id);
+            }
+            queues.control.push_back(frame);
+            None
+        })
+        .await
+    }
+
+    /// Queues a control frame without waiting for space, for callers that
+    /// cannot wait such as a stream being polled for reading.
+    pub(crate) fn send_control_now(&self, frame: Frame) -> Result<(), SendError<Frame>> {
+        let mut queues = self.queues.lock().unwrap();
+        if queues.closed {
+            return Err(SendError(frame));
+        }
+        if let Frame::Rst { stream_id, .. } = frame {
+            queues.discard(stream_id);
+        }
+        queues.control.push_back(frame);
+        drop(queues);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// Lets a new stream queue DATA and FIN.
+    pub(crate) fn register(&self, stream_id: u32) {
+        self.queues.lock().unwrap().writers.insert(stream_id);
+    }
+
+    /// Forgets a stream whose write half is gone. What it queued still goes
+    /// out.
+    pub(crate) fn unregister(&self, stream_id: u32) {
+        self.queues.lock().unwrap().writers.remove(&stream_id);
+    }
+
+    /// Queues DATA or FIN behind the stream's earlier frames. Frames of a
+    /// stream that was reset are dropped.
+    pub(crate) async fn send_stream(
+        &self,
+        stream_id: u32,
+        priority: Priority,
+        frame: Frame,
+    ) -> Result<(), SendError<Frame>> {
+        self.push(frame, |queues, frame| {
+            if !queues.writers.contains(&stream_id) {
+                return None;
+            }
+            let queue = queues.streams.entry(stream_id).or_default();
+            if queue.len() >= STREAM_QUEUE {
+                return Some(frame);
+            }
+            if queue.is_empty() {
+                queues.ready[priority as usize].push_back(stream_id);
+            }
+            queue.push_back(frame);
+            None
+        })
+        .await
+    }
+
+    /// Queues the frame `make` builds once the stream's queue has room, so
+    /// a caller that gets `Pending` has handed over nothing. Frames of a
+    /// stream that was reset are dropped, as in `send_stream`.
+    pub(crate) fn poll_send_stream(
+        &self,
+        cx: &mut Context<'_>,
+        stream_id: u32,
+        priority: Priority,
+        make: impl FnOnce() -> Frame,
+    ) -> Poll<Result<(), SendError<()>>> {
+        let mut queues = self.queues.lock().unwrap();
+        if queues.closed {
+            return Poll::Ready(Err(SendError(())));
+        }
+        if !queues.writers.contains(&stream_id) {
+            return Poll::Ready(Ok(()));
+        }
+        let queued = queues.streams.get(&stream_id).map_or(0, VecDeque::len);
+        if queued >= STREAM_QUEUE {
+            if !queues.space_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                queues.space_wakers.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+        if queued == 0 {
+            queues.ready[priority as usize].push_back(stream_id);
+        }
+        queues
+            .streams
+            .entry(stream_id)
+            .or_default()
+            .push_back(make());
+        drop(queues);
+        self.ready.notify_one();
+        Poll::Ready(Ok(()))
+    }
+
+    /// Runs `try_push` until it accepts the frame, waiting for space in
+    /// between. `try_push` hands the frame back if its queue is full.
+    async fn push(
+        &self,
+        mut frame: Frame,
+        try_push: impl Fn(&mut Queues, Frame) -> Option<Frame>,
+    ) -> Result<(), SendError<Frame>> {
+        loop {
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+            {
+                let mut queues = self.queues.lock().unwrap();
+                if queues.closed {
+                    return Err(SendError(frame));
+                }
+                match try_push(&mut queues, frame) {
+                    None => {
+                        drop(queues);
+                        self.ready.notify_one();
+                        return Ok(());
+                    }
+                    Some(rejected) => frame = rejected,
+                }
+            }
+            space.await;
+        }
+    }
+
+    /// Drops the frames a stream still has queued, and any it tries to
+    /// queue later.
+    pub(crate) fn discard(&self, stream_id: u32) {
+        self.queues.lock().unwrap().discard(stream_id);
+        self.wake_space();
+    }
+
+    /// Waits for the next frame to send. Returns `None` once the scheduler
+    /// is closed.
+    pub(crate) async fn next(&self) -> Option<Frame> {
+        loop {
+            if self.queues.lock().unwrap().closed {
+                return None;
+            }
+            if let Some(frame) = self.try_next() {
+                return Some(frame);
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// Takes the next frame if one is queued already.
+    pub(crate) fn try_next(&self) -> Option<Frame> {
+        let frame = {
+            let mut queues = self.queues.lock().unwrap();
+            if queues.closed {
+                return None;
+            }
+            queues.pop()
+        };
+        if frame.is_some() {
+            self.wake_space();
+        }
+        frame
+    }
+
+    /// Resolves once the writer has taken every queued frame.
+    pub(crate) async fn flushed(&self) {
+        loop {
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+            {
+                let queues = self.queues.lock().unwrap();
+                if queues.closed || (queues.control.is_empty() && queues.streams.is_empty()) {
+                    return;
+                }
+            }
+            space.await;
+        }
+    }
+
+    /// Drops everything queued and fails current and future sends.
+    pub(crate) fn close(&self) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.closed = true;
+        queues.control.clear();
+        queues.streams.clear();
+        queues.writers.clear();
+        drop(queues);
+        self.wake_space();
+        self.ready.notify_one();
+    }
+
+    /// Wakes senders waiting for queue space, async or polled.
+    fn wake_space(&self) {
+        self.space.notify_waiters();
+        let wakers = std::mem::take(&mut self.queues.lock().unwrap().space_wakers);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Queues {
+    fn pop(&mut self) -> Option<Frame> {
+        if let Some(frame) = self.control.pop_front() {
+            return Some(frame);
+        }
+        for _ in 0..2 {
+            for level in (0..Priority::LEVELS).rev() {
+                if self.credits[level] == 0 {
+                    continue;
+                }
+                if let Some(frame) = self.pop_level(level) {
+                    self.credits[level] -= 1;
+                    return Some(frame);
+                }
+            }
+            // Every level with data has used up its share; start a round.
+            self.credits = WEIGHTS;
+        }
+        None
+    }
+
+    fn pop_level(&mut self, level: usize) -> Option<Frame> {
+        let ready = &mut self.ready[level];
+        while let Some(stream_id) = ready.pop_front() {
+            let Some(queue) = self.streams.get_mut(&stream_id) else {
+                continue;
+            };
+            let Some(frame) = queue.pop_front() else {
+                self.streams.remove(&stream_id);
+                continue;
+            };
+            if queue.is_empty() {
+                self.streams.remove(&stream_id);
+            } else {
+                ready.push_back(stream_id);
+            }
+            return Some(frame);
+        }
+        None
+    }
+
+    fn discard(&mut self, stream_id: u32) {
+        self.writers.remove(&stream_id);
+        if self.streams.remove(&stream_id).is_some() {
+            for ready in &mut self.ready {
+                ready.retain(|id| *id != stream_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn data(stream_id: u32) -> Frame {
+        Frame::Data {
+            stream_id,
+            payload: Bytes::from_static(b"x"),
+        }
+    }
+
+    fn stream_of(frame: &Frame) -> u32 {
+        match frame {
+            Frame::Data { stream_id, .. } => *stream_id,
+            frame => panic!("expected data, got {:?}", frame),
+        }
+    }
+
+    #[tokio::test]
+    async fn control_frames_overtake_queued_data() {
+        let sched = Scheduler::new();
+        sched.register(1);
+        sched
+            .send_stream(1, Priority::Interactive, data(1))
+            .await
+            .unwrap();
+        sched
+            .send_stream(1, Priority::Interactive, data(1))
+            .await
+            .unwrap();
+        sched.send_control(Frame::Ping { nonce: 7 }).await.unwrap();
+        sched.send_control_now(Frame::Pong { nonce: 8 }).unwrap();
+        assert!(matches!(sched.next().await, Some(Frame::Ping { nonce: 7 })));
+        assert!(matches!(sched.next().await, Some(Frame::Pong { nonce: 8 })));
+        assert_eq!(stream_of(&sched.next().await.unwrap()), 1);
+        assert_eq!(stream_of(&sched.next().await.unwrap()), 1);
+        assert!(sched.try_next().is_none());
+    }
+
+    #[tokio::test]
+    async fn levels_share_each_round_by_weight() {
+        let sched = Scheduler::new();
+        // Streams 0.. are bulk, 100.. normal, 200.. interactive; each
+        // queues as much as it may.
+        for (base, priority) in [
+            (0, Priority::Bulk),
+            (100, Priority::Normal),
+            (200, Priority::Interactive),
+        ] {
+            for stream_id in base..base + 20 {
+                sched.register(stream_id);
+                for _ in 0..STREAM_QUEUE {
+                    sched
+                        .send_stream(stream_id, priority, data(stream_id))
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+        let round: usize = WEIGHTS.iter().sum::<u32>() as usize;
+        for _ in 0..3 {
+            let mut sent = [0u32; Priority::LEVELS];
+            for _ in 0..round {
+                sent[stream_of(&sched.try_next().unwrap()) as usize / 100] += 1;
+            }
+            assert_eq!(sent, WEIGHTS);
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_of_a_level_take_turns() {
+        let sched = Scheduler::new();
+        for stream_id in [1, 2] {
+            sched.register(stream_id);
+            for _ in 0..3 {
+                sched
+                    .send_stream(stream_id, Priority::Normal, data(stream_id))
+                    .await
+                    .unwrap();
+            }
+        }
+        let order: Vec<u32> = (0..6)
+            .map(|_| stream_of(&sched.try_next().unwrap()))
+            .collect();
+        assert_eq!(order, [1, 2, 1, 2, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn rst_discards_queued_data_and_refuses_more() {
+        let sched = Scheduler::new();
+        for stream_id in [1, 3] {
+            sched.register(stream_id);
+            sched
+                .send_stream(stream_id, Priority::Normal, data(stream_id))
+                .await
+                .unwrap();
+            sched
+                .send_stream(stream_id, Priority::Normal, data(stream_id))
+                .await
+                .unwrap();
+        }
+        sched
+            .send_control(Frame::Rst {
+                stream_id: 1,
+                code: 1,
+            })
+            .await
+            .unwrap();
+        // A send that raced with the RST.
+        sched
+            .send_stream(1, Priority::Normal, data(1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            sched.next().await,
+            Some(Frame::Rst { stream_id: 1, .. })
+        ));
+        assert_eq!(stream_of(&sched.next().await.unwrap()), 3);
+        assert_eq!(stream_of(&sched.next().await.unwrap()), 3);
+        assert!(sched.try_next().is_none());
+    }
+
+    #[tokio::test]
+    async fn full_stream_queue_waits_for_the_writer() {
+        let sched = Scheduler::new();
+        sched.register(1);
+        for _ in 0..STREAM_QUEUE {
+            sched
+                .send_stream(1, Priority::Normal, data(1))
+                .await
+                .unwrap();
+        }
+        let blocked = sched.send_stream(1, Priority::Normal, data(1));
+        tokio::pin!(blocked);
+        tokio::select! {
+            biased;
+            _ = &mut blocked => panic!("queued past the stream limit"),
+            _ = tokio::task::yield_now() => {}
+        }
+        sched.try_next().unwrap();
+        blocked.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unregistered_stream_keeps_what_it_queued() {
+        let sched = Scheduler::new();
+        sched.register(1);
+        sched
+            .send_stream(1, Priority::Normal, data(1))
+            .await
+            .unwrap();
+        sched
+            .send_stream(1, Priority::Normal, Frame::Fin { stream_id: 1 })
+            .await
+            .unwrap();
+        sched.unregister(1);
+        assert_eq!(stream_of(&sched.next().await.unwrap()), 1);
+        assert!(matches!(
+            sched.next().await,
+            Some(Frame::Fin { stream_id: 1 })
+        ));
+    }
+}
@@ -1,14 +1,59 @@
-use crate::codec::try_decode;
-use crate::frame::{Frame, TargetAddr};
-use crate::handshake::{build_hello, build_hello_ack, verify_hmac};
-use crate::keepalive::keepalive_task;
-use crate::stream::MuxStream;
+use crate::codec::FrameCodec;
+use crate::compress::{CompressionCounters, StreamCompressor, StreamDecompressor};
+use crate::crypto::LinkCipher;
+use crate::flow::{max_data, StreamFlow};
+use crate::frame::{
+    Compression, Frame, HelloFrame, Priority, TargetAddr, FLAG_BOND, FLAG_CHECKSUM, FLAG_GOAWAY,
+    FLAG_JOIN, FLAG_SPLIT_IDS, FLAG_WINDOW, GOAWAY_INCOMPATIBLE, GOAWAY_NO_ERROR,
+};
+use crate::handshake::{
+    build_auth, build_auth_key, build_hello, build_hello_ack, check_peer_flags, negotiate, refusal,
+    verify_auth, verify_auth_key, verify_hello_ack, KeyExchange, Negotiated, MIN_MAX_FRAME,
+};
+use crate::keepalive::{keepalive_task, Keepalive};
+use crate::keys::{AuthorizedKey, AuthorizedKeys, ClientKey};
+use crate::sched::Scheduler;
+use crate::stream::{LiveStreams, MuxStream, StreamError, StreamState, StreamStatus};
+use btlink::{BtLink, BtLinkHandle, CloseReason, LinkGroup, LinkId};
 use bytes::{Bytes, BytesMut};
 use common::error::{BtProxyError, Result};
-use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot, Mutex};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, Notify};
 use tokio::task::JoinHandle;
-use tracing::{debug, info, warn};
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
+use tokio_util::codec::Decoder;
+use tracing::{debug, info, warn, Instrument};
+
+/// Sequenced frames a bonded session keeps for retransmission before the
+/// writer waits for the peer to ack.
+const MAX_UNACKED: usize = 256;
+/// A bonded receiver acks after this many frames, or after `ACK_DELAY`.
+const ACK_EVERY: u64 = 16;
+const ACK_DELAY: Duration = Duration::from_millis(20);
+/// How long the oldest unacked frame may go without an ack before it is
+/// sent again. A frame the checksummed framing dropped on a live link is
+/// recovered this way instead of stalling the window.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+/// RST code for a peer that sent more than the stream window allowed.
+const RST_FLOW_CONTROL: u16 = 429;
+/// RST code for compressed DATA that does not decompress.
+const RST_BAD_DATA: u16 = 422;
+/// Chunks the writer leaves queued on a link. Anything beyond that waits in
+/// the scheduler, where a later, more urgent frame can still overtake it.
+const LINK_QUEUE: usize = 1;
+/// How long a closing session waits for queued frames to reach the links.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// OPEN_ERR code for streams opened after a GOAWAY.
+const OPEN_REFUSED: u16 = 503;
+/// RST code for a stream whose opener gave up before OPEN_OK.
+const RST_CANCELLED: u16 = 499;
+/// OPEN_ERR code for an OPEN whose stream id the peer may not use.
+const OPEN_BAD_ID: u16 = 400;
+/// RST code for streams that may have lost a frame to link corruption.
+const RST_FRAME_LOST: u16 = 410;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Role {
@@ -20,7 +65,88 @@ pub enum Role {
 pub struct MuxConfig {
     pub max_frame: usize,
     pub keepalive_ms: u32,
+    /// Unanswered PINGs in a row after which the session is closed as dead;
+    /// 0 never gives up.
+    pub max_missed_pongs: u32,
+    /// Close the session when nothing at all arrives for this long; 0
+    /// disables.
+    pub idle_timeout_ms: u32,
     pub psk: Option<Vec<u8>>,
+    /// Client: key to sign the handshake with.
+    pub client_key: Option<ClientKey>,
+    /// Server: accept only clients that sign with one of these keys.
+    pub authorized_keys: Option<Arc<AuthorizedKeys>>,
+    /// Ask for sync-marker + CRC32 framing; used only if the peer agrees.
+    pub checksum: bool,
+    /// Ask for a sequenced session that more links can join; used only if
+    /// the peer agrees.
+    pub bond: bool,
+    /// How long `open_stream` waits for OPEN_OK; 0 waits forever.
+    pub open_timeout_ms: u32,
+    /// Bytes buffered per stream before the peer has to wait for the
+    /// consumer; advertised in HELLO.
+    pub initial_window: u32,
+    /// DATA compression algorithms, most preferred first; empty disables
+    /// compression. Used only if the peer supports one of them.
+    pub compression: Vec<Compression>,
+    /// Frames that are ready together go to the link as one chunk of up to
+    /// this many bytes; 0 sends each frame on its own.
+    pub write_batch: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MuxStats {
+    /// Corrupt frames dropped by the checksummed decoder.
+    pub frame_errors: u64,
+    /// Links currently carrying the session.
+    pub links: usize,
+    /// Smoothed PING round trip, once a PONG has come back.
+    pub rtt: Option<Duration>,
+    /// PINGs sent since the last PONG.
+    pub missed_pongs: u32,
+    /// Streams that still have a handle on this side.
+    pub open_streams: usize,
+    /// DATA payload bytes sent, and what they took on the wire after
+    /// compression.
+    pub data_sent: u64,
+    pub data_sent_wire: u64,
+    /// DATA payload bytes received, and what they took on the wire.
+    pub data_received: u64,
+    pub data_received_wire: u64,
+}
+
+impl MuxStats {
+    /// Wire bytes per payload byte over both directions; 1.0 without
+    /// compression.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        let plain = self.data_sent + self.data_received;
+        let wire = self.data_sent_wire + self.data_received_wire;
+        (plain > 0).then(|| wire as f64 / plain as f64)
+    }
+}
+
+/// Why a session ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// This side closed or drained the session; `code` went out in GOAWAY.
+    Local { code: u16 },
+    /// The peer sent GOAWAY with `code`.
+    GoAway { code: u16 },
+    /// The peer stopped answering keepalives.
+    Timeout,
+    /// Every link carrying the session went down.
+    Link(CloseReason),
+}
+
+impl fmt::Display for SessionEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionEnd::Local { code } => write!(f, "closed locally (code {})", code),
+            SessionEnd::GoAway { code } => write!(f, "peer went away (code {})", code),
+            SessionEnd::Timeout => write!(f, "peer timed out"),
+            SessionEnd::Link(reason) => write!(f, "link closed: {}", reason),
+        }
+    }
 }
 
 impl Default for MuxConfig {
@@ -28,221 +154,531 @@ impl Default for MuxConfig {
         Self {
             max_frame: 65536,
             keepalive_ms: 10_000,
+            max_missed_pongs: 3,
+            idle_timeout_ms: 0,
             psk: None,
+            client_key: None,
+            authorized_keys: None,
+            checksum: false,
+            bond: false,
+            open_timeout_ms: 15_000,
+            initial_window: 256 * 1024,
+            compression: Vec::new(),
+            write_batch: 4096,
         }
     }
 }
 
+/// A link that has completed the HELLO exchange but does not carry a
+/// session yet.
+pub struct PendingLink {
+    link: BtLink,
+    codec: FrameCodec,
+    buffer: BytesMut,
+    /// The HELLO (client) or HELLO_ACK (server) received from the peer.
+    peer: HelloFrame,
+    negotiated: Negotiated,
+    /// Server: the authorized key the client signed with.
+    client_key: Option<Arc<AuthorizedKey>>,
+}
+
+impl PendingLink {
+    pub fn negotiated(&self) -> &Negotiated {
+        &self.negotiated
+    }
+
+    pub fn client_key(&self) -> Option<&AuthorizedKey> {
+        self.client_key.as_deref()
+    }
+}
+
+/// What a server finds on a freshly accepted link.
+pub enum Incoming {
+    /// The link starts a new session.
+    Session(MuxSession),
+    /// The link wants to join the bonded session identified by `token`;
+    /// pass it to that session's `attach`.
+    Join { token: u64, link: Box<PendingLink> },
+}
+
 #[derive(Clone)]
 pub struct MuxSession {
     inner: Arc<InnerSession>,
 }
 
 struct InnerSession {
-    outgoing: mpsc::Sender<Frame>,
     incoming: Mutex<mpsc::Receiver<(TargetAddr, MuxStream)>>,
-    streams: Arc<Mutex<std::collections::HashMap<u32, mpsc::Sender<Bytes>>>>,
-    pending: Arc<Mutex<std::collections::HashMap<u32, oneshot::Sender<Result<()>>>>>,
-    next_stream_id: Mutex<u32>,
+    shared: Arc<Shared>,
+    /// `None` once every id of our parity has been used.
+    next_stream_id: Mutex<Option<u32>>,
     _tasks: Vec<JoinHandle<()>>,
 }
 
+/// State shared by the link readers, the dispatcher and the writer.
+struct Shared {
+    cfg: MuxConfig,
+    role: Role,
+    /// Settled in the handshake of the first link.
+    negotiated: Negotiated,
+    /// Whether the peer keeps to its half of the stream ids.
+    peer_split_ids: bool,
+    group: LinkGroup,
+    /// Template for per-link codecs; clones share its error counter.
+    codec: FrameCodec,
+    codecs: std::sync::Mutex<HashMap<LinkId, FrameCodec>>,
+    frames: mpsc::Sender<(LinkId, Frame)>,
+    sched: Arc<Scheduler>,
+    keepalive: Arc<Keepalive>,
+    live: LiveStreams,
+    goaway: std::sync::Mutex<GoAwayState>,
+    /// Flips to true once either side has sent GOAWAY.
+    going_away: watch::Sender<bool>,
+    /// Whether the peer understands GOAWAY.
+    peer_goaway: bool,
+    /// First recorded reason for the session to end.
+    end: watch::Sender<Option<SessionEnd>>,
+    /// Initial window the peer advertised, if it does flow control.
+    peer_window: Option<u32>,
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    pending: Mutex<HashMap<u32, oneshot::Sender<Result<()>>>>,
+    bond: Option<Bond>,
+    client_key: Option<Arc<AuthorizedKey>>,
+    compression: CompressionCounters,
+}
+
+/// Sequencing state of a bonded session. Every frame after the handshake is
+/// wrapped in SEQ, kept until the peer acks it and sent again on another
+/// link if the one it went out on drops, or once it went unacked for
+/// `RETRANSMIT_TIMEOUT`.
+struct Bond {
+    token: u64,
+    unacked: std::sync::Mutex<VecDeque<Unacked>>,
+    acked: Notify,
+    /// Next sequence number expected from the peer.
+    received: AtomicU64,
+    ack_wanted: Notify,
+    /// A duplicate arrived, so our last ACK may have been lost; send it
+    /// again even though nothing new came in.
+    ack_again: AtomicBool,
+}
+
+#[derive(Default)]
+struct GoAwayState {
+    /// Highest stream id the peer opened and we accepted.
+    last_peer_stream: u32,
+    /// Set once we sent GOAWAY; no stream from the peer is accepted after.
+    sent: bool,
+    /// A task is waiting to close the session once its streams are done.
+    finishing: bool,
+}
+
+/// A stream the session still routes frames to. Removed once both sides
+/// have sent FIN, either side sent RST, or every handle is gone.
+struct StreamEntry {
+    /// Dropped once the peer has finished sending.
+    tx: Option<mpsc::UnboundedSender<Bytes>>,
+    /// Gone once every handle to the stream has been dropped.
+    flow: Weak<StreamFlow>,
+    status: Arc<StreamStatus>,
+    /// Present once compression was negotiated.
+    compressor: Option<Arc<std::sync::Mutex<StreamCompressor>>>,
+    decompressor: Option<StreamDecompressor>,
+}
+
+/// Undoes a pending `open_stream` that did not complete: forgets the
+/// stream and, if OPEN went out, resets it so the peer does not keep it.
+struct OpenGuard {
+    shared: Arc<Shared>,
+    stream_id: u32,
+    /// OPEN went out and the peer may have the stream.
+    sent: bool,
+    /// The open succeeded; nothing to undo.
+    done: bool,
+}
+
+impl Drop for OpenGuard {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let stream_id = self.stream_id;
+        if self.sent {
+            let _ = self.shared.sched.send_control_now(Frame::Rst {
+                stream_id,
+                code: RST_CANCELLED,
+            });
+        }
+        let shared = Arc::clone(&self.shared);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                shared.pending.lock().await.remove(&stream_id);
+                shared.remove_stream(stream_id).await;
+            });
+        }
+    }
+}
+
+struct Unacked {
+    seq: u64,
+    link: LinkId,
+    frame: Frame,
+    sent_at: Instant,
+}
+
 impl MuxSession {
-    pub async fn start(link: btlink::BtLink, cfg: MuxConfig, role: Role) -> Result<Self> {
-        let (tx_frame, mut rx_frame) = mpsc::channel::<Frame>(cfg.max_frame / 1024 + 32);
-        let (tx_open, rx_open) = mpsc::channel::<(TargetAddr, MuxStream)>(128);
-        let psk = cfg.psk.clone();
+    pub async fn start(link: BtLink, cfg: MuxConfig, role: Role) -> Result<Self> {
+        match role {
+            Role::Client => {
+                let pending =
+                    handshake(link, &cfg, Role::Client, 0, FrameCodec::new(cfg.max_frame)).await?;
+                Self::from_pending(pending, cfg, role)
+            }
+            Role::Server => match Self::accept(link, cfg).await? {
+                Incoming::Session(session) => Ok(session),
+                Incoming::Join { .. } => Err(BtProxyError::Protocol(
+                    "join received where a new session was expected".to_string(),
+                )),
+            },
+        }
+    }
 
-        let (link_tx, mut link_rx) = (link.tx, link.rx);
+    /// Server side of the handshake for a link that may either start a
+    /// session or join a bonded one.
+    pub async fn accept(link: BtLink, cfg: MuxConfig) -> Result<Incoming> {
+        let mut pending =
+            handshake(link, &cfg, Role::Server, 0, FrameCodec::new(cfg.max_frame)).await?;
+        if pending.peer.flags & FLAG_JOIN == 0 {
+            return Ok(Incoming::Session(Self::from_pending(
+                pending,
+                cfg,
+                Role::Server,
+            )?));
+        }
+        if !pending.negotiated.has(FLAG_BOND) {
+            return Err(BtProxyError::Protocol(
+                "join without bonding enabled".to_string(),
+            ));
+        }
+        if let Some(key) = pending
+            .client_key
+            .as_ref()
+            .filter(|key| key.options.no_bond)
+        {
+            return Err(BtProxyError::Auth(format!(
+                "key {} may not join bonded sessions",
+                key
+            )));
+        }
+        let frame = next_frame(
+            &mut pending.link.rx,
+            &mut pending.buffer,
+            &mut pending.codec,
+        )
+        .await?;
+        match frame {
+            Frame::Join { token } => Ok(Incoming::Join {
+                token,
+                link: Box::new(pending),
+            }),
+            frame => Err(BtProxyError::Protocol(format!(
+                "expected join, got {:?}",
+                frame
+            ))),
+        }
+    }
 
-        let hello = build_hello(cfg.max_frame as u32, cfg.keepalive_ms, psk.as_deref());
-        link_tx
-            .send(hello.encode()?.into())
+    /// Client side: runs the handshake on an extra link and adds it to this
+    /// bonded session. Returns the link's handle so the caller can notice
+    /// when it drops.
+    pub async fn join(&self, link: BtLink) -> Result<BtLinkHandle> {
+        let shared = &self.inner.shared;
+        let bond = shared
+            .bond
+            .as_ref()
+            .ok_or_else(|| BtProxyError::Protocol("session is not bonded".to_string()))?;
+        // HELLO always goes out in plain framing.
+        let mut codec = shared.codec.clone();
+        codec.set_checksum(false);
+        codec.set_cipher(None);
+        let pending = handshake(link, &shared.cfg, Role::Client, FLAG_JOIN, codec).await?;
+        if !pending.negotiated.has(FLAG_BOND) {
+            return Err(BtProxyError::Protocol(
+                "peer refused to bond this link".to_string(),
+            ));
+        }
+        let join = pending
+            .codec
+            .encode_frame(&Frame::Join { token: bond.token })?;
+        pending
+            .link
+            .tx
+            .send(join)
             .await
-            .map_err(|_| BtProxyError::Protocol("failed to send hello".to_string()))?;
+            .map_err(|_| BtProxyError::Protocol("failed to send join".to_string()))?;
+        let handle = pending.link.handle.clone();
+        self.attach(pending)?;
+        Ok(handle)
+    }
 
-        let mut buffer = BytesMut::new();
-        let mut got_handshake = false;
-        while !got_handshake {
-            let chunk = link_rx
-                .recv()
-                .await
-                .ok_or_else(|| BtProxyError::Protocol("handshake eof".to_string()))?;
-            buffer.extend_from_slice(&chunk);
-            if let Some(frame) = try_decode(&mut buffer, cfg.max_frame)? {
-                match frame {
-                    Frame::Hello(frame) => {
-                        verify_hmac(psk.as_deref(), &frame)?;
-                        let ack = build_hello_ack(
-                            cfg.max_frame as u32,
-                            cfg.keepalive_ms,
-                            psk.as_deref(),
-                            frame.nonce,
-                        );
-                        link_tx.send(ack.encode()?.into()).await.map_err(|_| {
-                            BtProxyError::Protocol("failed to send hello ack".to_string())
-                        })?;
-                        got_handshake = true;
-                    }
-                    Frame::HelloAck(frame) => {
-                        verify_hmac(psk.as_deref(), &frame)?;
-                        got_handshake = true;
-                    }
-                    _ => {
-                        warn!(?frame, "unexpected frame before handshake");
-                    }
-                }
-            }
+    /// Adds a link that asked to join this session. It must have been
+    /// signed with the same client key as the session.
+    pub fn attach(&self, link: PendingLink) -> Result<()> {
+        if self.inner.shared.bond.is_none() || !link.negotiated.has(FLAG_BOND) {
+            return Err(BtProxyError::Protocol("session is not bonded".to_string()));
+        }
+        let key_of = |key: Option<&AuthorizedKey>| key.map(|key| key.key);
+        if key_of(link.client_key()) != key_of(self.client_key()) {
+            return Err(BtProxyError::Auth(
+                "link was signed with a different key than the session".to_string(),
+            ));
         }
+        add_link(&self.inner.shared, link)?;
+        Ok(())
+    }
 
-        let streams: Arc<Mutex<std::collections::HashMap<u32, mpsc::Sender<Bytes>>>> =
-            Arc::new(Mutex::new(std::collections::HashMap::new()));
-        let pending: Arc<Mutex<std::collections::HashMap<u32, oneshot::Sender<Result<()>>>>> =
-            Arc::new(Mutex::new(std::collections::HashMap::new()));
-        let next_stream_id = Mutex::new(1u32);
-
-        let streams_clone = Arc::clone(&streams);
-        let pending_clone = Arc::clone(&pending);
-        let tx_open_clone = tx_open.clone();
-        let mut buffer = buffer;
-
-        let tx_frame_for_read = tx_frame.clone();
-        let read_task = tokio::spawn(async move {
-            loop {
-                match link_rx.recv().await {
-                    Some(chunk) => {
-                        buffer.extend_from_slice(&chunk);
-                        loop {
-                            match try_decode(&mut buffer, cfg.max_frame) {
-                                Ok(Some(frame)) => match frame {
-                                    Frame::Open { stream_id, target } => {
-                                        let (tx_stream, rx_stream) = mpsc::channel(128);
-                                        streams_clone.lock().await.insert(stream_id, tx_stream);
-                                        let stream = MuxStream::new(
-                                            stream_id,
-                                            tx_frame_for_read.clone(),
-                                            rx_stream,
-                                        );
-                                        if tx_open_clone.send((target, stream)).await.is_err() {
-                                            break;
-                                        }
-                                    }
-                                    Frame::OpenOk { stream_id } => {
-                                        if let Some(tx) =
-                                            pending_clone.lock().await.remove(&stream_id)
-                                        {
-                                            let _ = tx.send(Ok(()));
-                                        }
-                                    }
-                                    Frame::OpenErr {
-                                        stream_id, message, ..
-                                    } => {
-                                        if let Some(tx) =
-                                            pending_clone.lock().await.remove(&stream_id)
-                                        {
-                                            let _ = tx.send(Err(BtProxyError::Protocol(message)));
-                                        }
-                                    }
-                                    Frame::Data { stream_id, payload } => {
-                                        if let Some(tx) = streams_clone.lock().await.get(&stream_id)
-                                        {
-                                            let _ = tx.send(payload).await;
-                                        }
-                                    }
-                                    Frame::Fin { stream_id } | Frame::Rst { stream_id, .. } => {
-                                        streams_clone.lock().await.remove(&stream_id);
-                                    }
-                                    Frame::Ping { nonce } => {
-                                        let _ = tx_frame_for_read.send(Frame::Pong { nonce }).await;
-                                    }
-                                    Frame::Pong { .. } => {}
-                                    Frame::Hello(_) | Frame::HelloAck(_) => {}
-                                },
-                                Ok(None) => break,
-                                Err(err) => {
-                                    debug!(?err, "decode error");
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    None => break,
-                }
-            }
-        });
+    /// Server: the authorized key the client signed the handshake with.
+    pub fn client_key(&self) -> Option<&AuthorizedKey> {
+        self.inner.shared.client_key.as_deref()
+    }
 
-        let write_task = tokio::spawn(async move {
-            while let Some(frame) = rx_frame.recv().await {
-                match frame.encode() {
-                    Ok(bytes) => {
-                        if link_tx.send(bytes).await.is_err() {
-                            break;
-                        }
-                    }
-                    Err(err) => {
-                        debug!(?err, "encode error");
-                    }
-                }
-            }
-        });
+    /// Version, capabilities and limits agreed with the peer.
+    pub fn negotiated(&self) -> &Negotiated {
+        &self.inner.shared.negotiated
+    }
 
-        let keepalive_handle = tokio::spawn(keepalive_task(tx_frame.clone(), cfg.keepalive_ms));
+    /// Identifies a bonded session to links that want to join it.
+    pub fn bond_token(&self) -> Option<u64> {
+        self.inner.shared.bond.as_ref().map(|bond| bond.token)
+    }
+
+    fn from_pending(pending: PendingLink, cfg: MuxConfig, role: Role) -> Result<Self> {
+        let (tx_acks, rx_acks) = mpsc::channel::<Frame>(16);
+        let (tx_open, rx_open) = mpsc::channel::<(TargetAddr, MuxStream)>(128);
+        let (tx_links, rx_links) = mpsc::channel::<(LinkId, Frame)>(128);
 
-        let tasks = vec![read_task, write_task, keepalive_handle];
+        let negotiated = pending.negotiated;
+        let bonded = negotiated.has(FLAG_BOND);
+        let encrypted = pending.codec.encrypted();
+        let bond = bonded.then(|| Bond {
+            token: pending.peer.nonce,
+            unacked: std::sync::Mutex::new(VecDeque::new()),
+            acked: Notify::new(),
+            received: AtomicU64::new(0),
+            ack_wanted: Notify::new(),
+            ack_again: AtomicBool::new(false),
+        });
+        let shared = Arc::new(Shared {
+            codec: pending.codec.clone(),
+            cfg: cfg.clone(),
+            role,
+            negotiated,
+            peer_split_ids: negotiated.has(FLAG_SPLIT_IDS),
+            group: LinkGroup::new(),
+            codecs: std::sync::Mutex::new(HashMap::new()),
+            frames: tx_links,
+            sched: Arc::new(Scheduler::new()),
+            keepalive: Arc::new(Keepalive::new()),
+            live: LiveStreams::new(),
+            goaway: std::sync::Mutex::new(GoAwayState::default()),
+            going_away: watch::channel(false).0,
+            peer_goaway: negotiated.has(FLAG_GOAWAY),
+            end: watch::channel(None).0,
+            peer_window: pending
+                .peer
+                .initial_window
+                .filter(|_| negotiated.has(FLAG_WINDOW)),
+            streams: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            bond,
+            client_key: pending.client_key.clone(),
+            compression: CompressionCounters::default(),
+        });
+        add_link(&shared, pending)?;
 
-        let outgoing = tx_frame;
+        let dispatch_task =
+            tokio::spawn(dispatch_loop(Arc::clone(&shared), rx_links, tx_open).in_current_span());
+        let write_task = tokio::spawn(write_loop(Arc::clone(&shared), rx_acks).in_current_span());
+        let keepalive_handle = tokio::spawn(watch_peer(Arc::clone(&shared)).in_current_span());
+        let mut tasks = vec![dispatch_task, write_task, keepalive_handle];
+        if bonded {
+            tasks.push(tokio::spawn(
+                ack_loop(Arc::clone(&shared), tx_acks).in_current_span(),
+            ));
+        }
 
-        info!(?role, "mux session started");
+        info!(
+            ?role,
+            version = negotiated.version,
+            flags = format_args!("{:#06x}", negotiated.flags),
+            max_frame = negotiated.max_frame,
+            keepalive_ms = negotiated.keepalive_ms,
+            compression = ?negotiated.compression,
+            bonded,
+            encrypted,
+            "mux session started"
+        );
 
         Ok(Self {
             inner: Arc::new(InnerSession {
-                outgoing,
                 incoming: Mutex::new(rx_open),
-                streams: Arc::clone(&streams),
-                pending: Arc::clone(&pending),
-                next_stream_id,
+                shared,
+                // Clients open odd ids, servers even ones.
+                next_stream_id: Mutex::new(Some(match role {
+                    Role::Client => 1,
+                    Role::Server => 2,
+                })),
                 _tasks: tasks,
             }),
         })
     }
 
     pub async fn open_stream(&self, target: TargetAddr) -> Result<MuxStream> {
-        let mut id_guard = self.inner.next_stream_id.lock().await;
-        let stream_id = *id_guard;
-        *id_guard = id_guard.wrapping_add(1);
-        drop(id_guard);
+        self.open_stream_with_priority(target, Priority::Normal)
+            .await
+    }
 
-        let (tx_stream, rx_stream) = mpsc::channel(128);
-        self.inner.streams.lock().await.insert(stream_id, tx_stream);
+    /// Opens a stream whose frames are scheduled at `priority` in both
+    /// directions. Either side may open streams, unless the peer predates
+    /// split stream ids; then only the client can.
+    pub async fn open_stream_with_priority(
+        &self,
+        target: TargetAddr,
+        priority: Priority,
+    ) -> Result<MuxStream> {
+        let shared = &self.inner.shared;
+        if *shared.going_away.borrow() {
+            return Err(BtProxyError::Protocol("session is going away".to_string()));
+        }
+        if matches!(shared.role, Role::Server) && !shared.peer_split_ids {
+            return Err(BtProxyError::Unsupported(
+                "peer only accepts streams it opens".to_string(),
+            ));
+        }
+        // Held until OPEN is queued: the peer expects ids in order.
+        let mut next = self.inner.next_stream_id.lock().await;
+        let Some(stream_id) = *next else {
+            drop(next);
+            // Ids are never reused; wind the session down instead.
+            shared.set_end(SessionEnd::Local {
+                code: GOAWAY_NO_ERROR,
+            });
+            shared.send_goaway(GOAWAY_NO_ERROR).await;
+            shared.finish_when_idle();
+            return Err(BtProxyError::Protocol("stream ids exhausted".to_string()));
+        };
+        *next = stream_id.checked_add(2);
+
+        let stream = shared.register_stream(stream_id, priority).await;
 
         let (tx_pending, rx_pending) = oneshot::channel();
-        self.inner
-            .pending
-            .lock()
-            .await
-            .insert(stream_id, tx_pending);
+        shared.pending.lock().await.insert(stream_id, tx_pending);
+        // Cleans up if this future is dropped or fails from here on.
+        let mut guard = OpenGuard {
+            shared: Arc::clone(shared),
+            stream_id,
+            sent: false,
+            done: false,
+        };
 
-        self.inner
-            .outgoing
-            .send(Frame::Open { stream_id, target })
+        shared
+            .sched
+            .send_control(Frame::Open {
+                stream_id,
+                target,
+                priority,
+            })
             .await
             .map_err(|_| BtProxyError::Protocol("failed to send open".to_string()))?;
+        guard.sent = true;
+        drop(next);
 
-        match rx_pending.await {
-            Ok(Ok(())) => Ok(MuxStream::new(
-                stream_id,
-                self.inner.outgoing.clone(),
-                rx_stream,
-            )),
-            Ok(Err(err)) => {
-                self.inner.streams.lock().await.remove(&stream_id);
+        let open_timeout = match shared.cfg.open_timeout_ms {
+            0 => Duration::MAX,
+            ms => Duration::from_millis(ms as u64),
+        };
+        match timeout(open_timeout, rx_pending).await {
+            Ok(Ok(Ok(()))) => {
+                guard.done = true;
+                Ok(stream)
+            }
+            Ok(Ok(Err(err))) => {
+                // The peer already dropped the stream.
+                guard.sent = false;
                 Err(err)
             }
-            Err(_) => {
-                self.inner.streams.lock().await.remove(&stream_id);
-                Err(BtProxyError::Protocol("open canceled".to_string()))
+            Ok(Err(_)) => Err(BtProxyError::Protocol("open canceled".to_string())),
+            Err(_) => Err(BtProxyError::Timeout(format!(
+                "no answer to open of stream {} within {:?}",
+                stream_id, open_timeout
+            ))),
+        }
+    }
+
+    pub fn stats(&self) -> MuxStats {
+        let counters = &self.inner.shared.compression;
+        MuxStats {
+            frame_errors: self.inner.shared.codec.errors(),
+            links: self.inner.shared.group.len(),
+            rtt: self.inner.shared.keepalive.rtt(),
+            missed_pongs: self.inner.shared.keepalive.missed(),
+            open_streams: self.inner.shared.live.count(),
+            data_sent: counters.sent.load(Ordering::Relaxed),
+            data_sent_wire: counters.sent_wire.load(Ordering::Relaxed),
+            data_received: counters.received.load(Ordering::Relaxed),
+            data_received_wire: counters.received_wire.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resolves once the last link carrying the session has stopped.
+    pub async fn closed(&self) -> SessionEnd {
+        let shared = &self.inner.shared;
+        let reason = shared.group.closed().await;
+        shared.set_end(SessionEnd::Link(reason));
+        let end = shared.end.borrow().clone();
+        end.unwrap_or(SessionEnd::Link(CloseReason::LocalShutdown))
+    }
+
+    /// Resolves once either side has sent GOAWAY. The session takes no new
+    /// streams from then on and ends when the open ones are done.
+    pub async fn going_away(&self) {
+        let mut rx = self.inner.shared.going_away.subscribe();
+        let _ = rx.wait_for(|going| *going).await;
+    }
+
+    /// Closes every link carrying the session right away, failing open
+    /// streams.
+    pub async fn close(&self) -> SessionEnd {
+        let shared = &self.inner.shared;
+        shared.set_end(SessionEnd::Local {
+            code: GOAWAY_NO_ERROR,
+        });
+        shared.group.close().await;
+        self.closed().await
+    }
+
+    /// Sends GOAWAY with `code`, lets the open streams finish and closes the
+    /// session. Streams still open after `deadline` are cut off.
+    pub async fn drain(&self, code: u16, deadline: Duration) -> SessionEnd {
+        let shared = &self.inner.shared;
+        shared.set_end(SessionEnd::Local { code });
+        shared.send_goaway(code).await;
+        let drained = timeout(deadline, async {
+            shared.live.idle().await;
+            if shared.peer_goaway {
+                // The peer closes once it has everything it was waiting for.
+                shared.group.closed().await;
             }
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                streams = shared.live.count(),
+                "drain deadline passed, closing open streams"
+            );
         }
+        let _ = timeout(FLUSH_TIMEOUT, shared.flush()).await;
+        shared.group.close().await;
+        self.closed().await
     }
 
     pub async fn accept_stream(&self) -> Option<(TargetAddr, MuxStream)> {
@@ -252,18 +688,23 @@ impl MuxSession {
 
     pub async fn send_open_ok(&self, stream_id: u32) -> Result<()> {
         self.inner
-            .outgoing
-            .send(Frame::OpenOk { stream_id })
+            .shared
+            .sched
+            .send_control(Frame::OpenOk { stream_id })
             .await
             .map_err(|_| BtProxyError::Protocol("open ok send failed".to_string()))
     }
 
     pub async fn send_open_err(&self, stream_id: u32, code: u16, message: &str) -> Result<()> {
-        self.inner.streams.lock().await.remove(&stream_id);
-        self.inner.pending.lock().await.remove(&stream_id);
         self.inner
-            .outgoing
-            .send(Frame::OpenErr {
+            .shared
+            .abort_stream(stream_id, StreamError::LocalReset(code))
+            .await;
+        self.inner.shared.pending.lock().await.remove(&stream_id);
+        self.inner
+            .shared
+            .sched
+            .send_control(Frame::OpenErr {
                 stream_id,
                 code,
                 message: message.to_string(),
@@ -272,13 +713,1074 @@ impl MuxSession {
             .map_err(|_| BtProxyError::Protocol("open err send failed".to_string()))
     }
 
+    /// Aborts both directions of a stream. Its handles fail with
+    /// `StreamError::LocalReset`.
     pub async fn send_rst(&self, stream_id: u32, code: u16) -> Result<()> {
-        self.inner.streams.lock().await.remove(&stream_id);
-        self.inner.pending.lock().await.remove(&stream_id);
         self.inner
-            .outgoing
-            .send(Frame::Rst { stream_id, code })
+            .shared
+            .abort_stream(stream_id, StreamError::LocalReset(code))
+            .await;
+        self.inner.shared.pending.lock().await.remove(&stream_id);
+        self.inner
+            .shared
+            .sched
+            .send_control(Frame::Rst { stream_id, code })
             .await
             .map_err(|_| BtProxyError::Protocol("rst send failed".to_string()))
     }
 }
+
+async fn handshake(
+    mut link: BtLink,
+    cfg: &MuxConfig,
+    role: Role,
+    extra_flags: u16,
+    mut codec: FrameCodec,
+) -> Result<PendingLink> {
+    if cfg.initial_window == 0 {
+        return Err(BtProxyError::Protocol(
+            "initial window must not be zero".to_string(),
+        ));
+    }
+    if cfg.keepalive_ms == 0 {
+        return Err(BtProxyError::Protocol(
+            "keepalive interval must not be zero".to_string(),
+        ));
+    }
+    if cfg.max_frame < MIN_MAX_FRAME as usize {
+        return Err(BtProxyError::Protocol(format!(
+            "max frame must be at least {}",
+            MIN_MAX_FRAME
+        )));
+    }
+    let psk = cfg.psk.as_deref();
+    let pubkey = match role {
+        Role::Client => cfg.client_key.is_some(),
+        Role::Server => cfg.authorized_keys.is_some(),
+    };
+    let kex = pubkey.then(KeyExchange::new);
+    let kex_public = kex.as_ref().map(KeyExchange::public);
+    let mut flags = extra_flags;
+    if cfg.checksum {
+        flags |= FLAG_CHECKSUM;
+    }
+    if cfg.bond {
+        flags |= FLAG_BOND;
+    }
+    let mut buffer = BytesMut::new();
+    let (peer, negotiated, hello, ack, client_key) = match role {
+        Role::Client => {
+            let hello = build_hello(cfg, flags, kex_public);
+            link.tx
+                .send(codec.encode_frame(&hello)?)
+                .await
+                .map_err(|_| BtProxyError::Protocol("failed to send hello".to_string()))?;
+            let Frame::Hello(sent) = &hello else {
+                unreachable!("build_hello returns a hello");
+            };
+            let ack = loop {
+                match next_frame(&mut link.rx, &mut buffer, &mut codec).await? {
+                    Frame::HelloAck(frame) => break frame,
+                    Frame::GoAway { code, .. } => return Err(refusal(code)),
+                    frame => warn!(?frame, "unexpected frame before handshake"),
+                }
+            };
+            let negotiated = negotiate(sent, &ack)?;
+            check_peer_flags(psk, pubkey, &ack)?;
+            verify_hello_ack(psk, sent, &ack)?;
+            let mut answers = Vec::new();
+            if let Some(psk) = psk {
+                answers.push(build_auth(psk, sent, &ack)?);
+            }
+            if let Some(key) = &cfg.client_key {
+                answers.push(build_auth_key(key, sent, &ack)?);
+            }
+            for answer in answers {
+                link.tx
+                    .send(codec.encode_frame(&answer)?)
+                    .await
+                    .map_err(|_| BtProxyError::Protocol("failed to send auth".to_string()))?;
+            }
+            (*ack.clone(), negotiated, hello, Frame::HelloAck(ack), None)
+        }
+        Role::Server => {
+            let hello = loop {
+                match next_frame(&mut link.rx, &mut buffer, &mut codec).await? {
+                    Frame::Hello(frame) => break frame,
+                    frame => warn!(?frame, "unexpected frame before handshake"),
+                }
+            };
+            check_peer_flags(psk, pubkey, &hello)?;
+            let ack = build_hello_ack(cfg, flags, kex_public, &hello)?;
+            let Frame::HelloAck(sent) = &ack else {
+                unreachable!("build_hello_ack returns a hello ack");
+            };
+            let negotiated = match negotiate(sent, &hello) {
+                Ok(negotiated) => negotiated,
+                Err(err) => {
+                    let refuse = Frame::GoAway {
+                        last_stream_id: 0,
+                        code: GOAWAY_INCOMPATIBLE,
+                    };
+                    let _ = link.tx.send(codec.encode_frame(&refuse)?).await;
+                    return Err(err);
+                }
+            };
+            link.tx
+                .send(codec.encode_frame(&ack)?)
+                .await
+                .map_err(|_| BtProxyError::Protocol("failed to send hello ack".to_string()))?;
+            if let Some(psk) = psk {
+                match next_frame(&mut link.rx, &mut buffer, &mut codec).await? {
+                    Frame::Auth { proof } => verify_auth(psk, &hello, sent, &proof)?,
+                    frame => {
+                        return Err(BtProxyError::Auth(format!(
+                            "expected auth, got {:?}",
+                            frame
+                        )))
+                    }
+                }
+            }
+            let client_key = match &cfg.authorized_keys {
+                Some(keys) => match next_frame(&mut link.rx, &mut buffer, &mut codec).await? {
+                    Frame::AuthKey {
+                        public_key,
+                        signature,
+                    } => {
+                        let key = verify_auth_key(keys, &hello, sent, &public_key, &signature)?;
+                        info!(key = %key, "client key accepted");
+                        Some(Arc::new(key))
+                    }
+                    frame => {
+                        return Err(BtProxyError::Auth(format!(
+                            "expected auth key, got {:?}",
+                            frame
+                        )))
+                    }
+                },
+                None => None,
+            };
+            (
+                *hello.clone(),
+                negotiated,
+                Frame::Hello(hello),
+                ack,
+                client_key,
+            )
+        }
+    };
+    if peer.initial_window == Some(0) {
+        return Err(BtProxyError::Protocol(
+            "peer advertised a zero window".to_string(),
+        ));
+    }
+    // Everything after the handshake uses the negotiated framing.
+    codec.set_max_frame(negotiated.max_frame as usize);
+    codec.set_checksum(negotiated.has(FLAG_CHECKSUM));
+    // The session keys come from the PSK, the key exchange, or both.
+    let mut secret = psk.map(<[u8]>::to_vec).unwrap_or_default();
+    if let Some(kex) = kex {
+        let peer_kex = peer
+            .kex
+            .ok_or_else(|| BtProxyError::Auth("peer sent no key exchange".to_string()))?;
+        secret.extend_from_slice(&kex.agree(peer_kex)?);
+    }
+    let cipher = if psk.is_some() || pubkey {
+        Some(Arc::new(LinkCipher::derive(&secret, role, &hello, &ack)?))
+    } else {
+        None
+    };
+    codec.set_cipher(cipher);
+    Ok(PendingLink {
+        link,
+        codec,
+        buffer,
+        peer,
+        negotiated,
+        client_key,
+    })
+}
+
+fn add_link(shared: &Arc<Shared>, pending: PendingLink) -> Result<LinkId> {
+    let PendingLink {
+        link,
+        codec: link_codec,
+        buffer,
+        ..
+    } = pending;
+    let handle = link.handle.clone();
+    let (id, rx) = shared.group.add(link)?;
+    let mut codec = shared.codec.clone();
+    codec.set_checksum(link_codec.checksum());
+    codec.set_cipher(link_codec.cipher());
+    shared.codecs.lock().unwrap().insert(id, codec.clone());
+    tokio::spawn(read_link(Arc::clone(shared), id, rx, buffer, codec, handle).in_current_span());
+    Ok(id)
+}
+
+/// Decodes one link and forwards its frames to the dispatcher.
+async fn read_link(
+    shared: Arc<Shared>,
+    id: LinkId,
+    mut rx: mpsc::Receiver<Bytes>,
+    mut buffer: BytesMut,
+    mut codec: FrameCodec,
+    handle: BtLinkHandle,
+) {
+    'read: loop {
+        loop {
+            let decoded = codec.decode(&mut buffer);
+            if codec.take_lost() {
+                shared.frame_lost(id).await;
+            }
+            match decoded {
+                Ok(Some(frame)) => {
+                    if shared.frames.send((id, frame)).await.is_err() {
+                        break 'read;
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    warn!(link = id, ?err, "unrecoverable decode error, closing link");
+                    handle.close().await;
+                    break 'read;
+                }
+            }
+        }
+        match rx.recv().await {
+            Some(chunk) => {
+                shared.keepalive.touch();
+                append_chunk(&mut buffer, chunk);
+            }
+            None => break,
+        }
+    }
+    shared.codecs.lock().unwrap().remove(&id);
+    let reason = handle.closed().await;
+    info!(link = id, %reason, "btlink closed");
+}
+
+async fn dispatch_loop(
+    shared: Arc<Shared>,
+    mut frames: mpsc::Receiver<(LinkId, Frame)>,
+    tx_open: mpsc::Sender<(TargetAddr, MuxStream)>,
+) {
+    let mut reorder = BTreeMap::<u64, Frame>::new();
+    loop {
+        let (link, frame) = tokio::select! {
+            next = frames.recv() => match next {
+                Some(next) => next,
+                None => break,
+            },
+            _ = shared.group.closed() => break,
+        };
+        match (frame, &shared.bond) {
+            (Frame::Seq { seq, frame }, Some(bond)) => {
+                let mut next = bond.received.load(Ordering::Relaxed);
+                // Anything below `next` is a retransmitted duplicate, most
+                // likely because our ACK for it went missing.
+                if seq < next {
+                    bond.ack_again.store(true, Ordering::Relaxed);
+                    bond.ack_wanted.notify_one();
+                    continue;
+                }
+                if seq >= next + MAX_UNACKED as u64 {
+                    warn!(
+                        link,
+                        seq, next, "sequence number outside the window, dropping"
+                    );
+                    continue;
+                }
+                reorder.insert(seq, *frame);
+                while let Some(frame) = reorder.remove(&next) {
+                    next += 1;
+                    bond.received.store(next, Ordering::Relaxed);
+                    if next % ACK_EVERY == 0 {
+                        bond.ack_wanted.notify_one();
+                    }
+                    dispatch(&shared, frame, &tx_open).await;
+                }
+            }
+            (Frame::Ack { next_seq }, Some(bond)) => bond.release(next_seq),
+            (frame @ (Frame::Seq { .. } | Frame::Ack { .. }), None) => {
+                warn!(link, ?frame, "sequenced frame on an unbonded session");
+            }
+            (frame, _) => dispatch(&shared, frame, &tx_open).await,
+        }
+    }
+    // Wake everything still waiting on the session.
+    for (_, entry) in shared.streams.lock().await.drain() {
+        entry.status.reset(StreamError::SessionClosed);
+        if let Some(flow) = entry.flow.upgrade() {
+            flow.close();
+        }
+    }
+    shared.pending.lock().await.clear();
+    shared.sched.close();
+}
+
+async fn dispatch(
+    shared: &Arc<Shared>,
+    frame: Frame,
+    tx_open: &mpsc::Sender<(TargetAddr, MuxStream)>,
+) {
+    match frame {
+        Frame::Open {
+            stream_id,
+            target,
+            priority,
+        } => {
+            let (valid, refused) = {
+                let mut goaway = shared.goaway.lock().unwrap();
+                // Peer ids only go up, so a reused id is always smaller.
+                let valid = shared.is_peer_stream(stream_id) && stream_id > goaway.last_peer_stream;
+                if valid && !goaway.sent {
+                    goaway.last_peer_stream = stream_id;
+                }
+                (valid, goaway.sent)
+            };
+            if !valid {
+                warn!(stream_id, "peer opened a stream with an invalid id");
+                let _ = shared
+                    .sched
+                    .send_control(Frame::OpenErr {
+                        stream_id,
+                        code: OPEN_BAD_ID,
+                        message: "invalid stream id".to_string(),
+                    })
+                    .await;
+                return;
+            }
+            if refused {
+                debug!(stream_id, "refusing stream after goaway");
+                let _ = shared
+                    .sched
+                    .send_control(Frame::OpenErr {
+                        stream_id,
+                        code: OPEN_REFUSED,
+                        message: "session is going away".to_string(),
+                    })
+                    .await;
+                return;
+            }
+            let stream = shared.register_stream(stream_id, priority).await;
+            if tx_open.send((target, stream)).await.is_err() {
+                debug!(stream_id, "nobody is accepting streams");
+            }
+        }
+        Frame::OpenOk { stream_id } => {
+            let pending = shared.pending.lock().await.remove(&stream_id);
+            // Nobody is waiting any more: the opener gave up.
+            if pending.is_none_or(|tx| tx.send(Ok(())).is_err()) {
+                debug!(stream_id, "open ok for an abandoned stream, resetting");
+                shared
+                    .abort_stream(stream_id, StreamError::LocalReset(RST_CANCELLED))
+                    .await;
+                let _ = shared
+                    .sched
+                    .send_control(Frame::Rst {
+                        stream_id,
+                        code: RST_CANCELLED,
+                    })
+                    .await;
+            }
+        }
+        Frame::OpenErr {
+            stream_id, message, ..
+        } => {
+            if let Some(tx) = shared.pending.lock().await.remove(&stream_id) {
+                let _ = tx.send(Err(BtProxyError::Protocol(message)));
+            }
+        }
+        Frame::Data { stream_id, payload } => {
+            if let Some(entry) = shared.streams.lock().await.get_mut(&stream_id) {
+                if let Some(decompressor) = &mut entry.decompressor {
+                    decompressor.reset();
+                }
+            }
+            shared
+                .compression
+                .count_received(payload.len(), payload.len());
+            deliver(shared, stream_id, payload).await;
+        }
+        Frame::CompressedData {
+            stream_id,
+            len,
+            payload,
+        } => {
+            let plain = match shared.streams.lock().await.get_mut(&stream_id) {
+                Some(entry) => match &mut entry.decompressor {
+                    Some(decompressor) => decompressor.decompress(&payload, len as usize),
+                    None => Err(BtProxyError::Protocol(
+                        "compressed data without compression".to_string(),
+                    )),
+                },
+                None => return,
+            };
+            match plain {
+                Ok(plain) => {
+                    shared
+                        .compression
+                        .count_received(plain.len(), payload.len());
+                    deliver(shared, stream_id, plain).await;
+                }
+                Err(err) => {
+                    warn!(stream_id, %err, "bad compressed data, resetting");
+                    reset_stream(shared, stream_id, RST_BAD_DATA).await;
+                }
+            }
+        }
+        Frame::Fin { stream_id } => {
+            // Our side may still be sending and needs the peer's updates.
+            let mut streams = shared.streams.lock().await;
+            if let Some(entry) = streams.get_mut(&stream_id) {
+                // Recorded before the sender drops so readers see the FIN.
+                let done = entry.status.close_remote();
+                entry.tx = None;
+                if done {
+                    streams.remove(&stream_id);
+                }
+            }
+        }
+        Frame::Rst { stream_id, code } => {
+            shared.sched.discard(stream_id);
+            shared
+                .abort_stream(stream_id, StreamError::Reset(code))
+                .await;
+        }
+        Frame::WindowUpdate {
+            stream_id,
+            increment,
+        } => {
+            let flow = shared
+                .streams
+                .lock()
+                .await
+                .get(&stream_id)
+                .and_then(|entry| entry.flow.upgrade());
+            if let Some(flow) = flow {
+                if !flow.grant(increment) {
+                    warn!(
+                        stream_id,
+                        increment, "window update overflows the window, resetting"
+                    );
+                    reset_stream(shared, stream_id, RST_FLOW_CONTROL).await;
+                }
+            }
+        }
+        Frame::Ping { nonce } => {
+            let _ = shared.sched.send_control(Frame::Pong { nonce }).await;
+        }
+        Frame::GoAway {
+            last_stream_id,
+            code,
+        } => {
+            info!(last_stream_id, code, "peer is going away");
+            shared.set_end(SessionEnd::GoAway { code });
+            shared.going_away.send_replace(true);
+            // Streams above `last_stream_id` never reached the peer's
+            // application.
+            let mut pending = shared.pending.lock().await;
+            let refused: Vec<u32> = pending
+                .keys()
+                .copied()
+                .filter(|id| *id > last_stream_id)
+                .collect();
+            for stream_id in refused {
+                if let Some(tx) = pending.remove(&stream_id) {
+                    let _ = tx.send(Err(BtProxyError::Protocol("refused by goaway".to_string())));
+                }
+            }
+            drop(pending);
+            shared.finish_when_idle();
+        }
+        Frame::Pong { nonce } => {
+            if let Some(rtt) = shared.keepalive.pong(nonce) {
+                debug!(?rtt, "pong");
+            }
+        }
+        frame @ (Frame::Hello(_)
+        | Frame::HelloAck(_)
+        | Frame::Join { .. }
+        | Frame::Auth { .. }
+        | Frame::AuthKey { .. }
+        | Frame::Seq { .. }
+        | Frame::Ack { .. }) => {
+            debug!(?frame, "ignoring out-of-place frame");
+        }
+    }
+}
+
+/// Hands a DATA payload to its stream, enforcing the receive window.
+async fn deliver(shared: &Shared, stream_id: u32, payload: Bytes) {
+    let entry = shared
+        .streams
+        .lock()
+        .await
+        .get(&stream_id)
+        .and_then(|entry| Some((entry.tx.clone()?, entry.flow.upgrade()?)));
+    let Some((tx, flow)) = entry else {
+        return;
+    };
+    if !flow.negotiated() {
+        // The peer ignores windows, so hold up the session until the
+        // consumer catches up.
+        tokio::select! {
+            _ = flow.wait_room() => {}
+            _ = tx.closed() => return,
+        }
+    }
+    if !flow.receive(payload.len()) {
+        warn!(stream_id, "peer overran the stream window, resetting");
+        reset_stream(shared, stream_id, RST_FLOW_CONTROL).await;
+        return;
+    }
+    let _ = tx.send(payload);
+}
+
+async fn reset_stream(shared: &Shared, stream_id: u32, code: u16) {
+    shared
+        .abort_stream(stream_id, StreamError::LocalReset(code))
+        .await;
+    let _ = shared
+        .sched
+        .send_control(Frame::Rst { stream_id, code })
+        .await;
+}
+
+async fn write_loop(shared: Arc<Shared>, mut acks: mpsc::Receiver<Frame>) {
+    let mut changes = shared.group.subscribe();
+    let mut next_seq = 0u64;
+    loop {
+        let window_open = shared.bond.as_ref().is_none_or(Bond::window_open);
+        let retransmit_at = shared.bond.as_ref().and_then(Bond::retransmit_at);
+        tokio::select! {
+            biased;
+            _ = shared.group.closed() => break,
+            Some(frame) = acks.recv() => {
+                shared.transmit(&frame).await;
+            }
+            changed = changes.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                shared.retransmit_lost().await;
+            }
+            _ = async { sleep_until(retransmit_at.unwrap()).await }, if retransmit_at.is_some() => {
+                shared.retransmit_stale().await;
+            }
+            _ = async { shared.bond.as_ref().unwrap().acked.notified().await }, if !window_open => {}
+            frame = async {
+                shared.link_room().await;
+                shared.sched.next().await
+            }, if window_open => {
+                let Some(frame) = frame else {
+                    break;
+                };
+                // Whatever else is queued already rides along in the same
+                // chunk, so small frames do not cost a link write each.
+                let picked = shared.group.pick();
+                let mut batch = BytesMut::new();
+                let mut next = Some(frame);
+                while let Some(frame) = next.take() {
+                    let frame = shared.compress(frame).await;
+                    if let Frame::Fin { stream_id } = frame {
+                        shared.fin_sent(stream_id).await;
+                    }
+                    let frame = match &shared.bond {
+                        Some(bond) => {
+                            let frame = Frame::Seq {
+                                seq: next_seq,
+                                frame: Box::new(frame),
+                            };
+                            bond.unacked.lock().unwrap().push_back(Unacked {
+                                seq: next_seq,
+                                // Link 0 never exists, so an unsent frame counts as lost.
+                                link: picked.as_ref().map_or(0, |(id, _)| *id),
+                                frame: frame.clone(),
+                                sent_at: Instant::now(),
+                            });
+                            next_seq += 1;
+                            frame
+                        }
+                        None => frame,
+                    };
+                    let Some((link, _)) = &picked else {
+                        continue;
+                    };
+                    shared.encode(*link, &frame, &mut batch);
+                    if batch.len() < shared.cfg.write_batch
+                        && shared.bond.as_ref().is_none_or(Bond::window_open)
+                    {
+                        next = shared.sched.try_next();
+                    }
+                }
+                if let (Some((_, tx)), false) = (picked, batch.is_empty()) {
+                    let _ = tx.send(batch.freeze()).await;
+                }
+            }
+        }
+    }
+    shared.sched.close();
+}
+
+/// Pings the peer and closes every link once it stops answering, which
+/// fails the open streams and resolves `closed`.
+async fn watch_peer(shared: Arc<Shared>) {
+    let dead = keepalive_task(
+        Arc::clone(&shared.sched),
+        Arc::clone(&shared.keepalive),
+        shared.negotiated.keepalive_ms,
+        shared.cfg.max_missed_pongs,
+        shared.cfg.idle_timeout_ms,
+    )
+    .await;
+    if let Some(why) = dead {
+        warn!(%why, "peer is not responding, closing session");
+        shared.set_end(SessionEnd::Timeout);
+        shared.group.close_with(CloseReason::Timeout).await;
+    }
+}
+
+async fn ack_loop(shared: Arc<Shared>, control: mpsc::Sender<Frame>) {
+    let Some(bond) = &shared.bond else {
+        return;
+    };
+    let mut sent = 0;
+    loop {
+        tokio::select! {
+            _ = bond.ack_wanted.notified() => {}
+            _ = sleep(ACK_DELAY) => {}
+            _ = shared.group.closed() => break,
+        }
+        let next = bond.received.load(Ordering::Relaxed);
+        if next > sent || bond.ack_again.swap(false, Ordering::Relaxed) {
+            if control.send(Frame::Ack { next_seq: next }).await.is_err() {
+                break;
+            }
+            sent = next;
+        }
+    }
+}
+
+impl Shared {
+    async fn register_stream(&self, stream_id: u32, priority: Priority) -> MuxStream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let flow = Arc::new(StreamFlow::new(
+            self.cfg.initial_window,
+            self.peer_window,
+            max_data(self.negotiated.max_frame),
+        ));
+        let status = Arc::new(StreamStatus::new());
+        let mut streams = self.streams.lock().await;
+        streams.retain(|_, entry| entry.flow.strong_count() > 0);
+        streams.insert(
+            stream_id,
+            StreamEntry {
+                tx: Some(tx),
+                flow: Arc::downgrade(&flow),
+                status: Arc::clone(&status),
+                compressor: self
+                    .negotiated
+                    .compression
+                    .map(|alg| Arc::new(std::sync::Mutex::new(StreamCompressor::new(alg)))),
+                decompressor: self.negotiated.compression.map(StreamDecompressor::new),
+            },
+        );
+        MuxStream::new(
+            stream_id,
+            priority,
+            Arc::clone(&self.sched),
+            rx,
+            flow,
+            status,
+            &self.live,
+        )
+    }
+
+    /// Whether `stream_id` belongs to the peer's half of the id space.
+    fn is_peer_stream(&self, stream_id: u32) -> bool {
+        if stream_id == 0 {
+            return false;
+        }
+        if !self.peer_split_ids {
+            // Older clients count up from 1; older servers never open.
+            return matches!(self.role, Role::Server);
+        }
+        let even = stream_id.is_multiple_of(2);
+        match self.role {
+            Role::Client => even,
+            Role::Server => !even,
+        }
+    }
+
+    fn set_end(&self, end: SessionEnd) {
+        self.end.send_if_modified(|current| {
+            if current.is_none() {
+                *current = Some(end);
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Tells the peer to open no more streams. Only the first call sends
+    /// anything; peers that predate GOAWAY are just refused new streams.
+    async fn send_goaway(self: &Arc<Self>, code: u16) {
+        let last_stream_id = {
+            let mut goaway = self.goaway.lock().unwrap();
+            if goaway.sent {
+                return;
+            }
+            goaway.sent = true;
+            goaway.last_peer_stream
+        };
+        self.going_away.send_replace(true);
+        if self.peer_goaway {
+            let _ = self
+                .sched
+                .send_control(Frame::GoAway {
+                    last_stream_id,
+                    code,
+                })
+                .await;
+        }
+    }
+
+    /// Resolves once every queued frame has been handed to a transport.
+    async fn flush(&self) {
+        self.sched.flushed().await;
+        self.group.flushed().await;
+    }
+
+    /// Closes the session once the peer's GOAWAY has let the remaining
+    /// streams finish.
+    fn finish_when_idle(self: &Arc<Self>) {
+        {
+            let mut goaway = self.goaway.lock().unwrap();
+            if goaway.finishing {
+                return;
+            }
+            goaway.finishing = true;
+        }
+        let shared = Arc::clone(self);
+        tokio::spawn(
+            async move {
+                tokio::select! {
+                    _ = shared.live.idle() => {}
+                    _ = shared.group.closed() => return,
+                }
+                let _ = timeout(FLUSH_TIMEOUT, shared.flush()).await;
+                shared.group.close().await;
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Handles a frame the link corrupted beyond recovery. A bonded
+    /// session gets it again by retransmission. Otherwise there is no
+    /// telling which stream it belonged to, and any of them may now miss
+    /// DATA, a WINDOW_UPDATE or a FIN, so every stream and pending open is
+    /// reset rather than left with a silent gap.
+    async fn frame_lost(&self, link: LinkId) {
+        if self.bond.is_some() {
+            return;
+        }
+        let opens: Vec<_> = self.pending.lock().await.drain().collect();
+        let ids: Vec<u32> = self.streams.lock().await.keys().copied().collect();
+        warn!(
+            link,
+            streams = ids.len(),
+            "frame lost on the link, resetting streams"
+        );
+        for (_, tx) in opens {
+            let _ = tx.send(Err(BtProxyError::Protocol(
+                "frame lost on the link".to_string(),
+            )));
+        }
+        for stream_id in ids {
+            reset_stream(self, stream_id, RST_FRAME_LOST).await;
+        }
+    }
+
+    async fn remove_stream(&self, stream_id: u32) {
+        let entry = self.streams.lock().await.remove(&stream_id);
+        if let Some(flow) = entry.and_then(|entry| entry.flow.upgrade()) {
+            flow.close();
+        }
+    }
+
+    /// Ends both directions of a stream, failing its handles with `err`.
+    async fn abort_stream(&self, stream_id: u32, err: StreamError) {
+        let entry = self.streams.lock().await.remove(&stream_id);
+        if let Some(entry) = entry {
+            // Recorded before the sender drops so readers see the reset.
+            entry.status.reset(err);
+            if let Some(flow) = entry.flow.upgrade() {
+                flow.close();
+            }
+        }
+    }
+
+    /// Compresses DATA with its stream's context. Runs in the writer, so
+    /// the peer sees a stream's frames in the order they were compressed.
+    async fn compress(&self, frame: Frame) -> Frame {
+        let Frame::Data { stream_id, payload } = frame else {
+            return frame;
+        };
+        let compressor = match self.negotiated.compression {
+            Some(_) => self
+                .streams
+                .lock()
+                .await
+                .get(&stream_id)
+                .and_then(|entry| entry.compressor.clone()),
+            None => None,
+        };
+        let compressed = compressor.and_then(|c| c.lock().unwrap().compress(&payload));
+        match compressed {
+            Some(compressed) => {
+                self.compression.count_sent(payload.len(), compressed.len());
+                Frame::CompressedData {
+                    stream_id,
+                    len: payload.len() as u16,
+                    payload: Bytes::from(compressed),
+                }
+            }
+            None => {
+                self.compression.count_sent(payload.len(), payload.len());
+                Frame::Data { stream_id, payload }
+            }
+        }
+    }
+
+    /// Forgets a stream once the writer sent our FIN after the peer's.
+    async fn fin_sent(&self, stream_id: u32) {
+        let mut streams = self.streams.lock().await;
+        if streams
+            .get(&stream_id)
+            .is_some_and(|entry| entry.status.state() == StreamState::Closed)
+        {
+            streams.remove(&stream_id);
+        }
+    }
+
+    async fn send_on(&self, link: LinkId, tx: mpsc::Sender<Bytes>, frame: &Frame) -> bool {
+        let mut buf = BytesMut::new();
+        self.encode(link, frame, &mut buf) && tx.send(buf.freeze()).await.is_ok()
+    }
+
+    /// Appends `frame`, encoded with the codec of the link it goes out on,
+    /// to `dst`.
+    fn encode(&self, link: LinkId, frame: &Frame, dst: &mut BytesMut) -> bool {
+        let codec = self.codecs.lock().unwrap().get(&link).cloned();
+        let Some(codec) = codec else {
+            return false;
+        };
+        match codec.encode_into(frame, dst) {
+            Ok(()) => true,
+            Err(err) => {
+                debug!(?err, "encode error");
+                false
+            }
+        }
+    }
+
+    /// Waits until the link the next frame would go out on has no more than
+    /// `LINK_QUEUE` chunks waiting.
+    async fn link_room(&self) {
+        let Some((_, tx)) = self.group.pick() else {
+            return;
+        };
+        let wanted = tx.max_capacity().saturating_sub(LINK_QUEUE);
+        if wanted > 0 {
+            let _ = tx.reserve_many(wanted).await;
+        }
+    }
+
+    /// Sends `frame` on whichever link the group picks.
+    async fn transmit(&self, frame: &Frame) -> bool {
+        match self.group.pick() {
+            Some((link, tx)) => self.send_on(link, tx, frame).await,
+            None => false,
+        }
+    }
+
+    /// Sends unacked frames whose link has left the group again on a live
+    /// one. The receiver drops whatever turns out to be a duplicate.
+    async fn retransmit_lost(&self) {
+        let Some(bond) = &self.bond else {
+            return;
+        };
+        let lost: Vec<(u64, Frame)> = bond
+            .unacked
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| !self.group.contains(entry.link))
+            .map(|entry| (entry.seq, entry.frame.clone()))
+            .collect();
+        if lost.is_empty() {
+            return;
+        }
+        info!(
+            frames = lost.len(),
+            "retransmitting frames from a lost link"
+        );
+        for (seq, frame) in lost {
+            let Some((link, tx)) = self.group.pick() else {
+                return;
+            };
+            {
+                let mut unacked = bond.unacked.lock().unwrap();
+                let Some(first) = unacked.front().map(|entry| entry.seq) else {
+                    return;
+                };
+                match unacked.get_mut((seq.wrapping_sub(first)) as usize) {
+                    Some(entry) if entry.seq == seq => entry.link = link,
+                    // Acked while we were sending the ones before it.
+                    _ => continue,
+                }
+            }
+            self.send_on(link, tx, &frame).await;
+        }
+    }
+
+    /// Sends the oldest unacked frame again once it is overdue. Acks are
+    /// cumulative, so that is the one the peer is missing; if more are
+    /// gone, its next ack points at the next gap.
+    async fn retransmit_stale(&self) {
+        let Some(bond) = &self.bond else {
+            return;
+        };
+        // Without a link the timer just restarts; a joining link picks the
+        // frame up through `retransmit_lost`.
+        let picked = self.group.pick();
+        let frame = {
+            let mut unacked = bond.unacked.lock().unwrap();
+            match unacked.front_mut() {
+                Some(entry) if entry.sent_at.elapsed() >= RETRANSMIT_TIMEOUT => {
+                    entry.link = picked.as_ref().map_or(0, |(id, _)| *id);
+                    entry.sent_at = Instant::now();
+                    debug!(seq = entry.seq, "retransmitting unacked frame");
+                    entry.frame.clone()
+                }
+                _ => return,
+            }
+        };
+        if let Some((link, tx)) = picked {
+            self.send_on(link, tx, &frame).await;
+        }
+    }
+}
+
+impl Bond {
+    fn window_open(&self) -> bool {
+        self.unacked.lock().unwrap().len() < MAX_UNACKED
+    }
+
+    /// When the oldest unacked frame is due to be sent again.
+    fn retransmit_at(&self) -> Option<Instant> {
+        let unacked = self.unacked.lock().unwrap();
+        unacked
+            .front()
+            .map(|entry| entry.sent_at + RETRANSMIT_TIMEOUT)
+    }
+
+    fn release(&self, next_seq: u64) {
+        let mut unacked = self.unacked.lock().unwrap();
+        while unacked.front().is_some_and(|entry| entry.seq < next_seq) {
+            unacked.pop_front();
+        }
+        drop(unacked);
+        self.acked.notify_one();
+    }
+}
+
+async fn next_frame(
+    rx: &mut mpsc::Receiver<Bytes>,
+    buffer: &mut BytesMut,
+    codec: &mut FrameCodec,
+) -> Result<Frame> {
+    loop {
+        if let Some(frame) = codec.decode(buffer)? {
+            return Ok(frame);
+        }
+        let chunk = rx
+            .recv()
+            .await
+            .ok_or_else(|| BtProxyError::Protocol("handshake eof".to_string()))?;
+        append_chunk(buffer, chunk);
+    }
+}
+
+/// Takes a received chunk over without copying when nothing is buffered,
+/// which is the common case; decoded DATA then points into the chunk.
+fn append_chunk(buffer: &mut BytesMut, chunk: Bytes) {
+    if buffer.is_empty() {
+        *buffer = chunk.into();
+    } else {
+        buffer.extend_from_slice(&chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btlink::{spawn_async_io, BtLinkConfig};
+
+    fn link_pair() -> (BtLink, BtLink) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (a_read, a_write) = tokio::io::split(a);
+        let (b_read, b_write) = tokio::io::split(b);
+        (
+            spawn_async_io(a_read, a_write, BtLinkConfig::default()),
+            spawn_async_io(b_read, b_write, BtLinkConfig::default()),
+        )
+    }
+
+    fn target() -> TargetAddr {
+        TargetAddr::Domain("example.com".to_string(), 80)
+    }
+
+    #[tokio::test]
+    async fn running_out_of_ids_winds_the_session_down() {
+        let (a, b) = link_pair();
+        let (client, server) = tokio::join!(
+            MuxSession::start(a, MuxConfig::default(), Role::Client),
+            MuxSession::start(b, MuxConfig::default(), Role::Server),
+        );
+        let (client, server) = (client.unwrap(), server.unwrap());
+        *client.inner.next_stream_id.lock().await = Some(u32::MAX);
+
+        // The last odd id still works.
+        let accept = async {
+            let (_, stream) = server.accept_stream().await.unwrap();
+            server.send_open_ok(stream.stream_id).await.unwrap();
+            stream
+        };
+        let (opened, accepted) = tokio::join!(client.open_stream(target()), accept);
+        let opened = opened.unwrap();
+        assert_eq!(opened.stream_id, u32::MAX);
+        assert_eq!(accepted.stream_id, u32::MAX);
+
+        let exhausted = client.open_stream(target()).await;
+        assert!(matches!(exhausted, Err(BtProxyError::Protocol(_))));
+        timeout(Duration::from_secs(5), server.going_away())
+            .await
+            .expect("peer never heard the goaway");
+
+        // The session ends once the last stream is done.
+        drop((opened, accepted));
+        let end = timeout(Duration::from_secs(5), client.closed())
+            .await
+            .expect("session outlived its streams");
+        assert_eq!(
+            end,
+            SessionEnd::Local {
+                code: GOAWAY_NO_ERROR
+            }
+        );
+    }
+}
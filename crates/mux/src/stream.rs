@@ -1,47 +1,485 @@
-use crate::frame::Frame;
+use crate::flow::{CreditReserve, StreamFlow};
+use crate::frame::{Frame, Priority};
+use crate::sched::Scheduler;
 use bytes::Bytes;
+use common::error::BtProxyError;
+use std::fmt;
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, watch};
 
+/// Which directions of a stream are still open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    Open,
+    /// We sent FIN; the peer may still send.
+    LocalClosed,
+    /// The peer sent FIN; we may still send.
+    RemoteClosed,
+    /// Both sides sent FIN, or the stream was reset.
+    Closed,
+}
+
+/// Why a stream ended without a clean FIN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamError {
+    /// The peer sent RST with this code.
+    Reset(u16),
+    /// This side sent RST with this code.
+    LocalReset(u16),
+    /// The session ended first.
+    SessionClosed,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Reset(code) => write!(f, "stream reset by peer (code {})", code),
+            StreamError::LocalReset(code) => write!(f, "stream reset (code {})", code),
+            StreamError::SessionClosed => write!(f, "session closed"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<StreamError> for BtProxyError {
+    fn from(err: StreamError) -> Self {
+        BtProxyError::Protocol(err.to_string())
+    }
+}
+
+/// Half-close state of one stream, shared by its handles and the session.
+pub(crate) struct StreamStatus(std::sync::Mutex<Status>);
+
+struct Status {
+    state: StreamState,
+    error: Option<StreamError>,
+}
+
+impl StreamStatus {
+    pub(crate) fn new() -> Self {
+        Self(std::sync::Mutex::new(Status {
+            state: StreamState::Open,
+            error: None,
+        }))
+    }
+
+    pub(crate) fn state(&self) -> StreamState {
+        self.0.lock().unwrap().state
+    }
+
+    pub(crate) fn error(&self) -> Option<StreamError> {
+        self.0.lock().unwrap().error
+    }
+
+    /// Records our FIN. Returns false if this side had already stopped
+    /// sending.
+    fn close_local(&self) -> bool {
+        let mut status = self.0.lock().unwrap();
+        status.state = match status.state {
+            StreamState::Open => StreamState::LocalClosed,
+            StreamState::RemoteClosed => StreamState::Closed,
+            StreamState::LocalClosed | StreamState::Closed => return false,
+        };
+        true
+    }
+
+    /// Records the peer's FIN. Returns whether both directions are done.
+    pub(crate) fn close_remote(&self) -> bool {
+        let mut status = self.0.lock().unwrap();
+        status.state = match status.state {
+            StreamState::Open => StreamState::RemoteClosed,
+            _ => StreamState::Closed,
+        };
+        status.state == StreamState::Closed
+    }
+
+    /// Ends both directions. Reading still ends cleanly if the peer had
+    /// already sent FIN.
+    pub(crate) fn reset(&self, err: StreamError) {
+        let mut status = self.0.lock().unwrap();
+        if matches!(status.state, StreamState::Open | StreamState::LocalClosed) {
+            status.error.get_or_insert(err);
+        }
+        status.state = StreamState::Closed;
+    }
+}
+
+/// Counts the streams that still have a handle somewhere.
 #[derive(Clone)]
+pub(crate) struct LiveStreams(Arc<watch::Sender<usize>>);
+
+impl LiveStreams {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(watch::channel(0).0))
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        *self.0.borrow()
+    }
+
+    /// Resolves once no stream is left.
+    pub(crate) async fn idle(&self) {
+        let mut rx = self.0.subscribe();
+        let _ = rx.wait_for(|count| *count == 0).await;
+    }
+
+    fn enter(&self) -> LiveStream {
+        self.0.send_modify(|count| *count += 1);
+        LiveStream(self.clone())
+    }
+}
+
+/// Keeps its stream counted until the last handle is dropped.
+struct LiveStream(LiveStreams);
+
+impl Drop for LiveStream {
+    fn drop(&mut self) {
+        self.0 .0.send_modify(|count| *count -= 1);
+    }
+}
+
+/// One multiplexed stream. Read and write it with tokio's `AsyncRead` and
+/// `AsyncWrite`, or frame by frame with `recv_data` and `send_data`;
+/// `poll_shutdown` and `send_fin` close our sending side. `into_split`
+/// hands out owned halves for separate tasks.
 pub struct MuxStream {
     pub stream_id: u32,
-    outbound: mpsc::Sender<Frame>,
-    inbound: Arc<Mutex<mpsc::Receiver<Bytes>>>,
+    read: MuxReadHalf,
+    write: MuxWriteHalf,
+}
+
+/// Receiving side of a `MuxStream`.
+pub struct MuxReadHalf {
+    stream_id: u32,
+    inbound: mpsc::UnboundedReceiver<Bytes>,
+    /// Rest of a chunk that did not fit the caller's buffer.
+    leftover: Bytes,
+    outbound: Arc<Scheduler>,
+    flow: Arc<StreamFlow>,
+    status: Arc<StreamStatus>,
+    _live: Arc<LiveStream>,
+}
+
+/// Sending side of a `MuxStream`.
+pub struct MuxWriteHalf {
+    sender: Sender,
+    /// Credit `poll_write` took before the stream's queue had room.
+    credit: CreditReserve,
+    _live: Arc<LiveStream>,
+}
+
+struct Sender {
+    stream_id: u32,
+    priority: Priority,
+    outbound: Arc<Scheduler>,
+    flow: Arc<StreamFlow>,
+    status: Arc<StreamStatus>,
 }
 
 impl MuxStream {
-    pub fn new(
+    pub(crate) fn new(
         stream_id: u32,
-        outbound: mpsc::Sender<Frame>,
-        inbound: mpsc::Receiver<Bytes>,
+        priority: Priority,
+        outbound: Arc<Scheduler>,
+        inbound: mpsc::UnboundedReceiver<Bytes>,
+        flow: Arc<StreamFlow>,
+        status: Arc<StreamStatus>,
+        live: &LiveStreams,
     ) -> Self {
+        let live = Arc::new(live.enter());
+        outbound.register(stream_id);
         Self {
             stream_id,
-            outbound,
-            inbound: Arc::new(Mutex::new(inbound)),
+            read: MuxReadHalf {
+                stream_id,
+                inbound,
+                leftover: Bytes::new(),
+                outbound: Arc::clone(&outbound),
+                flow: Arc::clone(&flow),
+                status: Arc::clone(&status),
+                _live: Arc::clone(&live),
+            },
+            write: MuxWriteHalf {
+                credit: flow.reserve_credit(),
+                sender: Sender {
+                    stream_id,
+                    priority,
+                    outbound,
+                    flow,
+                    status,
+                },
+                _live: live,
+            },
         }
     }
 
+    pub fn priority(&self) -> Priority {
+        self.write.sender.priority
+    }
+
+    pub fn state(&self) -> StreamState {
+        self.write.sender.status.state()
+    }
+
+    /// Splits the stream into halves that can be moved to different tasks.
+    /// The stream stays counted as open until both are dropped.
+    pub fn into_split(self) -> (MuxReadHalf, MuxWriteHalf) {
+        (self.read, self.write)
+    }
+
+    /// See [`MuxWriteHalf::send_data`].
     pub async fn send_data(&self, data: Bytes) -> Result<(), mpsc::error::SendError<Frame>> {
-        self.outbound
-            .send(Frame::Data {
+        self.write.send_data(data).await
+    }
+
+    /// See [`MuxWriteHalf::send_fin`].
+    pub async fn send_fin(&self) -> Result<(), mpsc::error::SendError<Frame>> {
+        self.write.send_fin().await
+    }
+
+    /// See [`MuxReadHalf::recv_data`].
+    pub async fn recv_data(&mut self) -> Result<Option<Bytes>, StreamError> {
+        self.read.recv_data().await
+    }
+}
+
+impl MuxReadHalf {
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// Returns the next chunk from the peer, `Ok(None)` once it has sent
+    /// FIN, or why the stream ended otherwise. Data still buffered when
+    /// the stream is reset is dropped.
+    pub async fn recv_data(&mut self) -> Result<Option<Bytes>, StreamError> {
+        if !self.leftover.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.leftover)));
+        }
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, StreamError>> {
+        if let Some(err) = self.status.error() {
+            return Poll::Ready(Err(err));
+        }
+        let Some(chunk) = ready!(self.inbound.poll_recv(cx)) else {
+            return Poll::Ready(self.status.error().map_or(Ok(None), Err));
+        };
+        if let Some(increment) = self.flow.consume(chunk.len()) {
+            let _ = self.outbound.send_control_now(Frame::WindowUpdate {
                 stream_id: self.stream_id,
-                payload: data,
-            })
-            .await
+                increment,
+            });
+        }
+        Poll::Ready(Ok(Some(chunk)))
+    }
+}
+
+impl MuxWriteHalf {
+    pub fn stream_id(&self) -> u32 {
+        self.sender.stream_id
     }
 
+    /// Sends `data`, split into frames the peer's window can take, waiting
+    /// for credit as needed. Fails once this side has sent FIN or the
+    /// stream was reset.
+    pub async fn send_data(&self, data: Bytes) -> Result<(), mpsc::error::SendError<Frame>> {
+        self.sender.send_data(data).await
+    }
+
+    /// Closes our sending side. The peer can keep sending until it sends
+    /// FIN too.
     pub async fn send_fin(&self) -> Result<(), mpsc::error::SendError<Frame>> {
-        self.outbound
-            .send(Frame::Fin {
+        self.sender.send_fin().await
+    }
+}
+
+impl Sender {
+    async fn send_data(&self, mut data: Bytes) -> Result<(), mpsc::error::SendError<Frame>> {
+        while !data.is_empty() {
+            if matches!(
+                self.status.state(),
+                StreamState::LocalClosed | StreamState::Closed
+            ) {
+                return Err(mpsc::error::SendError(Frame::Data {
+                    stream_id: self.stream_id,
+                    payload: data,
+                }));
+            }
+            let len = data.len().min(self.flow.max_chunk());
+            let ready = self.flow.reserve(len).await;
+            let frame = Frame::Data {
                 stream_id: self.stream_id,
-            })
+                payload: data.split_to(len),
+            };
+            if !ready {
+                return Err(mpsc::error::SendError(frame));
+            }
+            self.outbound
+                .send_stream(self.stream_id, self.priority, frame)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn send_fin(&self) -> Result<(), mpsc::error::SendError<Frame>> {
+        let frame = Frame::Fin {
+            stream_id: self.stream_id,
+        };
+        if !self.status.close_local() {
+            return Err(mpsc::error::SendError(frame));
+        }
+        self.outbound
+            .send_stream(self.stream_id, self.priority, frame)
             .await
     }
 
-    pub async fn recv_data(&self) -> Option<Bytes> {
-        let mut rx = self.inbound.lock().await;
-        rx.recv().await
+    /// Why sending failed, for the `AsyncWrite` side.
+    fn io_error(&self) -> io::Error {
+        match self.status.error() {
+            Some(err) => err.into(),
+            None => io::Error::new(io::ErrorKind::BrokenPipe, "stream closed for sending"),
+        }
+    }
+}
+
+impl From<StreamError> for io::Error {
+    fn from(err: StreamError) -> Self {
+        let kind = match err {
+            StreamError::Reset(_) => io::ErrorKind::ConnectionReset,
+            StreamError::LocalReset(_) | StreamError::SessionClosed => {
+                io::ErrorKind::ConnectionAborted
+            }
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl AsyncRead for MuxReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.leftover.is_empty() {
+            match ready!(this.poll_recv(cx)) {
+                Ok(Some(chunk)) => this.leftover = chunk,
+                Ok(None) => return Poll::Ready(Ok(())),
+                Err(err) => return Poll::Ready(Err(err.into())),
+            }
+        }
+        let len = buf.remaining().min(this.leftover.len());
+        buf.put_slice(&this.leftover.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxWriteHalf {
+    fn drop(&mut self) {
+        self.sender.outbound.unregister(self.sender.stream_id);
+    }
+}
+
+/// A write first waits for credit and for room in the stream's queue, and
+/// only then copies bytes into a DATA frame, so a write that returns
+/// `Pending` has taken nothing. It may take less than `buf` when credit is
+/// short.
+impl AsyncWrite for MuxWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let sender = &this.sender;
+        if matches!(
+            sender.status.state(),
+            StreamState::LocalClosed | StreamState::Closed
+        ) {
+            return Poll::Ready(Err(sender.io_error()));
+        }
+        let want = buf.len().min(sender.flow.max_chunk());
+        let Some(len) = ready!(this.credit.poll_reserve(cx, want)) else {
+            return Poll::Ready(Err(sender.io_error()));
+        };
+        let queued =
+            sender
+                .outbound
+                .poll_send_stream(cx, sender.stream_id, sender.priority, || Frame::Data {
+                    stream_id: sender.stream_id,
+                    payload: Bytes::copy_from_slice(&buf[..len]),
+                });
+        if ready!(queued).is_err() {
+            return Poll::Ready(Err(sender.io_error()));
+        }
+        this.credit.spend(len);
+        Poll::Ready(Ok(len))
+    }
+
+    /// Written data is queued in the session already.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let sender = &self.get_mut().sender;
+        if matches!(
+            sender.status.state(),
+            StreamState::LocalClosed | StreamState::Closed
+        ) {
+            return Poll::Ready(Ok(()));
+        }
+        let queued =
+            sender
+                .outbound
+                .poll_send_stream(cx, sender.stream_id, sender.priority, || {
+                    // Before the writer can take the FIN and look at the state.
+                    sender.status.close_local();
+                    Frame::Fin {
+                        stream_id: sender.stream_id,
+                    }
+                });
+        if ready!(queued).is_err() {
+            return Poll::Ready(Err(sender.io_error()));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().write).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().write).poll_shutdown(cx)
     }
 }
@@ -0,0 +1,171 @@
+mod support;
+
+use btlink::{emulated_pair, BtLink, BtLinkConfig, EmulatorConfig};
+use bytes::{Bytes, BytesMut};
+use mux::{Incoming, MuxConfig, MuxSession, TargetAddr};
+use std::time::Duration;
+use support::{config, link_pair, pattern, session_pair};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+
+const TRANSFER: usize = 512 * 1024;
+
+fn bond_config() -> MuxConfig {
+    MuxConfig {
+        bond: true,
+        ..config(None)
+    }
+}
+
+/// Starts a bonded session on `first` and joins `second` to it.
+async fn bonded(first: (BtLink, BtLink), second: (BtLink, BtLink)) -> (MuxSession, MuxSession) {
+    let (client, server) = session_pair(first.0, first.1, bond_config()).await;
+    let (joined, incoming) = tokio::join!(
+        client.join(second.0),
+        MuxSession::accept(second.1, bond_config())
+    );
+    joined.unwrap();
+    let Incoming::Join { token, link } = incoming.unwrap() else {
+        panic!("expected join");
+    };
+    assert_eq!(Some(token), server.bond_token());
+    server.attach(*link).unwrap();
+    assert_eq!(client.stats().links, 2);
+    assert_eq!(server.stats().links, 2);
+    (client, server)
+}
+
+/// A link pair whose bytes pass a relay that flips a bit at each of the
+/// given offsets, counted separately for each direction.
+fn noisy_pair(forward: &'static [u64], back: &'static [u64]) -> (BtLink, BtLink) {
+    let (a, x) = link_pair();
+    let (y, b) = link_pair();
+    tokio::spawn(flip_bits(x.rx, y.tx, forward));
+    tokio::spawn(flip_bits(y.rx, x.tx, back));
+    (a, b)
+}
+
+async fn flip_bits(mut rx: mpsc::Receiver<Bytes>, tx: mpsc::Sender<Bytes>, offsets: &[u64]) {
+    let mut seen = 0;
+    while let Some(chunk) = rx.recv().await {
+        let mut chunk = BytesMut::from(&chunk[..]);
+        let end = seen + chunk.len() as u64;
+        for &at in offsets.iter().filter(|&&at| (seen..end).contains(&at)) {
+            chunk[(at - seen) as usize] ^= 0x10;
+        }
+        seen = end;
+        if tx.send(chunk.freeze()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Streams `TRANSFER` bytes from client to server, calling `midway` once a
+/// quarter of them has arrived, and returns what the server read.
+async fn transfer(
+    client: &MuxSession,
+    server: MuxSession,
+    midway: impl FnOnce() + Send + 'static,
+) -> Vec<u8> {
+    let sink = tokio::spawn(async move {
+        let (_, mut stream) = server.accept_stream().await.unwrap();
+        server.send_open_ok(stream.stream_id).await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = vec![0; 8192];
+        let mut midway = Some(midway);
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+            if received.len() >= TRANSFER / 4 {
+                if let Some(midway) = midway.take() {
+                    midway();
+                }
+            }
+        }
+        (received, server)
+    });
+    let mut stream = client
+        .open_stream(TargetAddr::Domain("example.com".to_string(), 80))
+        .await
+        .unwrap();
+    for chunk in pattern(TRANSFER).chunks(4096) {
+        stream.write_all(chunk).await.unwrap();
+    }
+    stream.shutdown().await.unwrap();
+    let (received, server) = timeout(Duration::from_secs(20), sink)
+        .await
+        .expect("transfer stalled")
+        .unwrap();
+    assert_eq!(server.stats().links, 1);
+    received
+}
+
+#[tokio::test]
+async fn dropping_a_link_mid_transfer_loses_nothing() {
+    let (a, b) = link_pair();
+    let (c, d) = link_pair();
+    // Both ends at once, so whatever is in flight on it is lost.
+    let ends = (c.handle.clone(), d.handle.clone());
+    let (client, server) = bonded((a, b), (c, d)).await;
+    let received = transfer(&client, server, move || {
+        tokio::spawn(async move { tokio::join!(ends.0.close(), ends.1.close()) });
+    })
+    .await;
+    assert_eq!(received.len(), TRANSFER);
+    assert!(received == pattern(TRANSFER), "data reordered or corrupted");
+    assert_eq!(client.stats().links, 1);
+}
+
+#[tokio::test]
+async fn emulated_link_cut_mid_transfer_loses_nothing() {
+    let emu = EmulatorConfig {
+        latency: Duration::from_millis(2),
+        jitter: Duration::from_millis(3),
+        max_fragment: Some(300),
+        seed: 10,
+        ..EmulatorConfig::default()
+    };
+    let steady = emulated_pair(emu.clone(), BtLinkConfig::default());
+    let flaky = emulated_pair(
+        EmulatorConfig {
+            disconnect_after_bytes: Some(96 * 1024),
+            ..emu
+        },
+        BtLinkConfig::default(),
+    );
+    let (client, server) = bonded(steady, flaky).await;
+    let received = transfer(&client, server, || {}).await;
+    assert_eq!(received.len(), TRANSFER);
+    assert!(received == pattern(TRANSFER), "data reordered or corrupted");
+    // The client notices the reset too.
+    timeout(Duration::from_secs(5), async {
+        while client.stats().links > 1 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn frames_corrupted_on_a_live_link_are_retransmitted() {
+    // Data frames on the way in, an ack on the way back.
+    let (a, b) = noisy_pair(&[40_000, 150_000, 300_000], &[250]);
+    let cfg = MuxConfig {
+        checksum: true,
+        ..bond_config()
+    };
+    let (client, server) = session_pair(a, b, cfg).await;
+    let server_stats = server.clone();
+    let received = transfer(&client, server, || {}).await;
+    assert!(
+        received == pattern(TRANSFER),
+        "data lost, reordered or corrupted"
+    );
+    assert!(server_stats.stats().frame_errors >= 3);
+    assert!(client.stats().frame_errors >= 1);
+}
@@ -0,0 +1,333 @@
+use bytes::{Bytes, BytesMut};
+use common::error::BtProxyError;
+use mux::codec::{FrameCodec, SYNC};
+use mux::{Frame, HelloFrame, Priority, TargetAddr};
+use tokio_util::codec::{Decoder, Encoder};
+
+fn codec(checksum: bool) -> FrameCodec {
+    let mut codec = FrameCodec::new(65536);
+    codec.set_checksum(checksum);
+    codec
+}
+
+fn frames() -> Vec<Frame> {
+    vec![
+        Frame::Open {
+            stream_id: 1,
+            target: TargetAddr::Domain("example.com".to_string(), 443),
+            priority: Priority::Interactive,
+        },
+        Frame::Data {
+            stream_id: 1,
+            payload: Bytes::from((0..3000).map(|i| i as u8).collect::<Vec<_>>()),
+        },
+        Frame::CompressedData {
+            stream_id: 1,
+            len: 100,
+            payload: Bytes::from_static(b"not really compressed"),
+        },
+        Frame::Seq {
+            seq: 7,
+            frame: Box::new(Frame::Data {
+                stream_id: 3,
+                payload: Bytes::from_static(b"sequenced"),
+            }),
+        },
+        Frame::WindowUpdate {
+            stream_id: 1,
+            increment: 65536,
+        },
+        Frame::Ping { nonce: 42 },
+        Frame::Fin { stream_id: 1 },
+    ]
+}
+
+#[test]
+fn frames_survive_any_chunking() {
+    for checksum in [false, true] {
+        let mut wire = BytesMut::new();
+        let mut encoder = codec(checksum);
+        for frame in frames() {
+            encoder.encode(&frame, &mut wire).unwrap();
+        }
+        for chunk_len in [1, 7, 1000, wire.len()] {
+            let mut decoder = codec(checksum);
+            let mut buffer = BytesMut::new();
+            let mut decoded = Vec::new();
+            for chunk in wire.chunks(chunk_len) {
+                buffer.extend_from_slice(chunk);
+                while let Some(frame) = decoder.decode(&mut buffer).unwrap() {
+                    decoded.push(format!("{:?}", frame));
+                }
+            }
+            let expected: Vec<_> = frames().iter().map(|f| format!("{:?}", f)).collect();
+            assert_eq!(
+                decoded, expected,
+                "checksum {}, chunks of {}",
+                checksum, chunk_len
+            );
+            assert!(buffer.is_empty());
+            assert_eq!(decoder.errors(), 0);
+        }
+    }
+}
+
+#[test]
+fn data_payload_points_into_the_receive_buffer() {
+    for checksum in [false, true] {
+        let mut buffer = BytesMut::new();
+        codec(checksum)
+            .encode(
+                Frame::Data {
+                    stream_id: 1,
+                    payload: Bytes::from(vec![0x5a; 4096]),
+                },
+                &mut buffer,
+            )
+            .unwrap();
+        let received = buffer.as_ptr_range();
+        let Some(Frame::Data { payload, .. }) = codec(checksum).decode(&mut buffer).unwrap() else {
+            panic!("expected data");
+        };
+        assert_eq!(payload.len(), 4096);
+        assert!(received.contains(&payload.as_ptr()));
+    }
+}
+
+#[test]
+fn truncated_data_is_an_error() {
+    // DATA claiming 100 bytes but carrying 3.
+    let mut buffer = BytesMut::from(&[0, 0, 0, 10, 0x20, 0, 0, 0, 1, 0, 100, 1, 2, 3][..]);
+    assert!(codec(false).decode(&mut buffer).is_err());
+}
+
+/// One frame of every type, with the shortest payload it decodes from.
+fn every_frame() -> Vec<(Frame, usize)> {
+    let hello = HelloFrame {
+        version: 1,
+        flags: 0,
+        max_frame: 65536,
+        keepalive_ms: 15000,
+        nonce: 9,
+        hmac: None,
+        initial_window: None,
+        key_salt: None,
+        kex: None,
+        compression: Vec::new(),
+    };
+    vec![
+        (Frame::Hello(Box::new(hello.clone())), 20),
+        (Frame::HelloAck(Box::new(hello)), 20),
+        (Frame::Join { token: 9 }, 8),
+        (Frame::Auth { proof: [1; 32] }, 32),
+        (
+            Frame::AuthKey {
+                public_key: [2; 32],
+                signature: [3; 64],
+            },
+            96,
+        ),
+        (
+            Frame::Open {
+                stream_id: 1,
+                target: TargetAddr::Domain("example.com".to_string(), 443),
+                priority: Priority::Bulk,
+            },
+            // The priority byte is optional.
+            20,
+        ),
+        (
+            Frame::Open {
+                stream_id: 1,
+                target: TargetAddr::IpV4([127, 0, 0, 1], 80),
+                priority: Priority::Normal,
+            },
+            11,
+        ),
+        (
+            Frame::Open {
+                stream_id: 1,
+                target: TargetAddr::IpV6([1; 16], 80),
+                priority: Priority::Normal,
+            },
+            23,
+        ),
+        (Frame::OpenOk { stream_id: 1 }, 4),
+        (
+            Frame::OpenErr {
+                stream_id: 1,
+                code: 503,
+                message: "refused".to_string(),
+            },
+            15,
+        ),
+        (
+            Frame::Data {
+                stream_id: 1,
+                payload: Bytes::from_static(b"hello"),
+            },
+            11,
+        ),
+        (
+            Frame::CompressedData {
+                stream_id: 1,
+                len: 5,
+                payload: Bytes::from_static(b"hello"),
+            },
+            6,
+        ),
+        (Frame::Fin { stream_id: 1 }, 4),
+        (
+            Frame::Rst {
+                stream_id: 1,
+                code: 499,
+            },
+            6,
+        ),
+        (
+            Frame::WindowUpdate {
+                stream_id: 1,
+                increment: 4096,
+            },
+            8,
+        ),
+        (Frame::Ping { nonce: 7 }, 8),
+        (Frame::Pong { nonce: 7 }, 8),
+        (
+            Frame::GoAway {
+                last_stream_id: 5,
+                code: 2,
+            },
+            6,
+        ),
+        (
+            Frame::Seq {
+                seq: 3,
+                frame: Box::new(Frame::Rst {
+                    stream_id: 1,
+                    code: 499,
+                }),
+            },
+            15,
+        ),
+        (Frame::Ack { next_seq: 3 }, 8),
+    ]
+}
+
+#[test]
+fn truncated_payloads_are_errors_for_every_frame_type() {
+    for (frame, min_len) in every_frame() {
+        let encoded = frame.encode().unwrap();
+        let (frame_type, payload) = (encoded[4], encoded.slice(5..));
+        assert!(payload.len() >= min_len, "{:?}", frame);
+        for cut in 0..=payload.len() {
+            match Frame::decode(frame_type, payload.slice(..cut)) {
+                Ok(_) if cut >= min_len => {}
+                Err(BtProxyError::Protocol(_)) if cut < min_len => {}
+                other => panic!("{:?} cut to {} bytes: {:?}", frame, cut, other),
+            }
+        }
+    }
+}
+
+#[test]
+fn truncated_frame_on_the_wire_is_an_error() {
+    // FIN with no stream id.
+    let mut buffer = BytesMut::from(&[0, 0, 0, 1, 0x21][..]);
+    assert!(matches!(
+        codec(false).decode(&mut buffer),
+        Err(BtProxyError::Protocol(_))
+    ));
+    // The same inside a sequenced frame.
+    let mut buffer = BytesMut::from(&[0, 0, 0, 10, 0x40, 0, 0, 0, 0, 0, 0, 0, 1, 0x22][..]);
+    assert!(matches!(
+        codec(false).decode(&mut buffer),
+        Err(BtProxyError::Protocol(_))
+    ));
+}
+
+/// Checksummed frames, each encoded on its own so tests can corrupt one.
+fn checked_frames() -> Vec<BytesMut> {
+    frames()
+        .iter()
+        .map(|frame| {
+            let mut wire = BytesMut::new();
+            codec(true).encode(frame, &mut wire).unwrap();
+            wire
+        })
+        .collect()
+}
+
+/// Decodes `wire` fed in `chunk_len` pieces, returning the frames and the
+/// error count.
+fn decode_chunked(wire: &[u8], chunk_len: usize) -> (Vec<String>, u64) {
+    let mut decoder = codec(true);
+    let mut buffer = BytesMut::new();
+    let mut decoded = Vec::new();
+    for chunk in wire.chunks(chunk_len) {
+        buffer.extend_from_slice(chunk);
+        while let Some(frame) = decoder.decode(&mut buffer).unwrap() {
+            decoded.push(format!("{:?}", frame));
+        }
+    }
+    (decoded, decoder.errors())
+}
+
+#[test]
+fn corrupt_frame_is_dropped_and_the_next_one_decodes() {
+    let expected: Vec<_> = frames().iter().map(|f| format!("{:?}", f)).collect();
+    let victim = 1;
+    let len = checked_frames()[victim].len();
+    // Offsets into the victim: the length field, its header check, the
+    // frame type, the payload and the trailing CRC.
+    for offset in [3, 6, 8, 20, len - 1] {
+        let mut frames = checked_frames();
+        frames[victim][offset] ^= 0x40;
+        let wire: Vec<u8> = frames.concat();
+        for chunk_len in [1, 13, wire.len()] {
+            let (decoded, errors) = decode_chunked(&wire, chunk_len);
+            let mut survivors = expected.clone();
+            survivors.remove(victim);
+            assert_eq!(
+                decoded, survivors,
+                "offset {}, chunks of {}",
+                offset, chunk_len
+            );
+            assert_eq!(errors, 1, "offset {}, chunks of {}", offset, chunk_len);
+        }
+    }
+}
+
+#[test]
+fn garbage_between_frames_is_skipped() {
+    let expected: Vec<_> = frames().iter().map(|f| format!("{:?}", f)).collect();
+    let garbage: [&[u8]; 3] = [
+        b"noise",
+        // A stray marker followed by a header that does not check out.
+        &[SYNC[0], SYNC[1], 0, 0, 0, 9, 0xde, 0xad, 1, 2, 3],
+        // Half a marker right before the real one.
+        &[7, SYNC[0]],
+    ];
+    for junk in garbage {
+        let mut frames = checked_frames();
+        frames[2] = [junk, &frames[2][..]].concat()[..].into();
+        let wire: Vec<u8> = frames.concat();
+        for chunk_len in [1, 13, wire.len()] {
+            let (decoded, errors) = decode_chunked(&wire, chunk_len);
+            assert_eq!(decoded, expected, "{:?}, chunks of {}", junk, chunk_len);
+            assert_eq!(errors, 1, "{:?}, chunks of {}", junk, chunk_len);
+        }
+    }
+}
+
+#[test]
+fn separate_corruptions_count_separately() {
+    let mut frames = checked_frames();
+    let last = frames.len() - 1;
+    frames[1][10] ^= 1;
+    frames[3][10] ^= 1;
+    frames[last] = [&b"junk"[..], &frames[last][..]].concat()[..].into();
+    let (decoded, errors) = decode_chunked(&frames.concat(), 5);
+    assert_eq!(decoded.len(), frames.len() - 2);
+    assert_eq!(errors, 3);
+}
@@ -0,0 +1,156 @@
+mod support;
+
+use mux::{Compression, MuxConfig, MuxSession, Role, TargetAddr, FLAG_COMPRESS};
+use rand::RngCore;
+use std::time::Duration;
+use support::{config, link_pair, PSK};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+fn compressing(compression: &[Compression], psk: Option<&[u8]>) -> MuxConfig {
+    MuxConfig {
+        compression: compression.to_vec(),
+        ..config(psk)
+    }
+}
+
+async fn session_pair(client: MuxConfig, server: MuxConfig) -> (MuxSession, MuxSession) {
+    let (a, b) = link_pair();
+    let (client, server) = tokio::join!(
+        MuxSession::start(a, client, Role::Client),
+        MuxSession::start(b, server, Role::Server),
+    );
+    (client.unwrap(), server.unwrap())
+}
+
+/// Sends `data` through a stream the server echoes back.
+async fn echo(client: &MuxSession, server: &MuxSession, data: &[u8]) -> Vec<u8> {
+    let server = server.clone();
+    tokio::spawn(async move {
+        let (_, stream) = server.accept_stream().await.unwrap();
+        server.send_open_ok(stream.stream_id).await.unwrap();
+        let (mut read, mut write) = tokio::io::split(stream);
+        tokio::io::copy(&mut read, &mut write).await.unwrap();
+        write.shutdown().await.unwrap();
+    });
+    let stream = client
+        .open_stream(TargetAddr::Domain("example.com".to_string(), 80))
+        .await
+        .unwrap();
+    let (mut read, mut write) = tokio::io::split(stream);
+    let sent = data.to_vec();
+    let writer = tokio::spawn(async move {
+        write.write_all(&sent).await.unwrap();
+        write.shutdown().await.unwrap();
+    });
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(10), read.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    writer.await.unwrap();
+    received
+}
+
+fn text(len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0u32;
+    while out.len() < len {
+        out.extend_from_slice(
+            format!(
+                "{{\"id\":{},\"name\":\"item {}\",\"tags\":[\"a\",\"b\"]}}\n",
+                i,
+                i % 7
+            )
+            .as_bytes(),
+        );
+        i += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+fn random(len: usize) -> Vec<u8> {
+    let mut out = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut out);
+    out
+}
+
+#[tokio::test]
+async fn compressible_data_shrinks_on_the_wire() {
+    for alg in [Compression::Zstd, Compression::Deflate] {
+        for psk in [None, Some(PSK)] {
+            let (client, server) =
+                session_pair(compressing(&[alg], psk), compressing(&[alg], psk)).await;
+            assert_eq!(client.negotiated().compression, Some(alg));
+            let data = text(256 * 1024);
+            assert_eq!(echo(&client, &server, &data).await, data);
+
+            let stats = client.stats();
+            assert_eq!(stats.data_sent, data.len() as u64);
+            assert_eq!(stats.data_received, data.len() as u64);
+            assert!(
+                stats.data_sent_wire * 4 < stats.data_sent,
+                "{:?} sent {} bytes as {}",
+                alg,
+                stats.data_sent,
+                stats.data_sent_wire
+            );
+            assert!(stats.compression_ratio().unwrap() < 0.25);
+        }
+    }
+}
+
+#[tokio::test]
+async fn incompressible_data_goes_out_plain() {
+    for alg in [Compression::Zstd, Compression::Deflate] {
+        let (client, server) =
+            session_pair(compressing(&[alg], None), compressing(&[alg], None)).await;
+        // Random bytes around text, so contexts are dropped and restarted
+        // mid-stream.
+        let mut data = random(128 * 1024);
+        data.extend(text(128 * 1024));
+        data.extend(random(64 * 1024));
+        data.extend(text(512 * 1024));
+        assert_eq!(echo(&client, &server, &data).await, data);
+
+        let stats = client.stats();
+        assert!(stats.data_sent_wire < stats.data_sent);
+        // Each random block costs at most its own size.
+        assert!(stats.data_sent_wire > 192 * 1024);
+    }
+}
+
+#[tokio::test]
+async fn client_preference_picks_the_algorithm() {
+    let both = [Compression::Zstd, Compression::Deflate];
+    for (client, server, expected) in [
+        (
+            vec![Compression::Deflate, Compression::Zstd],
+            both.to_vec(),
+            Some(Compression::Deflate),
+        ),
+        (
+            both.to_vec(),
+            vec![Compression::Deflate],
+            Some(Compression::Deflate),
+        ),
+        (vec![Compression::Zstd], vec![Compression::Deflate], None),
+        (both.to_vec(), vec![], None),
+        (vec![], both.to_vec(), None),
+    ] {
+        let (client, server) =
+            session_pair(compressing(&client, None), compressing(&server, None)).await;
+        assert_eq!(client.negotiated().compression, expected);
+        assert_eq!(server.negotiated().compression, expected);
+        assert_eq!(client.negotiated().has(FLAG_COMPRESS), expected.is_some());
+
+        let data = text(64 * 1024);
+        assert_eq!(echo(&client, &server, &data).await, data);
+        let stats = server.stats();
+        assert_eq!(
+            stats.data_received_wire < stats.data_received,
+            expected.is_some()
+        );
+    }
+}
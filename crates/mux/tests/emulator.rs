@@ -0,0 +1,125 @@
+mod support;
+
+use btlink::{emulated_pair, BtLinkConfig, CloseReason, EmulatorConfig};
+use mux::{MuxConfig, MuxSession, SessionEnd, TargetAddr};
+use std::io;
+use std::time::Duration;
+use support::{config, pattern, session_pair};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// Echoes every stream the server accepts back to the client.
+fn echo(server: MuxSession) {
+    tokio::spawn(async move {
+        while let Some((_, stream)) = server.accept_stream().await {
+            server.send_open_ok(stream.stream_id).await.unwrap();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.into_split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+                let _ = write.shutdown().await;
+            });
+        }
+    });
+}
+
+#[tokio::test]
+async fn streams_survive_fragmentation_and_jitter() {
+    let emu = EmulatorConfig {
+        latency: Duration::from_millis(1),
+        jitter: Duration::from_millis(3),
+        max_fragment: Some(7),
+        seed: 4,
+        ..EmulatorConfig::default()
+    };
+    for checksum in [false, true] {
+        let (a, b) = emulated_pair(emu.clone(), BtLinkConfig::default());
+        let cfg = MuxConfig {
+            checksum,
+            ..config(None)
+        };
+        let (client, server) = session_pair(a, b, cfg).await;
+        echo(server);
+        let mut transfers = Vec::new();
+        for i in 0..4 {
+            let client = client.clone();
+            transfers.push(tokio::spawn(async move {
+                let stream = client
+                    .open_stream(TargetAddr::Domain("example.com".to_string(), 80))
+                    .await
+                    .unwrap();
+                let data = pattern(16 * 1024 + i * 1000);
+                let (mut read, mut write) = stream.into_split();
+                let sent = data.clone();
+                let writer = tokio::spawn(async move {
+                    write.write_all(&sent).await.unwrap();
+                    write.shutdown().await.unwrap();
+                });
+                let mut echoed = Vec::new();
+                read.read_to_end(&mut echoed).await.unwrap();
+                writer.await.unwrap();
+                assert!(echoed == data, "stream {} came back altered", i);
+            }));
+        }
+        for transfer in transfers {
+            timeout(Duration::from_secs(20), transfer)
+                .await
+                .expect("transfer stalled")
+                .unwrap();
+        }
+        assert_eq!(client.stats().frame_errors, 0);
+    }
+}
+
+#[tokio::test]
+async fn cut_link_ends_the_session_and_its_streams() {
+    let emu = EmulatorConfig {
+        max_fragment: Some(512),
+        disconnect_after_bytes: Some(64 * 1024),
+        seed: 5,
+        ..EmulatorConfig::default()
+    };
+    let (a, b) = emulated_pair(emu, BtLinkConfig::default());
+    let (client, server) = session_pair(a, b, config(None)).await;
+    let reader = {
+        let server = server.clone();
+        tokio::spawn(async move {
+            let (_, mut stream) = server.accept_stream().await.unwrap();
+            server.send_open_ok(stream.stream_id).await.unwrap();
+            let mut received = Vec::new();
+            let res = stream.read_to_end(&mut received).await;
+            (res, received.len())
+        })
+    };
+    let mut stream = client
+        .open_stream(TargetAddr::Domain("example.com".to_string(), 80))
+        .await
+        .unwrap();
+    let written = timeout(Duration::from_secs(10), async {
+        let mut written = 0;
+        for chunk in pattern(1024 * 1024).chunks(4096) {
+            if stream.write_all(chunk).await.is_err() {
+                break;
+            }
+            written += chunk.len();
+        }
+        written
+    })
+    .await
+    .expect("writer hung after the cut");
+    assert!(written < 1024 * 1024);
+
+    let (res, received) = timeout(Duration::from_secs(5), reader)
+        .await
+        .expect("reader hung after the cut")
+        .unwrap();
+    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+    assert!(received < 64 * 1024);
+    for session in [&client, &server] {
+        match timeout(Duration::from_secs(5), session.closed()).await {
+            Ok(SessionEnd::Link(CloseReason::Io { kind, .. })) => {
+                assert_eq!(kind, io::ErrorKind::ConnectionReset)
+            }
+            other => panic!("unexpected end: {:?}", other),
+        }
+    }
+}
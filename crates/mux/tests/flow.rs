@@ -0,0 +1,66 @@
+mod support;
+
+use mux::MuxConfig;
+use std::time::Duration;
+use support::{config, link_pair, open, pattern, session_pair};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{sleep, timeout};
+
+const WINDOW: usize = 16 * 1024;
+
+#[tokio::test]
+async fn stalled_reader_blocks_only_its_own_stream() {
+    let (a, b) = link_pair();
+    let cfg = MuxConfig {
+        initial_window: WINDOW as u32,
+        ..config(None)
+    };
+    let (client, server) = session_pair(a, b, cfg).await;
+
+    let (mut stalled, mut stalled_peer) = open(&client, &server).await;
+    let stalled_data = pattern(4 * WINDOW);
+    let writer = {
+        let data = stalled_data.clone();
+        tokio::spawn(async move {
+            stalled.write_all(&data).await.unwrap();
+            stalled.shutdown().await.unwrap();
+        })
+    };
+
+    // Nobody reads the first stream, so its sender stops after one window
+    // and waits for credit.
+    sleep(Duration::from_millis(200)).await;
+    assert!(!writer.is_finished(), "sender ignored the window");
+    assert_eq!(server.stats().data_received, WINDOW as u64);
+
+    // The second stream still moves far more than a window.
+    let (mut flowing, mut flowing_peer) = open(&client, &server).await;
+    let flowing_data = pattern(32 * WINDOW);
+    let received = timeout(Duration::from_secs(10), async {
+        let send = async {
+            flowing.write_all(&flowing_data).await.unwrap();
+            flowing.shutdown().await.unwrap();
+        };
+        let mut received = Vec::new();
+        let (_, res) = tokio::join!(send, flowing_peer.read_to_end(&mut received));
+        res.unwrap();
+        received
+    })
+    .await
+    .expect("second stream blocked behind the stalled one");
+    assert!(received == flowing_data);
+    assert!(!writer.is_finished());
+    assert_eq!(
+        server.stats().data_received,
+        (WINDOW + flowing_data.len()) as u64
+    );
+
+    // Reading hands out credit again and the sender finishes.
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(10), stalled_peer.read_to_end(&mut rest))
+        .await
+        .expect("stalled sender never resumed")
+        .unwrap();
+    assert!(rest == stalled_data);
+    writer.await.unwrap();
+}
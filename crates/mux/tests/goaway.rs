@@ -0,0 +1,148 @@
+mod support;
+
+use btlink::CloseReason;
+use mux::{Frame, SessionEnd, TargetAddr, GOAWAY_NO_ERROR, GOAWAY_SHUTDOWN};
+use std::time::Duration;
+use support::{config, link_pair, open, raw_server, session_pair};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout, Instant};
+
+fn target() -> TargetAddr {
+    TargetAddr::Domain("example.com".to_string(), 80)
+}
+
+#[tokio::test]
+async fn drain_refuses_new_streams_and_lets_open_ones_finish() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, config(None)).await;
+    let (mut ours, mut theirs) = open(&client, &server).await;
+
+    let draining = {
+        let server = server.clone();
+        tokio::spawn(async move { server.drain(GOAWAY_SHUTDOWN, Duration::from_secs(10)).await })
+    };
+    timeout(Duration::from_secs(5), client.going_away())
+        .await
+        .expect("client never saw the goaway");
+    assert!(client.open_stream(target()).await.is_err());
+    assert!(server.open_stream(target()).await.is_err());
+
+    // The stream that was open keeps working both ways.
+    ours.write_all(b"request").await.unwrap();
+    ours.shutdown().await.unwrap();
+    let mut request = Vec::new();
+    theirs.read_to_end(&mut request).await.unwrap();
+    assert_eq!(request, b"request");
+    theirs.write_all(b"response").await.unwrap();
+    theirs.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    ours.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"response");
+    assert!(!draining.is_finished(), "drain ended with a stream open");
+
+    drop((ours, theirs));
+    let end = timeout(Duration::from_secs(5), draining)
+        .await
+        .expect("drain did not end once the streams were done")
+        .unwrap();
+    assert_eq!(
+        end,
+        SessionEnd::Local {
+            code: GOAWAY_SHUTDOWN
+        }
+    );
+    let end = timeout(Duration::from_secs(5), client.closed()).await;
+    assert_eq!(
+        end.unwrap(),
+        SessionEnd::GoAway {
+            code: GOAWAY_SHUTDOWN
+        }
+    );
+}
+
+#[tokio::test]
+async fn drain_deadline_cuts_off_streams_still_open() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, config(None)).await;
+    let (mut ours, _theirs) = open(&client, &server).await;
+
+    let started = Instant::now();
+    let end = timeout(
+        Duration::from_secs(5),
+        server.drain(GOAWAY_SHUTDOWN, Duration::from_millis(200)),
+    )
+    .await
+    .expect("drain ignored its deadline");
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(
+        end,
+        SessionEnd::Local {
+            code: GOAWAY_SHUTDOWN
+        }
+    );
+
+    // Our end of the stream learns it is over rather than waiting forever.
+    let read = timeout(Duration::from_secs(5), ours.read(&mut [0; 16])).await;
+    assert!(!matches!(read.expect("stream outlived its session"), Ok(n) if n > 0));
+    let end = timeout(Duration::from_secs(5), client.closed()).await;
+    assert_eq!(
+        end.unwrap(),
+        SessionEnd::GoAway {
+            code: GOAWAY_SHUTDOWN
+        }
+    );
+}
+
+#[tokio::test]
+async fn goaway_refuses_opens_the_peer_never_took() {
+    let (a, b) = link_pair();
+    let (client, mut peer) = raw_server(a, b, config(None)).await;
+    let opening = {
+        let client = client.clone();
+        tokio::spawn(async move { client.open_stream(target()).await })
+    };
+    loop {
+        match timeout(Duration::from_secs(5), peer.recv()).await.unwrap() {
+            Frame::Open { .. } => break,
+            _ => continue,
+        }
+    }
+    // The OPEN arrived after the peer had decided to go away.
+    peer.send(&Frame::GoAway {
+        last_stream_id: 0,
+        code: GOAWAY_SHUTDOWN,
+    })
+    .await;
+    let refused = timeout(Duration::from_secs(5), opening)
+        .await
+        .expect("open not refused")
+        .unwrap();
+    assert!(refused.is_err());
+    timeout(Duration::from_secs(5), client.going_away())
+        .await
+        .unwrap();
+    assert!(client.open_stream(target()).await.is_err());
+}
+
+#[tokio::test]
+async fn close_reason_reaches_closed_on_both_sides() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, config(None)).await;
+    let (_ours, _theirs) = open(&client, &server).await;
+
+    let end = timeout(Duration::from_secs(5), client.close())
+        .await
+        .expect("close hung with a stream open");
+    assert_eq!(
+        end,
+        SessionEnd::Local {
+            code: GOAWAY_NO_ERROR
+        }
+    );
+    // Asking again gives the same answer.
+    assert_eq!(client.closed().await, end);
+
+    let end = timeout(Duration::from_secs(5), server.closed()).await;
+    assert_eq!(end.unwrap(), SessionEnd::Link(CloseReason::PeerEof));
+    assert!(server.accept_stream().await.is_none());
+}
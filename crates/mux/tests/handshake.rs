@@ -0,0 +1,168 @@
+mod support;
+
+use common::error::BtProxyError;
+use mux::handshake::build_hello;
+use mux::{Frame, HelloFrame, MuxSession, Role, FLAG_MUTUAL_AUTH};
+use std::time::Duration;
+use support::{config, expect_auth_error, link_pair, RawPeer, PSK};
+use tokio::time::timeout;
+
+/// Relays a genuine handshake and returns the client's HELLO and AUTH as an
+/// eavesdropper would capture them.
+async fn record_client_handshake() -> (Frame, Frame) {
+    let (client, server) = link_pair();
+    let client = tokio::spawn(MuxSession::start(client, config(Some(PSK)), Role::Client));
+    let accept = tokio::spawn(async move {
+        let (front, back) = link_pair();
+        let session = tokio::spawn(MuxSession::start(back, config(Some(PSK)), Role::Server));
+        let mut proxy_client = RawPeer::new(server);
+        let mut proxy_server = RawPeer::new(front);
+        let hello = proxy_client.recv().await;
+        proxy_server.send(&hello).await;
+        let ack = proxy_server.recv().await;
+        proxy_client.send(&ack).await;
+        let auth = proxy_client.recv().await;
+        proxy_server.send(&auth).await;
+        session.await.unwrap().unwrap();
+        (hello, auth)
+    });
+    client.await.unwrap().unwrap();
+    accept.await.unwrap()
+}
+
+#[tokio::test]
+async fn psk_sessions_authenticate_both_ways() {
+    let (a, b) = link_pair();
+    let (client, server) = tokio::join!(
+        MuxSession::start(a, config(Some(PSK)), Role::Client),
+        MuxSession::start(b, config(Some(PSK)), Role::Server),
+    );
+    assert!(client.unwrap().stats().links > 0);
+    assert!(server.unwrap().stats().links > 0);
+}
+
+#[tokio::test]
+async fn wrong_psk_is_rejected_by_both_sides() {
+    let (a, b) = link_pair();
+    let client = MuxSession::start(a, config(Some(b"wrong")), Role::Client);
+    let server = MuxSession::start(b, config(Some(PSK)), Role::Server);
+    let server = tokio::spawn(async move { timeout(Duration::from_secs(5), server).await });
+    expect_auth_error(client, "server proof mismatch").await;
+    // The client hung up instead of answering the challenge.
+    assert!(matches!(server.await.unwrap(), Ok(Err(_))));
+}
+
+#[tokio::test]
+async fn one_sided_psk_is_reported() {
+    for (client_psk, server_psk, reason) in [
+        (None, Some(PSK), "did not offer PSK"),
+        (Some(PSK), None, "peer requires a PSK"),
+    ] {
+        let (a, b) = link_pair();
+        let server = tokio::spawn(MuxSession::start(b, config(server_psk), Role::Server));
+        let client = tokio::spawn(MuxSession::start(a, config(client_psk), Role::Client));
+        expect_auth_error(async { server.await.unwrap() }, reason).await;
+        // The server hangs up without answering.
+        assert!(client.await.unwrap().is_err());
+    }
+}
+
+#[tokio::test]
+async fn replayed_client_handshake_is_rejected() {
+    let (hello, auth) = record_client_handshake().await;
+
+    // Replay the captured HELLO and AUTH to a fresh server. It answers with
+    // a new challenge, so the old proof no longer fits.
+    let (attacker, server) = link_pair();
+    let server = tokio::spawn(MuxSession::start(server, config(Some(PSK)), Role::Server));
+    let mut attacker = RawPeer::new(attacker);
+    attacker.send(&hello).await;
+    attacker.recv_hello().await;
+    attacker.send(&auth).await;
+    expect_auth_error(async { server.await.unwrap() }, "client proof mismatch").await;
+}
+
+#[tokio::test]
+async fn replayed_server_ack_is_rejected() {
+    // Capture a genuine HELLO_ACK.
+    let (a, b) = link_pair();
+    let server = tokio::spawn(MuxSession::start(b, config(Some(PSK)), Role::Server));
+    let mut probe = RawPeer::new(a);
+    probe.send(&build_hello(&config(Some(PSK)), 0, None)).await;
+    let old_ack = probe.recv_hello().await;
+    drop(probe);
+    let _ = server.await;
+
+    // Offer it to a new client, even patched to echo the new HELLO's nonce.
+    let (client, fake_server) = link_pair();
+    let client = MuxSession::start(client, config(Some(PSK)), Role::Client);
+    let mut fake_server = RawPeer::new(fake_server);
+    let answer = async move {
+        let hello = fake_server.recv_hello().await;
+        let ack = HelloFrame {
+            nonce: hello.nonce,
+            ..old_ack
+        };
+        fake_server.send(&Frame::HelloAck(Box::new(ack))).await;
+        fake_server
+    };
+    let (result, _fake_server) = tokio::join!(timeout(Duration::from_secs(5), client), answer);
+    match result {
+        Ok(Err(BtProxyError::Auth(msg))) => assert!(msg.contains("server proof mismatch")),
+        other => panic!(
+            "expected server proof mismatch, got {:?}",
+            other.map(|r| r.err())
+        ),
+    }
+}
+
+#[tokio::test]
+async fn reflected_hello_is_rejected() {
+    // A fake server that bounces the client's own HELLO back as the ack.
+    let (client, fake_server) = link_pair();
+    let client = MuxSession::start(client, config(Some(PSK)), Role::Client);
+    let mut fake_server = RawPeer::new(fake_server);
+    let reflect = async move {
+        let hello = fake_server.recv_hello().await;
+        fake_server.send(&Frame::HelloAck(Box::new(hello))).await;
+        fake_server
+    };
+    let (result, _fake_server) = tokio::join!(timeout(Duration::from_secs(5), client), reflect);
+    match result {
+        Ok(Err(BtProxyError::Auth(msg))) => assert!(msg.contains("carries no proof")),
+        other => panic!("expected missing proof, got {:?}", other.map(|r| r.err())),
+    }
+}
+
+#[tokio::test]
+async fn reflected_server_proof_is_rejected() {
+    // An attacker without the PSK hands the server's own proof back as the
+    // client's answer.
+    let (attacker, server) = link_pair();
+    let server = tokio::spawn(MuxSession::start(server, config(Some(PSK)), Role::Server));
+    let mut attacker = RawPeer::new(attacker);
+    attacker
+        .send(&build_hello(&config(Some(b"guess")), 0, None))
+        .await;
+    let ack = attacker.recv_hello().await;
+    attacker
+        .send(&Frame::Auth {
+            proof: ack.hmac.expect("server proof"),
+        })
+        .await;
+    expect_auth_error(async { server.await.unwrap() }, "client proof mismatch").await;
+}
+
+#[tokio::test]
+async fn legacy_psk_hello_is_rejected() {
+    let (attacker, server) = link_pair();
+    let server = tokio::spawn(MuxSession::start(server, config(Some(PSK)), Role::Server));
+    let attacker = RawPeer::new(attacker);
+    let Frame::Hello(mut hello) = build_hello(&config(Some(PSK)), 0, None) else {
+        unreachable!();
+    };
+    hello.flags &= !FLAG_MUTUAL_AUTH;
+    hello.hmac = Some([0; 32]);
+    attacker.send(&Frame::Hello(hello)).await;
+    expect_auth_error(async { server.await.unwrap() }, "legacy PSK handshake").await;
+}
@@ -0,0 +1,90 @@
+mod support;
+
+use mux::{Frame, Priority, TargetAddr};
+use std::time::Duration;
+use support::{config, link_pair, open, raw_server, session_pair, RawPeer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// OPEN_ERR code for an OPEN that reuses or does not own its id.
+const OPEN_BAD_ID: u16 = 400;
+
+fn target() -> TargetAddr {
+    TargetAddr::Domain("example.com".to_string(), 80)
+}
+
+/// The next frame that is not keepalive traffic.
+async fn recv(peer: &mut RawPeer) -> Frame {
+    loop {
+        match timeout(Duration::from_secs(5), peer.recv()).await {
+            Ok(Frame::Ping { .. }) => continue,
+            Ok(frame) => return frame,
+            Err(_) => panic!("nothing received"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn clients_open_odd_ids_and_servers_even_ones() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, config(None)).await;
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let (ours, theirs) = open(&client, &server).await;
+        assert_eq!(ours.stream_id, theirs.stream_id);
+        ids.push(ours.stream_id);
+        let (ours, theirs) = open(&server, &client).await;
+        assert_eq!(ours.stream_id, theirs.stream_id);
+        ids.push(ours.stream_id);
+    }
+    assert_eq!(ids, [1, 2, 3, 4]);
+}
+
+#[tokio::test]
+async fn server_opened_stream_carries_data_both_ways() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, config(None)).await;
+    let (mut ours, mut theirs) = open(&server, &client).await;
+
+    ours.write_all(b"from the server").await.unwrap();
+    ours.shutdown().await.unwrap();
+    let mut request = Vec::new();
+    theirs.read_to_end(&mut request).await.unwrap();
+    assert_eq!(request, b"from the server");
+
+    theirs.write_all(b"from the client").await.unwrap();
+    theirs.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    ours.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"from the client");
+}
+
+#[tokio::test]
+async fn opens_with_a_foreign_or_reused_id_are_refused() {
+    let (a, b) = link_pair();
+    let (client, mut peer) = raw_server(a, b, config(None)).await;
+    // An odd id belongs to the client, and 2 can only be used once.
+    for stream_id in [1, 2, 2] {
+        peer.send(&Frame::Open {
+            stream_id,
+            target: target(),
+            priority: Priority::Normal,
+        })
+        .await;
+    }
+    let (_, accepted) = timeout(Duration::from_secs(5), client.accept_stream())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(accepted.stream_id, 2);
+    let mut refused = Vec::new();
+    while refused.len() < 2 {
+        match recv(&mut peer).await {
+            Frame::OpenErr {
+                stream_id, code, ..
+            } => refused.push((stream_id, code)),
+            frame => panic!("expected open_err, got {:?}", frame),
+        }
+    }
+    assert_eq!(refused, [(1, OPEN_BAD_ID), (2, OPEN_BAD_ID)]);
+}
@@ -0,0 +1,142 @@
+mod support;
+
+use btlink::{emulated_pair, BtLink, BtLinkConfig, CloseReason, EmulatorConfig};
+use mux::{Frame, MuxConfig, SessionEnd};
+use std::time::Duration;
+use support::{config, raw_server, RawPeer};
+use tokio::time::{sleep, timeout, Instant};
+
+/// One-way delay of the emulated link.
+const LATENCY: Duration = Duration::from_millis(15);
+
+fn slow_link() -> (BtLink, BtLink) {
+    let emu = EmulatorConfig {
+        latency: LATENCY,
+        max_fragment: Some(64),
+        seed: 13,
+        ..EmulatorConfig::default()
+    };
+    emulated_pair(emu, BtLinkConfig::default())
+}
+
+fn keepalive_config(keepalive_ms: u32, max_missed_pongs: u32, idle_timeout_ms: u32) -> MuxConfig {
+    MuxConfig {
+        keepalive_ms,
+        max_missed_pongs,
+        idle_timeout_ms,
+        ..config(None)
+    }
+}
+
+async fn recv_ping(peer: &mut RawPeer) -> u64 {
+    match peer.recv().await {
+        Frame::Ping { nonce } => nonce,
+        frame => panic!("expected ping, got {:?}", frame),
+    }
+}
+
+#[tokio::test]
+async fn pongs_are_matched_to_their_ping_and_smooth_the_rtt() {
+    let (a, b) = slow_link();
+    let (client, mut peer) = raw_server(a, b, keepalive_config(200, 0, 0)).await;
+
+    // Leave two PINGs open.
+    let first = recv_ping(&mut peer).await;
+    let second = recv_ping(&mut peer).await;
+    assert_eq!(client.stats().missed_pongs, 2);
+    assert_eq!(client.stats().rtt, None);
+
+    // A PONG nobody asked for matches nothing.
+    peer.send(&Frame::Pong {
+        nonce: first ^ second ^ 1,
+    })
+    .await;
+    sleep(LATENCY * 3).await;
+    assert_eq!(client.stats().missed_pongs, 2);
+    assert_eq!(client.stats().rtt, None);
+
+    // Answering the newer PING gives up on the older one too.
+    peer.send(&Frame::Pong { nonce: second }).await;
+    sleep(LATENCY * 3).await;
+    assert_eq!(client.stats().missed_pongs, 0);
+    let first_rtt = client.stats().rtt.expect("no rtt after a pong");
+    assert!(
+        first_rtt >= LATENCY * 2,
+        "rtt {:?} below the link's",
+        first_rtt
+    );
+
+    // Prompt answers pull the smoothed value down, a step at a time.
+    let nonce = recv_ping(&mut peer).await;
+    peer.send(&Frame::Pong { nonce }).await;
+    sleep(LATENCY * 3).await;
+    let rtt = client.stats().rtt.unwrap();
+    assert!(rtt < first_rtt, "{:?} not below {:?}", rtt, first_rtt);
+    assert!(rtt > first_rtt * 3 / 4, "{:?} not smoothed", rtt);
+    assert_eq!(client.stats().missed_pongs, 0);
+}
+
+#[tokio::test]
+async fn peer_that_stops_answering_times_out() {
+    let (a, b) = slow_link();
+    let (client, mut peer) = raw_server(a, b, keepalive_config(50, 3, 0)).await;
+
+    // Answer a few, then go silent.
+    for _ in 0..3 {
+        let nonce = recv_ping(&mut peer).await;
+        peer.send(&Frame::Pong { nonce }).await;
+    }
+    let silent_since = Instant::now();
+    let end = timeout(Duration::from_secs(5), client.closed())
+        .await
+        .expect("session outlived a silent peer");
+    assert_eq!(end, SessionEnd::Timeout);
+    // Three unanswered PINGs go out 50ms apart before the fourth tick
+    // gives up.
+    assert!(silent_since.elapsed() >= Duration::from_millis(100));
+    assert_eq!(peer.link.handle.closed().await, CloseReason::PeerEof);
+}
+
+#[tokio::test]
+async fn missed_pong_limit_zero_keeps_a_silent_session() {
+    let (a, b) = slow_link();
+    let (client, _peer) = raw_server(a, b, keepalive_config(20, 0, 0)).await;
+    assert!(timeout(Duration::from_millis(300), client.closed())
+        .await
+        .is_err());
+    assert!(client.stats().missed_pongs >= 5);
+}
+
+#[tokio::test]
+async fn idle_timeout_closes_a_session_that_hears_nothing() {
+    let (a, b) = slow_link();
+    // Keepalive far apart and the missed-pong limit off, so only the idle
+    // timer can end it.
+    let (client, _peer) = raw_server(a, b, keepalive_config(10_000, 0, 150)).await;
+    let started = Instant::now();
+    let end = timeout(Duration::from_secs(5), client.closed())
+        .await
+        .expect("idle session stayed open");
+    assert_eq!(end, SessionEnd::Timeout);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn traffic_resets_the_idle_timeout() {
+    let (a, b) = slow_link();
+    let (client, mut peer) = raw_server(a, b, keepalive_config(40, 0, 150)).await;
+    // Fifteen answered PINGs span four idle timeouts.
+    let answer = async move {
+        for _ in 0..15 {
+            let nonce = recv_ping(&mut peer).await;
+            peer.send(&Frame::Pong { nonce }).await;
+        }
+        peer
+    };
+    let _peer = tokio::select! {
+        end = client.closed() => panic!("session ended while the peer answered: {:?}", end),
+        peer = answer => peer,
+    };
+    let end = timeout(Duration::from_secs(5), client.closed()).await;
+    assert_eq!(end.unwrap(), SessionEnd::Timeout);
+}
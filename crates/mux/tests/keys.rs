@@ -0,0 +1,274 @@
+mod support;
+
+use mux::handshake::build_hello;
+use mux::keys::{AuthorizedKeys, ClientKey};
+use mux::{Frame, MuxConfig, MuxSession, Role};
+use std::sync::Arc;
+use support::{config, expect_auth_error, link_pair, RawPeer, PSK};
+
+fn client_config(key: &ClientKey, psk: Option<&[u8]>) -> MuxConfig {
+    MuxConfig {
+        client_key: Some(key.clone()),
+        ..config(psk)
+    }
+}
+
+fn server_config(keys: &[&ClientKey], psk: Option<&[u8]>) -> MuxConfig {
+    let text: String = keys
+        .iter()
+        .map(|key| key.public_key().to_line(&key.comment) + "\n")
+        .collect();
+    MuxConfig {
+        authorized_keys: Some(Arc::new(AuthorizedKeys::parse(&text).unwrap())),
+        ..config(psk)
+    }
+}
+
+#[tokio::test]
+async fn authorized_key_is_accepted_and_reported() {
+    let laptop = ClientKey::generate("laptop");
+    let other = ClientKey::generate("desktop");
+    for psk in [None, Some(PSK)] {
+        let (a, b) = link_pair();
+        let (client, server) = tokio::join!(
+            MuxSession::start(a, client_config(&laptop, psk), Role::Client),
+            MuxSession::start(b, server_config(&[&other, &laptop], psk), Role::Server),
+        );
+        client.unwrap();
+        let server = server.unwrap();
+        let key = server.client_key().expect("client key");
+        assert_eq!(key.key, laptop.public_key());
+        assert_eq!(key.label(), "laptop");
+    }
+}
+
+#[tokio::test]
+async fn unknown_key_is_rejected() {
+    let laptop = ClientKey::generate("laptop");
+    let stranger = ClientKey::generate("stranger");
+    let (a, b) = link_pair();
+    let server = tokio::spawn(MuxSession::start(
+        b,
+        server_config(&[&laptop], None),
+        Role::Server,
+    ));
+    let _client = tokio::spawn(MuxSession::start(
+        a,
+        client_config(&stranger, None),
+        Role::Client,
+    ));
+    let reason = format!("key {} is not authorized", stranger.public_key());
+    expect_auth_error(async { server.await.unwrap() }, &reason).await;
+}
+
+#[tokio::test]
+async fn missing_client_key_is_reported() {
+    let laptop = ClientKey::generate("laptop");
+    let (a, b) = link_pair();
+    let server = tokio::spawn(MuxSession::start(
+        b,
+        server_config(&[&laptop], None),
+        Role::Server,
+    ));
+    let _client = tokio::spawn(MuxSession::start(a, config(None), Role::Client));
+    expect_auth_error(async { server.await.unwrap() }, "does not use client keys").await;
+}
+
+#[tokio::test]
+async fn replayed_key_signature_is_rejected() {
+    let laptop = ClientKey::generate("laptop");
+
+    // Eavesdrop on a genuine handshake.
+    let (client, tap_client) = link_pair();
+    let (tap_server, server) = link_pair();
+    let client = tokio::spawn(MuxSession::start(
+        client,
+        client_config(&laptop, None),
+        Role::Client,
+    ));
+    let server = tokio::spawn(MuxSession::start(
+        server,
+        server_config(&[&laptop], None),
+        Role::Server,
+    ));
+    let mut tap_client = RawPeer::new(tap_client);
+    let mut tap_server = RawPeer::new(tap_server);
+    let hello = tap_client.recv().await;
+    tap_server.send(&hello).await;
+    let ack = tap_server.recv().await;
+    tap_client.send(&ack).await;
+    let auth_key = tap_client.recv().await;
+    assert!(matches!(auth_key, Frame::AuthKey { .. }));
+    tap_server.send(&auth_key).await;
+    client.await.unwrap().unwrap();
+    server.await.unwrap().unwrap();
+
+    // Replay it to a fresh server, which answers with a new key exchange.
+    let (attacker, server) = link_pair();
+    let server = tokio::spawn(MuxSession::start(
+        server,
+        server_config(&[&laptop], None),
+        Role::Server,
+    ));
+    let mut attacker = RawPeer::new(attacker);
+    attacker.send(&hello).await;
+    attacker.recv_hello().await;
+    attacker.send(&auth_key).await;
+    expect_auth_error(async { server.await.unwrap() }, "does not verify").await;
+}
+
+#[tokio::test]
+async fn forged_signature_is_rejected() {
+    // The attacker knows the authorized public key but not the private one.
+    let laptop = ClientKey::generate("laptop");
+    let forger = ClientKey::generate("forger");
+    let (attacker, server) = link_pair();
+    let server = tokio::spawn(MuxSession::start(
+        server,
+        server_config(&[&laptop], None),
+        Role::Server,
+    ));
+    let mut attacker = RawPeer::new(attacker);
+    let Frame::Hello(hello) = build_hello(&config(None), 0, Some([9; 32])) else {
+        unreachable!();
+    };
+    attacker.send(&Frame::Hello(hello.clone())).await;
+    let ack = attacker.recv_hello().await;
+    let Frame::AuthKey { signature, .. } =
+        mux::handshake::build_auth_key(&forger, &hello, &ack).unwrap()
+    else {
+        unreachable!();
+    };
+    attacker
+        .send(&Frame::AuthKey {
+            public_key: *laptop.public_key().as_bytes(),
+            signature,
+        })
+        .await;
+    expect_auth_error(async { server.await.unwrap() }, "does not verify").await;
+}
+
+#[tokio::test]
+async fn no_bond_key_cannot_join_links() {
+    let laptop = ClientKey::generate("laptop");
+    let keys = format!("no-bond {}", laptop.public_key().to_line(""));
+    let server_cfg = MuxConfig {
+        bond: true,
+        authorized_keys: Some(Arc::new(AuthorizedKeys::parse(&keys).unwrap())),
+        ..config(None)
+    };
+    let client_cfg = MuxConfig {
+        bond: true,
+        ..client_config(&laptop, None)
+    };
+    let (a, b) = link_pair();
+    let (client, server) = tokio::join!(
+        MuxSession::start(a, client_cfg, Role::Client),
+        MuxSession::start(b, server_cfg.clone(), Role::Server),
+    );
+    let (client, _server) = (client.unwrap(), server.unwrap());
+
+    let (a, b) = link_pair();
+    let accept = tokio::spawn(MuxSession::accept(b, server_cfg));
+    let _ = client.join(a).await;
+    expect_auth_error(async { accept.await.unwrap() }, "may not join").await;
+}
+
+#[test]
+fn authorized_keys_parse_options_and_comments() {
+    let laptop = ClientKey::generate("");
+    let desktop = ClientKey::generate("");
+    let text = format!(
+        "# team keys\n\n{}\nname=\"Ann's laptop\",from=\"10.0.0.*,192.168.1.7:*\",no-bond {}\n",
+        laptop.public_key().to_line("ann@laptop"),
+        desktop.public_key().to_line(""),
+    );
+    let keys = AuthorizedKeys::parse(&text).unwrap();
+    assert_eq!(keys.len(), 2);
+
+    let first = keys.get(&laptop.public_key()).unwrap();
+    assert_eq!(first.label(), "ann@laptop");
+    assert!(first.allows_from("172.16.0.1:4000"));
+    assert!(!first.options.no_bond);
+
+    let second = keys.get(&desktop.public_key()).unwrap();
+    assert_eq!(second.label(), "Ann's laptop");
+    assert!(second.options.no_bond);
+    assert!(second.allows_from("10.0.0.3:51234"));
+    assert!(second.allows_from("192.168.1.7:80"));
+    assert!(!second.allows_from("192.168.1.8:80"));
+}
+
+#[test]
+fn authorized_keys_errors_name_the_line() {
+    let key = ClientKey::generate("").public_key().to_line("");
+    for (text, reason) in [
+        (format!("{}\nssh-rsa AAAA", key), "line 2: unknown key type"),
+        (format!("# ok\nbogus {}", key), "line 2: unknown option"),
+        (format!("from=\"10.* {}", key), "line 1: unterminated quote"),
+        ("btproxy-ed25519 AAAA".to_string(), "line 1: malformed key"),
+    ] {
+        let err = AuthorizedKeys::parse(&text).unwrap_err().to_string();
+        assert!(err.contains(reason), "{:?} gave {}", text, err);
+    }
+}
+
+#[test]
+fn key_files_round_trip() {
+    let dir = std::env::temp_dir().join(format!("btproxy-keys-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("id_ed25519");
+    let key = ClientKey::generate("ann@laptop");
+    let public_path = key.save(&path).unwrap();
+
+    let loaded = ClientKey::load(&path).unwrap();
+    assert_eq!(loaded.public_key(), key.public_key());
+    assert_eq!(loaded.comment, "ann@laptop");
+    let authorized = AuthorizedKeys::load(&public_path).unwrap();
+    assert_eq!(
+        authorized.get(&key.public_key()).unwrap().label(),
+        "ann@laptop"
+    );
+    // Existing keys are never overwritten.
+    assert!(ClientKey::generate("again").save(&path).is_err());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failed_save_leaves_no_key_behind() {
+    let dir = std::env::temp_dir().join(format!("btproxy-keys-save-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("id_ed25519");
+    // Something in the way of the public half.
+    std::fs::create_dir(dir.join("id_ed25519.pub")).unwrap();
+    assert!(ClientKey::generate("first").save(&path).is_err());
+    assert!(!path.exists(), "private key left behind");
+
+    std::fs::remove_dir(dir.join("id_ed25519.pub")).unwrap();
+    ClientKey::generate("second").save(&path).unwrap();
+    assert_eq!(ClientKey::load(&path).unwrap().comment, "second");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn key_readable_by_others_is_refused() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = std::env::temp_dir().join(format!("btproxy-keys-mode-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("id_ed25519");
+    ClientKey::generate("ann@laptop").save(&path).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let err = ClientKey::load(&path).unwrap_err().to_string();
+    assert!(err.contains("accessible by others"), "{}", err);
+
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o400)).unwrap();
+    assert!(ClientKey::load(&path).is_ok());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
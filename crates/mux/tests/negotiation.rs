@@ -0,0 +1,141 @@
+mod support;
+
+use common::error::BtProxyError;
+use mux::handshake::{build_auth, build_hello, PROTOCOL_VERSION};
+use mux::{
+    Frame, MuxConfig, MuxSession, Role, TargetAddr, FLAG_GOAWAY, FLAG_SPLIT_IDS, FLAG_UDP,
+    FLAG_WINDOW, GOAWAY_INCOMPATIBLE,
+};
+use std::time::Duration;
+use support::{config, link_pair, RawPeer, PSK};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+fn expect_unsupported<T>(result: common::error::Result<T>, reason: &str) {
+    match result {
+        Err(BtProxyError::Unsupported(msg)) => {
+            assert!(msg.contains(reason), "unexpected reason: {}", msg)
+        }
+        Err(err) => panic!("expected unsupported, got {:?}", err),
+        Ok(_) => panic!("handshake unexpectedly succeeded"),
+    }
+}
+
+#[tokio::test]
+async fn sessions_agree_on_the_smaller_limits() {
+    let client_cfg = MuxConfig {
+        max_frame: 4096,
+        keepalive_ms: 5_000,
+        ..config(None)
+    };
+    let (a, b) = link_pair();
+    let (client, server) = tokio::join!(
+        MuxSession::start(a, client_cfg, Role::Client),
+        MuxSession::start(b, config(None), Role::Server),
+    );
+    let (client, server) = (client.unwrap(), server.unwrap());
+    assert_eq!(client.negotiated(), server.negotiated());
+    let negotiated = client.negotiated();
+    assert_eq!(negotiated.version, PROTOCOL_VERSION);
+    assert_eq!(negotiated.max_frame, 4096);
+    assert_eq!(negotiated.keepalive_ms, 5_000);
+    assert!(negotiated.has(FLAG_WINDOW | FLAG_GOAWAY | FLAG_SPLIT_IDS));
+    assert!(!negotiated.has(FLAG_UDP));
+
+    // Writes larger than the agreed frame size are split to fit.
+    let echo = tokio::spawn(async move {
+        let (_, mut stream) = server.accept_stream().await.unwrap();
+        server.send_open_ok(stream.stream_id).await.unwrap();
+        let mut data = vec![0; 64 * 1024];
+        stream.read_exact(&mut data).await.unwrap();
+        data
+    });
+    let mut stream = client
+        .open_stream(TargetAddr::Domain("example.com".to_string(), 80))
+        .await
+        .unwrap();
+    let sent: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+    stream.write_all(&sent).await.unwrap();
+    let received = timeout(Duration::from_secs(5), echo)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, sent);
+}
+
+#[tokio::test]
+async fn unsupported_version_is_refused_with_goaway() {
+    let (client, server) = link_pair();
+    let server = tokio::spawn(MuxSession::start(server, config(None), Role::Server));
+    let mut client = RawPeer::new(client);
+    let Frame::Hello(mut hello) = build_hello(&config(None), 0, None) else {
+        unreachable!();
+    };
+    hello.version = 0;
+    client.send(&Frame::Hello(hello)).await;
+    match client.recv().await {
+        Frame::GoAway { code, .. } => assert_eq!(code, GOAWAY_INCOMPATIBLE),
+        frame => panic!("expected goaway, got {:?}", frame),
+    }
+    expect_unsupported(server.await.unwrap(), "protocol version 0");
+}
+
+#[tokio::test]
+async fn tiny_frame_size_is_refused() {
+    let (client, server) = link_pair();
+    let server = tokio::spawn(MuxSession::start(server, config(None), Role::Server));
+    let mut client = RawPeer::new(client);
+    client
+        .send(&build_hello(
+            &MuxConfig {
+                max_frame: 512,
+                ..config(None)
+            },
+            0,
+            None,
+        ))
+        .await;
+    assert!(matches!(client.recv().await, Frame::GoAway { .. }));
+    expect_unsupported(server.await.unwrap(), "max frame 512");
+}
+
+#[tokio::test]
+async fn client_reports_an_incompatible_server() {
+    let (client, fake_server) = link_pair();
+    let client = MuxSession::start(client, config(None), Role::Client);
+    let mut fake_server = RawPeer::new(fake_server);
+    let refuse = async move {
+        fake_server.recv_hello().await;
+        fake_server
+            .send(&Frame::GoAway {
+                last_stream_id: 0,
+                code: GOAWAY_INCOMPATIBLE,
+            })
+            .await;
+        fake_server
+    };
+    let (result, _fake_server) = tokio::join!(timeout(Duration::from_secs(5), client), refuse);
+    expect_unsupported(result.unwrap(), "incompatible");
+}
+
+#[tokio::test]
+async fn newer_client_falls_back_to_the_server_version() {
+    // A future client offers a newer version; the server answers with its
+    // own and the PSK proofs cover the version both agreed on.
+    let (client, server) = link_pair();
+    let server = tokio::spawn(MuxSession::start(server, config(Some(PSK)), Role::Server));
+    let mut client = RawPeer::new(client);
+    let Frame::Hello(mut hello) = build_hello(&config(Some(PSK)), 0, None) else {
+        unreachable!();
+    };
+    hello.version = PROTOCOL_VERSION + 1;
+    client.send(&Frame::Hello(hello.clone())).await;
+    let ack = client.recv_hello().await;
+    assert_eq!(ack.version, PROTOCOL_VERSION);
+    client.send(&build_auth(PSK, &hello, &ack).unwrap()).await;
+    let server = timeout(Duration::from_secs(5), server).await.unwrap();
+    assert_eq!(
+        server.unwrap().unwrap().negotiated().version,
+        PROTOCOL_VERSION
+    );
+}
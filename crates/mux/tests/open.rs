@@ -0,0 +1,102 @@
+mod support;
+
+use common::error::BtProxyError;
+use mux::{Frame, MuxConfig, TargetAddr};
+use std::time::Duration;
+use support::{config, link_pair, raw_server, RawPeer};
+use tokio::time::{timeout, Instant};
+
+/// RST code for an open the opener gave up on.
+const RST_CANCELLED: u16 = 499;
+
+fn target() -> TargetAddr {
+    TargetAddr::Domain("example.com".to_string(), 80)
+}
+
+fn open_timeout(ms: u32) -> MuxConfig {
+    MuxConfig {
+        open_timeout_ms: ms,
+        ..config(None)
+    }
+}
+
+/// The next frame that is not keepalive traffic.
+async fn recv(peer: &mut RawPeer) -> Frame {
+    loop {
+        match timeout(Duration::from_secs(5), peer.recv()).await {
+            Ok(Frame::Ping { .. }) => continue,
+            Ok(frame) => return frame,
+            Err(_) => panic!("nothing received"),
+        }
+    }
+}
+
+async fn expect_open(peer: &mut RawPeer) -> u32 {
+    match recv(peer).await {
+        Frame::Open { stream_id, .. } => stream_id,
+        frame => panic!("expected open, got {:?}", frame),
+    }
+}
+
+async fn expect_cancel(peer: &mut RawPeer, expected: u32) {
+    match recv(peer).await {
+        Frame::Rst { stream_id, code } => {
+            assert_eq!((stream_id, code), (expected, RST_CANCELLED));
+        }
+        frame => panic!("expected rst, got {:?}", frame),
+    }
+}
+
+#[tokio::test]
+async fn unanswered_open_times_out_and_is_reset() {
+    let (a, b) = link_pair();
+    let (client, mut peer) = raw_server(a, b, open_timeout(100)).await;
+    let started = Instant::now();
+    let (result, stream_id) = tokio::join!(client.open_stream(target()), expect_open(&mut peer));
+    assert!(matches!(result, Err(BtProxyError::Timeout(_))));
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    expect_cancel(&mut peer, stream_id).await;
+    assert_eq!(client.stats().open_streams, 0);
+}
+
+#[tokio::test]
+async fn dropping_an_open_cleans_up() {
+    let (a, b) = link_pair();
+    let (client, mut peer) = raw_server(a, b, open_timeout(0)).await;
+    let abandon = timeout(Duration::from_millis(50), client.open_stream(target()));
+    let (abandoned, stream_id) = tokio::join!(abandon, expect_open(&mut peer));
+    assert!(abandoned.is_err(), "open completed without an answer");
+    expect_cancel(&mut peer, stream_id).await;
+    timeout(Duration::from_secs(5), async {
+        while client.stats().open_streams > 0 {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("abandoned stream still counted");
+}
+
+#[tokio::test]
+async fn late_open_ok_is_answered_with_rst() {
+    let (a, b) = link_pair();
+    let (client, mut peer) = raw_server(a, b, open_timeout(50)).await;
+    let (result, stream_id) = tokio::join!(client.open_stream(target()), expect_open(&mut peer));
+    assert!(matches!(result, Err(BtProxyError::Timeout(_))));
+    expect_cancel(&mut peer, stream_id).await;
+
+    // The answer shows up after the opener gave up.
+    peer.send(&Frame::OpenOk { stream_id }).await;
+    expect_cancel(&mut peer, stream_id).await;
+
+    // The session carries on with the next stream.
+    let answer = async {
+        let next = expect_open(&mut peer).await;
+        peer.send(&Frame::OpenOk { stream_id: next }).await;
+        next
+    };
+    let (stream, next) = tokio::join!(client.open_stream(target()), answer);
+    let stream = stream.unwrap();
+    assert_eq!(stream.stream_id, next);
+    assert!(next > stream_id);
+    assert_eq!(client.stats().open_streams, 1);
+}
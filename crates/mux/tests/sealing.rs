@@ -0,0 +1,290 @@
+mod support;
+
+use btlink::BtLinkHandle;
+use bytes::{Bytes, BytesMut};
+use mux::{MuxConfig, MuxSession, MuxStream, Role, SessionEnd, StreamError, TargetAddr};
+use std::io;
+use std::time::Duration;
+use support::{config, link_pair, PSK};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// RST code for streams that may have lost a frame to link corruption.
+const RST_FRAME_LOST: u16 = 410;
+
+/// One direction of a man in the middle that hands the test whole raw
+/// frames to forward, alter, repeat or reorder.
+struct Tap {
+    rx: mpsc::Receiver<Bytes>,
+    buffer: BytesMut,
+    /// Where the frames were headed.
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl Tap {
+    /// The next `LEN | BODY`, or `SYNC | LEN | HCRC | BODY | CRC32` once the
+    /// link is `checked`.
+    async fn next(&mut self, checked: bool) -> BytesMut {
+        let (len_at, overhead) = if checked { (2, 12) } else { (0, 4) };
+        loop {
+            if self.buffer.len() >= len_at + 4 {
+                let len = u32::from_be_bytes(self.buffer[len_at..len_at + 4].try_into().unwrap());
+                let total = overhead + len as usize;
+                if self.buffer.len() >= total {
+                    return self.buffer.split_to(total);
+                }
+            }
+            let chunk = self.rx.recv().await.expect("link closed");
+            self.buffer.extend_from_slice(&chunk);
+        }
+    }
+
+    async fn forward(&self, frame: &[u8]) {
+        self.tx.send(Bytes::copy_from_slice(frame)).await.unwrap();
+    }
+
+    async fn pass(&mut self, checked: bool) {
+        let frame = self.next(checked).await;
+        self.forward(&frame).await;
+    }
+}
+
+/// Recomputes the CRC32 of a checked frame after the test altered it, as an
+/// attacker on the link would.
+fn fix_crc(frame: &mut [u8]) {
+    let end = frame.len() - 4;
+    let crc = crc32fast::hash(&frame[2..end]);
+    frame[end..].copy_from_slice(&crc.to_be_bytes());
+}
+
+/// Runs a handshake through a tap in each direction and returns the sessions
+/// with the client-to-server tap. Server frames pass untouched. With a PSK,
+/// every frame after the handshake is sealed.
+async fn tapped_sessions(cfg: MuxConfig) -> (MuxSession, MuxSession, Tap, [BtLinkHandle; 2]) {
+    let (client_link, client_end) = link_pair();
+    let (server_end, server_link) = link_pair();
+    let client = tokio::spawn(MuxSession::start(client_link, cfg.clone(), Role::Client));
+    let sealed = cfg.psk.is_some();
+    let server = tokio::spawn(MuxSession::start(server_link, cfg, Role::Server));
+    let handles = [client_end.handle, server_end.handle];
+    let mut to_server = Tap {
+        rx: client_end.rx,
+        buffer: BytesMut::new(),
+        tx: server_end.tx,
+    };
+    let mut to_client = Tap {
+        rx: server_end.rx,
+        buffer: BytesMut::new(),
+        tx: client_end.tx,
+    };
+    // HELLO, HELLO_ACK and AUTH go out in plain framing.
+    to_server.pass(false).await;
+    to_client.pass(false).await;
+    if sealed {
+        to_server.pass(false).await;
+    }
+    tokio::spawn(async move {
+        let mut pending = to_client.buffer.split().freeze();
+        loop {
+            if !pending.is_empty() {
+                to_client.forward(&pending).await;
+            }
+            match to_client.rx.recv().await {
+                Some(chunk) => pending = chunk,
+                None => break,
+            }
+        }
+    });
+    let client = client.await.unwrap().unwrap();
+    let server = server.await.unwrap().unwrap();
+    // Let the first keepalive through.
+    while let Ok(frame) = timeout(Duration::from_millis(100), to_server.next(true)).await {
+        to_server.forward(&frame).await;
+    }
+    (client, server, to_server, handles)
+}
+
+/// Accepts one stream on `server` and reads it to the end.
+fn read_one(server: MuxSession) -> JoinHandle<(Vec<u8>, io::Result<usize>, MuxSession)> {
+    tokio::spawn(async move {
+        let (_, mut stream) = server.accept_stream().await.unwrap();
+        server.send_open_ok(stream.stream_id).await.unwrap();
+        let mut received = Vec::new();
+        let res = stream.read_to_end(&mut received).await;
+        (received, res, server)
+    })
+}
+
+/// Opens a stream from `client`, passing the OPEN through `tap`.
+async fn open_through(client: &MuxSession, tap: &mut Tap) -> MuxStream {
+    let client = client.clone();
+    let opening = tokio::spawn(async move {
+        client
+            .open_stream(TargetAddr::Domain("example.com".to_string(), 80))
+            .await
+            .unwrap()
+    });
+    tap.pass(true).await;
+    opening.await.unwrap()
+}
+
+fn assert_lost(res: io::Result<usize>, expected: StreamError) {
+    let err = res.expect_err("stream ended cleanly despite the lost frame");
+    let inner = err.get_ref().and_then(|e| e.downcast_ref::<StreamError>());
+    assert_eq!(inner, Some(&expected));
+}
+
+#[tokio::test]
+async fn tampered_and_replayed_frames_are_dropped() {
+    let cfg = MuxConfig {
+        checksum: true,
+        keepalive_ms: 60_000,
+        ..config(Some(PSK))
+    };
+    let (client, server, mut tap, _handles) = tapped_sessions(cfg).await;
+    let reader = read_one(server);
+    let stream = open_through(&client, &mut tap).await;
+
+    // Altered counter, ciphertext or tag: each fails authentication, and the
+    // genuine frame still gets through after them.
+    stream.send_data(Bytes::from_static(b"one ")).await.unwrap();
+    let genuine = tap.next(true).await;
+    let tag_end = genuine.len() - 4;
+    for offset in [15, 16, tag_end - 1] {
+        let mut forged = genuine.clone();
+        forged[offset] ^= 0x01;
+        fix_crc(&mut forged);
+        tap.forward(&forged).await;
+    }
+    tap.forward(&genuine).await;
+
+    // A replayed frame is dropped the second time.
+    stream.send_data(Bytes::from_static(b"two")).await.unwrap();
+    let frame = tap.next(true).await;
+    tap.forward(&frame).await;
+    tap.forward(&frame).await;
+
+    stream.send_fin().await.unwrap();
+    tap.pass(true).await;
+    let (received, res, server) = timeout(Duration::from_secs(5), reader)
+        .await
+        .expect("stream never finished")
+        .unwrap();
+    res.unwrap();
+    assert_eq!(received, b"one two");
+    assert_eq!(server.stats().frame_errors, 4);
+}
+
+#[tokio::test]
+async fn sealed_frame_that_never_arrives_resets_the_stream() {
+    let cfg = MuxConfig {
+        checksum: true,
+        keepalive_ms: 60_000,
+        ..config(Some(PSK))
+    };
+    let (client, server, mut tap, _handles) = tapped_sessions(cfg).await;
+    let reader = read_one(server);
+    let mut stream = open_through(&client, &mut tap).await;
+
+    stream.send_data(Bytes::from_static(b"one ")).await.unwrap();
+    tap.pass(true).await;
+    // The counter of the next frame shows the server one went missing; it
+    // arriving afterwards is a replay.
+    stream.send_data(Bytes::from_static(b"two ")).await.unwrap();
+    let early = tap.next(true).await;
+    stream
+        .send_data(Bytes::from_static(b"three"))
+        .await
+        .unwrap();
+    let late = tap.next(true).await;
+    tap.forward(&late).await;
+    tap.forward(&early).await;
+
+    let (received, res, server) = timeout(Duration::from_secs(5), reader)
+        .await
+        .expect("reader never learned of the lost frame")
+        .unwrap();
+    assert_eq!(received, b"one ");
+    assert_lost(res, StreamError::LocalReset(RST_FRAME_LOST));
+    assert_eq!(server.stats().frame_errors, 1);
+    // The sender hears of it too instead of believing it all arrived.
+    let end = timeout(Duration::from_secs(5), stream.recv_data()).await;
+    assert_eq!(end.unwrap(), Err(StreamError::Reset(RST_FRAME_LOST)));
+}
+
+#[tokio::test]
+async fn corrupted_data_frame_resets_the_streams_and_the_session_goes_on() {
+    let cfg = MuxConfig {
+        checksum: true,
+        keepalive_ms: 60_000,
+        ..config(None)
+    };
+    let (client, server, mut tap, _handles) = tapped_sessions(cfg).await;
+    let reader = read_one(server);
+    let mut stream = open_through(&client, &mut tap).await;
+
+    stream
+        .send_data(Bytes::from_static(b"intact "))
+        .await
+        .unwrap();
+    tap.pass(true).await;
+    // Line noise in the payload; the CRC no longer matches.
+    stream
+        .send_data(Bytes::from_static(b"garbled"))
+        .await
+        .unwrap();
+    let mut garbled = tap.next(true).await;
+    let last = garbled.len() - 5;
+    garbled[last] ^= 0x40;
+    tap.forward(&garbled).await;
+
+    let (received, res, server) = timeout(Duration::from_secs(5), reader)
+        .await
+        .expect("reader never learned of the corrupt frame")
+        .unwrap();
+    assert_eq!(received, b"intact ");
+    assert_lost(res, StreamError::LocalReset(RST_FRAME_LOST));
+    assert_eq!(server.stats().frame_errors, 1);
+    let end = timeout(Duration::from_secs(5), stream.recv_data()).await;
+    assert_eq!(end.unwrap(), Err(StreamError::Reset(RST_FRAME_LOST)));
+
+    // Only the streams are gone; the next one works.
+    let reader = read_one(server);
+    let next = open_through(&client, &mut tap).await;
+    next.send_data(Bytes::from_static(b"clean")).await.unwrap();
+    next.send_fin().await.unwrap();
+    tap.pass(true).await;
+    tap.pass(true).await;
+    let (received, res, _server) = timeout(Duration::from_secs(5), reader)
+        .await
+        .expect("session did not recover")
+        .unwrap();
+    res.unwrap();
+    assert_eq!(received, b"clean");
+}
+
+#[tokio::test]
+async fn tampered_frame_closes_an_unchecked_link() {
+    let cfg = MuxConfig {
+        keepalive_ms: 60_000,
+        ..config(Some(PSK))
+    };
+    let (client, server, mut tap, _handles) = tapped_sessions(cfg).await;
+    let _opening = tokio::spawn(async move {
+        client
+            .open_stream(TargetAddr::Domain("example.com".to_string(), 80))
+            .await
+    });
+    // Without checksums there is no way to skip a bad frame.
+    let mut forged = tap.next(false).await;
+    let last = forged.len() - 1;
+    forged[last] ^= 0x01;
+    tap.forward(&forged).await;
+    let end = timeout(Duration::from_secs(5), server.closed())
+        .await
+        .expect("server kept a link that sent a forged frame");
+    assert!(matches!(end, SessionEnd::Link(_)));
+    assert!(server.accept_stream().await.is_none());
+}
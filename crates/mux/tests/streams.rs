@@ -0,0 +1,259 @@
+mod support;
+
+use bytes::Bytes;
+use mux::{MuxConfig, MuxStream, MuxWriteHalf, StreamError, StreamState};
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+use support::{config, link_pair, open, pattern, session_pair};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{sleep, timeout};
+
+const WINDOW: usize = 64 * 1024;
+
+fn small_window() -> MuxConfig {
+    MuxConfig {
+        initial_window: WINDOW as u32,
+        ..config(None)
+    }
+}
+
+/// Polls one write and reports the outcome instead of waiting on it.
+async fn try_write(writer: &mut MuxWriteHalf, buf: &[u8]) -> Poll<io::Result<usize>> {
+    poll_fn(|cx| Poll::Ready(Pin::new(&mut *writer).poll_write(cx, buf))).await
+}
+
+/// Echoes what `stream` reads back to it until the peer's FIN.
+fn echo(stream: MuxStream) -> tokio::task::JoinHandle<u64> {
+    tokio::spawn(async move {
+        let (mut read, mut write) = stream.into_split();
+        let n = tokio::io::copy(&mut read, &mut write).await.unwrap();
+        write.shutdown().await.unwrap();
+        n
+    })
+}
+
+async fn wait_for_state(stream: &MuxStream, state: StreamState) {
+    timeout(Duration::from_secs(5), async {
+        while stream.state() != state {
+            sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("stream stuck in {:?}", stream.state()));
+}
+
+#[tokio::test]
+async fn peer_keeps_sending_after_our_fin() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, config(None)).await;
+    let (mut ours, mut theirs) = open(&client, &server).await;
+
+    ours.write_all(b"request").await.unwrap();
+    ours.shutdown().await.unwrap();
+    assert_eq!(ours.state(), StreamState::LocalClosed);
+    assert!(ours.send_data(Bytes::from_static(b"late")).await.is_err());
+
+    let mut request = Vec::new();
+    theirs.read_to_end(&mut request).await.unwrap();
+    assert_eq!(request, b"request");
+    assert_eq!(theirs.state(), StreamState::RemoteClosed);
+
+    // More than a window, so our side must keep granting credit after its
+    // FIN.
+    let response = pattern(1024 * 1024);
+    let writer = {
+        let response = response.clone();
+        tokio::spawn(async move {
+            theirs.write_all(&response).await.unwrap();
+            theirs.shutdown().await.unwrap();
+            theirs
+        })
+    };
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(10), ours.read_to_end(&mut received))
+        .await
+        .expect("response stalled after our fin")
+        .unwrap();
+    assert!(received == response);
+    assert_eq!(ours.state(), StreamState::Closed);
+    assert_eq!(writer.await.unwrap().state(), StreamState::Closed);
+}
+
+#[tokio::test]
+async fn rst_reaches_the_reader_with_its_code() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, config(None)).await;
+    let (ours, theirs) = open(&client, &server).await;
+    let (mut reader, mut writer) = ours.into_split();
+
+    // The reader is already parked when the RST arrives.
+    let parked = tokio::spawn(async move {
+        let err = reader.read(&mut [0; 16]).await.unwrap_err();
+        (reader, err)
+    });
+    tokio::task::yield_now().await;
+    server.send_rst(theirs.stream_id, 1234).await.unwrap();
+
+    let (mut reader, err) = timeout(Duration::from_secs(5), parked)
+        .await
+        .expect("reader not woken by rst")
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    let inner = err.get_ref().and_then(|e| e.downcast_ref::<StreamError>());
+    assert_eq!(inner, Some(&StreamError::Reset(1234)));
+    assert_eq!(reader.recv_data().await, Err(StreamError::Reset(1234)));
+    assert_eq!(
+        writer.write_all(b"x").await.unwrap_err().kind(),
+        io::ErrorKind::ConnectionReset
+    );
+
+    // Our own handles on the reset stream see it as a local reset.
+    let mut theirs = theirs;
+    assert_eq!(theirs.recv_data().await, Err(StreamError::LocalReset(1234)));
+    assert_eq!(theirs.state(), StreamState::Closed);
+}
+
+#[tokio::test]
+async fn rst_after_fin_leaves_the_data_readable() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, config(None)).await;
+    let (mut ours, mut theirs) = open(&client, &server).await;
+
+    theirs.write_all(b"complete").await.unwrap();
+    theirs.shutdown().await.unwrap();
+    // RST jumps the stream's queue, so hold it until the FIN is through.
+    wait_for_state(&ours, StreamState::RemoteClosed).await;
+    server.send_rst(theirs.stream_id, 1234).await.unwrap();
+
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(5), ours.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, b"complete");
+    // The RST still ends our sending side.
+    wait_for_state(&ours, StreamState::Closed).await;
+    assert!(ours.write_all(b"x").await.is_err());
+}
+
+#[tokio::test]
+async fn write_takes_no_bytes_while_out_of_credit() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, small_window()).await;
+    let (ours, mut theirs) = open(&client, &server).await;
+    let (_read, mut write) = ours.into_split();
+    let data = pattern(4 * WINDOW);
+
+    // Each write takes at most a frame; once the window is used up the next
+    // one is pending and has taken nothing.
+    let mut accepted = 0;
+    loop {
+        match try_write(&mut write, &data[accepted..]).await {
+            Poll::Ready(Ok(n)) => {
+                assert!(
+                    n > 0 && n < data.len() - accepted,
+                    "write of {} not partial",
+                    n
+                );
+                accepted += n;
+            }
+            Poll::Ready(Err(err)) => panic!("write failed: {}", err),
+            Poll::Pending => break,
+        }
+    }
+    assert_eq!(accepted, WINDOW);
+
+    let mut received = vec![0; accepted];
+    timeout(Duration::from_secs(5), theirs.read_exact(&mut received))
+        .await
+        .expect("accepted bytes never arrived")
+        .unwrap();
+    assert!(received == data[..accepted]);
+    assert!(
+        timeout(Duration::from_millis(100), theirs.read(&mut [0; 1]))
+            .await
+            .is_err(),
+        "a pending write sent data"
+    );
+
+    // Reading handed back credit; the rest follows where the writer left off.
+    let rest = data[accepted..].to_vec();
+    let writer = tokio::spawn(async move {
+        write.write_all(&rest).await.unwrap();
+        write.shutdown().await.unwrap();
+        write
+    });
+    let mut tail = Vec::new();
+    timeout(Duration::from_secs(10), theirs.read_to_end(&mut tail))
+        .await
+        .expect("writer never resumed")
+        .unwrap();
+    assert!(tail == data[accepted..]);
+    assert_eq!(writer.await.unwrap().stream_id(), theirs.stream_id);
+}
+
+#[tokio::test]
+async fn shutdown_sends_fin_behind_the_data() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, small_window()).await;
+    let (ours, theirs) = open(&client, &server).await;
+    let (mut read, mut write) = ours.into_split();
+    let echoed = echo(theirs);
+
+    let data = pattern(3 * WINDOW + 123);
+    let sent = data.clone();
+    let writer = tokio::spawn(async move {
+        write.write_all(&sent).await.unwrap();
+        write.shutdown().await.unwrap();
+        // Shutting down twice is fine; writing afterwards is not.
+        write.shutdown().await.unwrap();
+        assert_eq!(
+            write.write(b"late").await.unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    });
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(10), read.read_to_end(&mut received))
+        .await
+        .expect("echo never finished")
+        .unwrap();
+    assert!(received == data);
+    assert_eq!(echoed.await.unwrap(), data.len() as u64);
+    writer.await.unwrap();
+}
+
+/// What the HTTP proxy does with a tunnel: both directions copied at once,
+/// each ending with the other side's FIN.
+#[tokio::test]
+async fn copy_bidirectional_runs_a_stream_to_the_end() {
+    let (a, b) = link_pair();
+    let (client, server) = session_pair(a, b, small_window()).await;
+    let (mut ours, theirs) = open(&client, &server).await;
+    let echoed = echo(theirs);
+    let (mut app, mut tunnel_end) = tokio::io::duplex(4096);
+    let tunnel =
+        tokio::spawn(
+            async move { tokio::io::copy_bidirectional(&mut tunnel_end, &mut ours).await },
+        );
+
+    let data = pattern(8 * WINDOW);
+    let (mut app_read, mut app_write) = tokio::io::split(&mut app);
+    let send = async {
+        app_write.write_all(&data).await.unwrap();
+        app_write.shutdown().await.unwrap();
+    };
+    let mut received = Vec::new();
+    let (_, res) = timeout(Duration::from_secs(10), async {
+        tokio::join!(send, app_read.read_to_end(&mut received))
+    })
+    .await
+    .expect("tunnel stalled");
+    res.unwrap();
+    assert!(received == data);
+    let (up, down) = tunnel.await.unwrap().unwrap();
+    assert_eq!((up, down), (data.len() as u64, data.len() as u64));
+    assert_eq!(echoed.await.unwrap(), data.len() as u64);
+}
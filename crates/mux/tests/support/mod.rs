@@ -0,0 +1,125 @@
+#![allow(dead_code)]
+
+use btlink::{spawn_async_io, BtLink, BtLinkConfig};
+use bytes::BytesMut;
+use common::error::BtProxyError;
+use mux::codec::try_decode;
+use mux::handshake::build_hello_ack;
+use mux::{Frame, HelloFrame, MuxConfig, MuxSession, MuxStream, Role, TargetAddr};
+use std::time::Duration;
+use tokio::time::timeout;
+
+pub const PSK: &[u8] = b"correct horse battery staple";
+
+pub fn link_pair() -> (BtLink, BtLink) {
+    let (a, b) = tokio::io::duplex(64 * 1024);
+    let (a_read, a_write) = tokio::io::split(a);
+    let (b_read, b_write) = tokio::io::split(b);
+    (
+        spawn_async_io(a_read, a_write, BtLinkConfig::default()),
+        spawn_async_io(b_read, b_write, BtLinkConfig::default()),
+    )
+}
+
+pub fn config(psk: Option<&[u8]>) -> MuxConfig {
+    MuxConfig {
+        psk: psk.map(<[u8]>::to_vec),
+        ..Default::default()
+    }
+}
+
+/// Runs both handshakes over `client` and `server`.
+pub async fn session_pair(
+    client: BtLink,
+    server: BtLink,
+    cfg: MuxConfig,
+) -> (MuxSession, MuxSession) {
+    let (client, server) = tokio::join!(
+        MuxSession::start(client, cfg.clone(), Role::Client),
+        MuxSession::start(server, cfg, Role::Server),
+    );
+    (client.unwrap(), server.unwrap())
+}
+
+/// Opens a stream from `client` and returns both ends of it.
+pub async fn open(client: &MuxSession, server: &MuxSession) -> (MuxStream, MuxStream) {
+    let accept = async {
+        let (_, stream) = server.accept_stream().await.unwrap();
+        server.send_open_ok(stream.stream_id).await.unwrap();
+        stream
+    };
+    let (opened, accepted) = tokio::join!(
+        client.open_stream(TargetAddr::Domain("example.com".to_string(), 80)),
+        accept
+    );
+    (opened.unwrap(), accepted)
+}
+
+/// Starts a client session against a hand-driven server that completes a
+/// plain handshake with `cfg` and then does only what the test makes it do.
+pub async fn raw_server(client: BtLink, server: BtLink, cfg: MuxConfig) -> (MuxSession, RawPeer) {
+    let mut server = RawPeer::new(server);
+    let answer = async {
+        let hello = server.recv_hello().await;
+        server
+            .send(&build_hello_ack(&cfg, 0, None, &hello).unwrap())
+            .await;
+    };
+    let (client, _) = tokio::join!(MuxSession::start(client, cfg.clone(), Role::Client), answer);
+    (client.unwrap(), server)
+}
+
+/// Bytes that reveal loss, duplication and reordering when compared.
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Drives one side of a session by hand.
+pub struct RawPeer {
+    pub link: BtLink,
+    buffer: BytesMut,
+}
+
+impl RawPeer {
+    pub fn new(link: BtLink) -> Self {
+        Self {
+            link,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    pub async fn send(&self, frame: &Frame) {
+        self.link.tx.send(frame.encode().unwrap()).await.unwrap();
+    }
+
+    pub async fn recv(&mut self) -> Frame {
+        loop {
+            if let Some(frame) = try_decode(&mut self.buffer, 65536).unwrap() {
+                return frame;
+            }
+            let chunk = self.link.rx.recv().await.expect("link closed");
+            self.buffer.extend_from_slice(&chunk);
+        }
+    }
+
+    pub async fn recv_hello(&mut self) -> HelloFrame {
+        match self.recv().await {
+            Frame::Hello(hello) | Frame::HelloAck(hello) => *hello,
+            frame => panic!("expected hello, got {:?}", frame),
+        }
+    }
+}
+
+pub async fn expect_auth_error<T>(
+    result: impl std::future::Future<Output = common::error::Result<T>>,
+    reason: &str,
+) {
+    match timeout(Duration::from_secs(5), result).await {
+        Ok(Err(BtProxyError::Auth(msg))) => {
+            assert!(msg.contains(reason), "unexpected reason: {}", msg)
+        }
+        Ok(Err(err)) => panic!("expected auth error, got {:?}", err),
+        Ok(Ok(_)) => panic!("handshake unexpectedly succeeded"),
+        Err(_) => panic!("handshake hung"),
+    }
+}
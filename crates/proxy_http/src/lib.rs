@@ -1,3 +1,5 @@
+pub mod rules;
 pub mod server;
 
+pub use rules::PriorityRules;
 pub use server::run_http_proxy;
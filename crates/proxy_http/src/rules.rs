@@ -0,0 +1,52 @@
+use common::error::{BtProxyError, Result};
+use mux::Priority;
+use std::sync::Arc;
+
+/// Stream priorities by destination host. The first matching rule wins;
+/// unmatched hosts get `Priority::Normal`.
+#[derive(Debug, Clone, Default)]
+pub struct PriorityRules {
+    rules: Arc<Vec<(String, Priority)>>,
+}
+
+impl PriorityRules {
+    /// Parses `PATTERN=LEVEL` specs. A pattern is a host name, `*.domain`
+    /// for the domain and everything below it, or `*` for any host.
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let rules = specs
+            .iter()
+            .map(|spec| {
+                let (pattern, level) = spec.rsplit_once('=').ok_or_else(|| {
+                    BtProxyError::Config(format!("priority rule must be PATTERN=LEVEL: {}", spec))
+                })?;
+                Ok((pattern.to_ascii_lowercase(), level.parse()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            rules: Arc::new(rules),
+        })
+    }
+
+    pub fn priority_for(&self, host: &str) -> Priority {
+        let host = host.to_ascii_lowercase();
+        self.rules
+            .iter()
+            .find(|(pattern, _)| matches(pattern, &host))
+            .map_or(Priority::Normal, |(_, priority)| *priority)
+    }
+}
+
+fn matches(pattern: &str, host: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            host == domain
+                || host
+                    .strip_suffix(domain)
+                    .is_some_and(|rest| rest.ends_with('.'))
+        }
+        None => pattern == host,
+    }
+}
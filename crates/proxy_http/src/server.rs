@@ -1,28 +1,34 @@
+use crate::rules::PriorityRules;
 use bytes::Bytes;
 use common::error::{BtProxyError, Result};
 use common::read_until_double_crlf;
 use httparse::Request;
 use mux::{MuxSession, MuxStream, TargetAddr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{copy_bidirectional, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tracing::{info, warn};
 use url::Url;
 
-pub async fn run_http_proxy(listen: &str, session: MuxSession) -> Result<()> {
+pub async fn run_http_proxy(listen: &str, session: MuxSession, rules: PriorityRules) -> Result<()> {
     let listener = TcpListener::bind(listen).await?;
     info!("http proxy listening on {}", listen);
     loop {
         let (stream, addr) = listener.accept().await?;
         let session = session.clone();
+        let rules = rules.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_client(stream, session).await {
+            if let Err(err) = handle_client(stream, session, rules).await {
                 warn!(?addr, ?err, "client error");
             }
         });
     }
 }
 
-async fn handle_client(mut stream: TcpStream, session: MuxSession) -> Result<()> {
+async fn handle_client(
+    mut stream: TcpStream,
+    session: MuxSession,
+    rules: PriorityRules,
+) -> Result<()> {
     let header = read_until_double_crlf(&mut stream, 64 * 1024).await?;
     let mut headers = [httparse::EMPTY_HEADER; 32];
     let mut req = Request::new(&mut headers);
@@ -41,8 +47,9 @@ async fn handle_client(mut stream: TcpStream, session: MuxSession) -> Result<()>
 
     if method.eq_ignore_ascii_case("CONNECT") {
         let (host, port) = parse_connect_target(path)?;
+        let priority = rules.priority_for(&host);
         let target = TargetAddr::Domain(host.clone(), port);
-        let mux_stream = session.open_stream(target).await?;
+        let mux_stream = session.open_stream_with_priority(target, priority).await?;
         stream
             .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
             .await?;
@@ -55,7 +62,7 @@ async fn handle_client(mut stream: TcpStream, session: MuxSession) -> Result<()>
         .host_str()
         .ok_or_else(|| BtProxyError::Protocol("missing host".to_string()))?
         .to_string();
-    let port = url.port_or_known_default().unwrap_or(80) as u16;
+    let port = url.port_or_known_default().unwrap_or(80);
     let mut origin = url.path().to_string();
     if let Some(query) = url.query() {
         origin.push('?');
@@ -63,14 +70,15 @@ async fn handle_client(mut stream: TcpStream, session: MuxSession) -> Result<()>
     }
 
     let rewritten = rewrite_request(&header, method, &origin, &host);
+    let priority = rules.priority_for(&host);
     let target = TargetAddr::Domain(host.clone(), port);
-    let mux_stream = session.open_stream(target).await?;
+    let mux_stream = session.open_stream_with_priority(target, priority).await?;
     mux_stream
         .send_data(Bytes::from(rewritten))
         .await
         .map_err(|_| BtProxyError::Protocol("send failed".to_string()))?;
 
-    forward_body(stream, mux_stream).await?;
+    tunnel(stream, mux_stream).await?;
     Ok(())
 }
 
@@ -117,70 +125,7 @@ fn rewrite_request(original: &[u8], method: &str, origin: &str, host: &str) -> V
     out
 }
 
-async fn tunnel(client: TcpStream, mux_stream: MuxStream) -> Result<()> {
-    let (mut client_read, mut client_write) = client.into_split();
-    let inbound = mux_stream.clone();
-
-    let client_to_mux = tokio::spawn(async move {
-        let mut buf = [0u8; 4096];
-        loop {
-            let n = client_read.read(&mut buf).await?;
-            if n == 0 {
-                let _ = mux_stream.send_fin().await;
-                return Ok::<(), BtProxyError>(());
-            }
-            mux_stream
-                .send_data(Bytes::copy_from_slice(&buf[..n]))
-                .await
-                .map_err(|_| BtProxyError::Protocol("mux send failed".to_string()))?;
-        }
-    });
-
-    let mux_to_client = tokio::spawn(async move {
-        while let Some(chunk) = inbound.recv_data().await {
-            client_write.write_all(&chunk).await?;
-        }
-        let _ = client_write.shutdown().await;
-        Ok::<(), BtProxyError>(())
-    });
-
-    let (res_client, res_mux) = tokio::try_join!(client_to_mux, mux_to_client)
-        .map_err(|err| BtProxyError::Protocol(err.to_string()))?;
-    res_client?;
-    res_mux?;
-    Ok(())
-}
-
-async fn forward_body(client: TcpStream, mux_stream: MuxStream) -> Result<()> {
-    let (mut client_read, mut client_write) = client.into_split();
-    let inbound = mux_stream.clone();
-
-    let client_to_mux = tokio::spawn(async move {
-        let mut buf = [0u8; 4096];
-        loop {
-            let n = client_read.read(&mut buf).await?;
-            if n == 0 {
-                let _ = mux_stream.send_fin().await;
-                return Ok::<(), BtProxyError>(());
-            }
-            mux_stream
-                .send_data(Bytes::copy_from_slice(&buf[..n]))
-                .await
-                .map_err(|_| BtProxyError::Protocol("mux send failed".to_string()))?;
-        }
-    });
-
-    let mux_to_client = tokio::spawn(async move {
-        while let Some(chunk) = inbound.recv_data().await {
-            client_write.write_all(&chunk).await?;
-        }
-        let _ = client_write.shutdown().await;
-        Ok::<(), BtProxyError>(())
-    });
-
-    let (res_client, res_mux) = tokio::try_join!(client_to_mux, mux_to_client)
-        .map_err(|err| BtProxyError::Protocol(err.to_string()))?;
-    res_client?;
-    res_mux?;
+async fn tunnel(mut client: TcpStream, mut mux_stream: MuxStream) -> Result<()> {
+    copy_bidirectional(&mut client, &mut mux_stream).await?;
     Ok(())
 }